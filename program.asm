@@ -20,4 +20,5 @@ fibb:
     mov x3 acc
     jmp loop
 end:
-    nop
\ No newline at end of file
+    nop
+    # expect: acc=9 x1=34 x2=55 steps=113 power=94
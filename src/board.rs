@@ -0,0 +1,444 @@
+/*
+    Several chips wired together and to the outside world.
+*/
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::machine::{Io, MAX_STEPS_PER_TIMESTEP, Machine, Step};
+
+/// A chip on the board and the name it is wired up by.
+#[derive(Debug)]
+pub struct Chip {
+    pub name: String,
+    pub machine: Machine,
+}
+
+/// Something attached to a net.
+#[derive(Debug, Clone, PartialEq)]
+enum Endpoint {
+    /// A register on the chip with this index.
+    Pin(usize, String),
+    /// A port to the outside world, such as a puzzle input or output.
+    Port(String),
+}
+
+/// Whether a net carries simple I/O levels or XBus packets.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Simple,
+    XBus,
+}
+
+impl Kind {
+    /// Simple I/O pins are called `p0`, `p1`, ...; XBus pins `x0`, `x1`, ...
+    fn of(register: &str) -> Option<Kind> {
+        match register.chars().next() {
+            Some('p') => Some(Kind::Simple),
+            Some('x') => Some(Kind::XBus),
+            _ => None,
+        }
+    }
+}
+
+/// A wire joining endpoints.
+#[derive(Debug)]
+struct Net {
+    kind: Kind,
+    endpoints: Vec<Endpoint>,
+    /// The level each endpoint drives onto a simple I/O net.
+    levels: Vec<i64>,
+    /// Values written to an XBus net and not yet read, with the endpoint
+    /// that wrote each one.
+    queue: VecDeque<(usize, i64)>,
+}
+
+impl Net {
+    fn position(&self, endpoint: &Endpoint) -> Option<usize> {
+        self.endpoints.iter().position(|e| e == endpoint)
+    }
+
+    /// The level seen by endpoint `reader`: the highest level driven by
+    /// anything else on the net.
+    fn level_for(&self, reader: usize) -> i64 {
+        self.levels
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != reader)
+            .map(|(_, v)| *v)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Index into `queue` of the next value endpoint `reader` can read.
+    fn next_for(&self, reader: usize) -> Option<usize> {
+        self.queue.iter().position(|(writer, _)| *writer != reader)
+    }
+}
+
+/// The wiring seen by one chip while it steps.
+struct ChipIo<'a> {
+    chip: usize,
+    nets: &'a mut [Net],
+    pins: &'a HashMap<(usize, String), usize>,
+}
+
+impl ChipIo<'_> {
+    fn net(&self, name: &str) -> (usize, usize) {
+        let net = self.pins[&(self.chip, name.to_owned())];
+        let endpoint = Endpoint::Pin(self.chip, name.to_owned());
+        (net, self.nets[net].position(&endpoint).unwrap())
+    }
+}
+
+impl Io for ChipIo<'_> {
+    fn connected(&self, name: &str) -> bool {
+        self.pins.contains_key(&(self.chip, name.to_owned()))
+    }
+
+    fn ready(&self, names: &[&str]) -> bool {
+        // Values already promised to an earlier read can't be read again.
+        let mut taken: Vec<(usize, usize)> = Vec::new();
+        for name in names {
+            let (net, me) = self.net(name);
+            if self.nets[net].kind == Kind::Simple {
+                continue;
+            }
+            let queue = &self.nets[net].queue;
+            let next = (0..queue.len()).find(|i| queue[*i].0 != me && !taken.contains(&(net, *i)));
+            match next {
+                Some(i) => taken.push((net, i)),
+                None => return false,
+            }
+        }
+        true
+    }
+
+    fn read(&mut self, name: &str) -> Option<i64> {
+        let (net, me) = self.net(name);
+        let net = &mut self.nets[net];
+        match net.kind {
+            Kind::Simple => Some(net.level_for(me)),
+            Kind::XBus => {
+                let i = net.next_for(me)?;
+                net.queue.remove(i).map(|(_, value)| value)
+            }
+        }
+    }
+
+    fn write(&mut self, name: &str, value: i64) {
+        let (net, me) = self.net(name);
+        let net = &mut self.nets[net];
+        match net.kind {
+            Kind::Simple => net.levels[me] = value,
+            Kind::XBus => net.queue.push_back((me, value)),
+        }
+    }
+
+    fn delivered(&self, name: &str) -> bool {
+        let (net, me) = self.net(name);
+        let net = &self.nets[net];
+        // Ports outside the board take whatever is written at once.
+        net.kind == Kind::Simple
+            || net.endpoints.iter().any(|e| matches!(e, Endpoint::Port(_)))
+            || net.queue.iter().all(|(writer, _)| *writer != me)
+    }
+}
+
+/// Chips and the nets between them, run one timestep at a time.
+///
+/// Within a timestep every chip runs until it sleeps, halts or blocks on
+/// XBus, and chips keep taking turns while any of them makes progress.
+/// Programs loop back to their first line as they do in the game. An XBus
+/// write blocks the chip until another chip reads the value, except on a
+/// net wired to an outside port, which takes it at once.
+#[derive(Debug, Default)]
+pub struct Board {
+    pub chips: Vec<Chip>,
+    nets: Vec<Net>,
+    /// Which net each wired chip register is on.
+    pins: HashMap<(usize, String), usize>,
+    /// Which net each outside port is on.
+    ports: HashMap<String, usize>,
+    timestep: usize,
+}
+
+impl Board {
+    pub fn new() -> Board {
+        Board::default()
+    }
+
+    /// Put a chip on the board. Its program will loop instead of halting.
+    pub fn add_chip(&mut self, name: &str, mut machine: Machine) {
+        machine.set_wrap(true);
+        self.chips.push(Chip {
+            name: name.to_owned(),
+            machine,
+        });
+    }
+
+    pub fn chip(&self, name: &str) -> Option<&Chip> {
+        self.chips.iter().find(|c| c.name == name)
+    }
+
+    /// Wire endpoints together. Each is either `chip.register`, such as
+    /// `amp.p0`, or the name of an outside port.
+    pub fn connect(&mut self, endpoints: &[&str]) -> Result<(), String> {
+        let mut resolved = Vec::new();
+        let mut kind = None;
+        for name in endpoints {
+            let endpoint = match name.split_once('.') {
+                Some((chip, register)) => {
+                    let index = match self.chips.iter().position(|c| c.name == chip) {
+                        Some(v) => v,
+                        None => return Err(format!("no chip called {:?}", chip)),
+                    };
+                    if self.chips[index].machine.register(register).is_none() {
+                        return Err(format!("{} has no pin called {:?}", chip, register));
+                    }
+                    let pin_kind = match Kind::of(register) {
+                        Some(v) => v,
+                        None => return Err(format!("{} is not a pin", name)),
+                    };
+                    if kind.is_some_and(|k| k != pin_kind) {
+                        return Err(format!("{} can't join simple I/O to XBus", name));
+                    }
+                    kind = Some(pin_kind);
+                    if self.pins.contains_key(&(index, register.to_owned())) {
+                        return Err(format!("{} is already wired", name));
+                    }
+                    Endpoint::Pin(index, register.to_owned())
+                }
+                None => {
+                    if self.ports.contains_key(*name) {
+                        return Err(format!("port {} is already wired", name));
+                    }
+                    Endpoint::Port(name.to_string())
+                }
+            };
+            if resolved.contains(&endpoint) {
+                return Err(format!("{} is listed twice", name));
+            }
+            resolved.push(endpoint);
+        }
+        let kind = match kind {
+            Some(v) => v,
+            None => return Err(String::from("a wire needs at least one chip pin")),
+        };
+
+        let net = self.nets.len();
+        for endpoint in &resolved {
+            match endpoint {
+                Endpoint::Pin(chip, register) => {
+                    self.pins.insert((*chip, register.to_owned()), net);
+                }
+                Endpoint::Port(name) => {
+                    self.ports.insert(name.to_owned(), net);
+                }
+            }
+        }
+        self.nets.push(Net {
+            kind,
+            levels: vec![0; resolved.len()],
+            endpoints: resolved,
+            queue: VecDeque::new(),
+        });
+        Ok(())
+    }
+
+    /// The kind of net an outside port is wired to.
+    pub fn port_kind(&self, port: &str) -> Option<Kind> {
+        self.ports.get(port).map(|net| self.nets[*net].kind)
+    }
+
+    fn port(&self, port: &str) -> Result<(usize, usize), String> {
+        match self.ports.get(port) {
+            Some(net) => {
+                let endpoint = Endpoint::Port(port.to_owned());
+                Ok((*net, self.nets[*net].position(&endpoint).unwrap()))
+            }
+            None => Err(format!("port {:?} isn't wired to anything", port)),
+        }
+    }
+
+    /// Drive a simple I/O port to `value`.
+    pub fn set_input(&mut self, port: &str, value: i64) -> Result<(), String> {
+        let (net, me) = self.port(port)?;
+        self.nets[net].levels[me] = value;
+        Ok(())
+    }
+
+    /// Send a value into an XBus port, to be read by a chip.
+    pub fn send_input(&mut self, port: &str, value: i64) -> Result<(), String> {
+        let (net, me) = self.port(port)?;
+        self.nets[net].queue.push_back((me, value));
+        Ok(())
+    }
+
+    /// The level on the net a simple I/O port is wired to.
+    pub fn output(&self, port: &str) -> Result<i64, String> {
+        let (net, me) = self.port(port)?;
+        Ok(self.nets[net].level_for(me))
+    }
+
+    /// Take every value chips have written to an XBus port.
+    pub fn receive_output(&mut self, port: &str) -> Result<Vec<i64>, String> {
+        let (net, me) = self.port(port)?;
+        let net = &mut self.nets[net];
+        let mut values = Vec::new();
+        while let Some(i) = net.next_for(me) {
+            values.push(net.queue.remove(i).unwrap().1);
+        }
+        Ok(values)
+    }
+
+    /// The timestep `run_timestep` will run next.
+    pub fn timestep(&self) -> usize {
+        self.timestep
+    }
+
+    /// Run every chip through the current timestep.
+    pub fn run_timestep(&mut self) -> Result<(), String> {
+        let t = self.timestep;
+        for chip in self.chips.iter_mut() {
+            chip.machine.advance_clock(t);
+        }
+
+        let mut steps = vec![0; self.chips.len()];
+        loop {
+            let mut progress = false;
+            for (i, chip) in self.chips.iter_mut().enumerate() {
+                while chip.machine.next_timestep() <= t {
+                    let mut io = ChipIo {
+                        chip: i,
+                        nets: &mut self.nets,
+                        pins: &self.pins,
+                    };
+                    match chip.machine.step_with(&mut io) {
+                        Step::Ran => progress = true,
+                        Step::Blocked | Step::Halted => break,
+                    }
+                    steps[i] += 1;
+                    if steps[i] > MAX_STEPS_PER_TIMESTEP {
+                        return Err(format!(
+                            "{} ran {} instructions in timestep {} without sleeping",
+                            chip.name, MAX_STEPS_PER_TIMESTEP, t
+                        ));
+                    }
+                }
+            }
+            if !progress {
+                break;
+            }
+        }
+        self.timestep += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Board;
+    use crate::chip::ChipModel;
+    use crate::machine::Machine;
+
+    fn chip(source: &str) -> Machine {
+        Machine::load_chip(source, ChipModel::Mc4000).unwrap()
+    }
+
+    #[test]
+    fn simple_io_between_chips() {
+        let mut board = Board::new();
+        board.add_chip("a", chip("mov p0 acc\nadd 1\nmov acc p1\nslp 1"));
+        board.add_chip("b", chip("mov p0 acc\nmul 2\nmov acc p1\nslp 1"));
+        board.connect(&["in", "a.p0"]).unwrap();
+        board.connect(&["a.p1", "b.p0"]).unwrap();
+        board.connect(&["b.p1", "out"]).unwrap();
+
+        board.set_input("in", 10).unwrap();
+        board.run_timestep().unwrap();
+        assert_eq!(board.output("out"), Ok(22));
+        board.set_input("in", 20).unwrap();
+        board.run_timestep().unwrap();
+        assert_eq!(board.output("out"), Ok(42));
+    }
+
+    #[test]
+    fn xbus_blocks_until_written() {
+        let mut board = Board::new();
+        board.add_chip("reader", chip("mov x0 acc\nmov acc x1"));
+        board.add_chip("writer", chip("mov 7 x0\nslp 1"));
+        board.connect(&["reader.x0", "writer.x0"]).unwrap();
+        board.connect(&["reader.x1", "out"]).unwrap();
+
+        board.run_timestep().unwrap();
+        assert_eq!(board.receive_output("out"), Ok(vec![7]));
+        board.run_timestep().unwrap();
+        assert_eq!(board.receive_output("out"), Ok(vec![7]));
+    }
+
+    #[test]
+    fn xbus_writes_block_until_read() {
+        let mut board = Board::new();
+        board.add_chip("writer", chip("mov 7 x0\nadd 1\nslp 1"));
+        board.add_chip("reader", chip("slp 1\nmov x0 acc\nslp 5"));
+        board.connect(&["writer.x0", "reader.x0"]).unwrap();
+        let acc = |board: &Board, name| board.chip(name).unwrap().machine.register("acc");
+
+        board.run_timestep().unwrap();
+        assert_eq!(acc(&board, "writer"), Some(0));
+        board.run_timestep().unwrap();
+        assert_eq!(acc(&board, "reader"), Some(7));
+        assert_eq!(acc(&board, "writer"), Some(1));
+    }
+
+    #[test]
+    fn xbus_reads_take_one_value_each() {
+        let mut board = Board::new();
+        board.add_chip("a", chip("teq x0 x0\n+ mov 100 p1\n- mov 50 p1\nslp 1"));
+        board.connect(&["in", "a.x0"]).unwrap();
+        board.connect(&["a.p1", "out"]).unwrap();
+        board.send_input("in", 5).unwrap();
+        board.run_timestep().unwrap();
+        assert_eq!(board.output("out"), Ok(0));
+        board.send_input("in", 5).unwrap();
+        board.run_timestep().unwrap();
+        assert_eq!(board.output("out"), Ok(100));
+        board.send_input("in", 6).unwrap();
+        board.send_input("in", 7).unwrap();
+        board.run_timestep().unwrap();
+        assert_eq!(board.output("out"), Ok(50));
+
+        // Two pins on one net need a value each too.
+        let mut board = Board::new();
+        board.add_chip("a", chip("teq x0 x1\n+ mov 100 p1\nslp 1"));
+        board.connect(&["in", "a.x0", "a.x1"]).unwrap();
+        board.connect(&["a.p1", "out"]).unwrap();
+        board.send_input("in", 5).unwrap();
+        board.run_timestep().unwrap();
+        assert_eq!(board.output("out"), Ok(0));
+        board.send_input("in", 5).unwrap();
+        board.run_timestep().unwrap();
+        assert_eq!(board.output("out"), Ok(100));
+    }
+
+    #[test]
+    fn wiring_errors() {
+        let mut board = Board::new();
+        board.add_chip("a", chip("nop"));
+        assert!(board.connect(&["a.p0", "a.x0"]).is_err());
+        assert!(board.connect(&["b.p0"]).is_err());
+        assert!(board.connect(&["a.acc"]).is_err());
+        assert!(board.connect(&["in", "out"]).is_err());
+        assert!(board.connect(&["a.x0", "a.x0"]).is_err());
+        assert!(board.connect(&["a.x1", "radio", "radio"]).is_err());
+        board.connect(&["a.p0", "in"]).unwrap();
+        assert!(board.connect(&["a.p0", "a.p1"]).is_err());
+    }
+
+    #[test]
+    fn stuck_chip_is_an_error() {
+        let mut board = Board::new();
+        board.add_chip("a", chip("add 1"));
+        assert!(board.run_timestep().is_err());
+    }
+}
@@ -0,0 +1,239 @@
+/*
+    Control flow graphs: the program split into basic blocks, with edges for
+    falling through, jumps, `+`/`-` lines and the wrap back to the first
+    line that chips in the game make. Exported as Graphviz DOT.
+*/
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::format;
+use crate::instruction::{Arg, Instruction, condition};
+use crate::parse::Line;
+
+/// Lines that run one after another: nothing jumps into the middle and
+/// every line shares the same `+`/`-` condition.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    /// Program indices, `start..end`.
+    pub start: usize,
+    pub end: usize,
+    /// `Arg::BranchTrue` or `Arg::BranchFalse` if the lines are conditional.
+    pub condition: Option<Arg>,
+}
+
+/// How control gets from one block to another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// Running off the end of the block into the next.
+    Next,
+    /// An unconditional `jmp`.
+    Jump,
+    /// A conditional `jmp` whose condition held.
+    Taken,
+    /// The block's condition didn't hold, so its lines were skipped.
+    Skipped,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    pub kind: Kind,
+    /// The edge goes from the end of the program back to the start.
+    pub wraps: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cfg {
+    pub blocks: Vec<Block>,
+    pub edges: Vec<Edge>,
+}
+
+impl Cfg {
+    /// Build the graph of a program that has been checked, so every `jmp`
+    /// names a label that exists.
+    pub fn build(program: &[Line]) -> Cfg {
+        // A block starts at the first line, at a label, after a jmp or a
+        // test, and wherever the condition changes.
+        let mut blocks: Vec<Block> = Vec::new();
+        for (i, (instr, args)) in program.iter().enumerate() {
+            let leader = match i.checked_sub(1).map(|p| &program[p]) {
+                None => true,
+                Some((previous, previous_args)) => {
+                    (*instr == Instruction::Label && *previous != Instruction::Label)
+                        || *previous == Instruction::Jmp
+                        || previous.is_test()
+                        || condition(args) != condition(previous_args)
+                }
+            };
+            match blocks.last_mut() {
+                Some(block) if !leader => block.end = i + 1,
+                _ => blocks.push(Block {
+                    start: i,
+                    end: i + 1,
+                    condition: condition(args).cloned(),
+                }),
+            }
+        }
+
+        let mut labels = HashMap::new();
+        for (i, (instr, args)) in program.iter().enumerate() {
+            if *instr == Instruction::Label {
+                labels.insert(&args[0], i);
+            }
+        }
+        let block_of = |index: usize| {
+            blocks
+                .iter()
+                .position(|b| (b.start..b.end).contains(&index))
+        };
+
+        let mut edges = Vec::new();
+        for (b, block) in blocks.iter().enumerate() {
+            let next = Edge {
+                from: b,
+                to: (b + 1) % blocks.len(),
+                kind: Kind::Next,
+                wraps: b + 1 == blocks.len(),
+            };
+            let (instr, args) = &program[block.end - 1];
+            let target = match instr {
+                Instruction::Jmp => labels.get(&args[0]).and_then(|i| block_of(*i)),
+                _ => None,
+            };
+            match (target, &block.condition) {
+                (Some(to), None) => edges.push(Edge {
+                    to,
+                    kind: Kind::Jump,
+                    wraps: false,
+                    ..next
+                }),
+                (Some(to), Some(_)) => {
+                    edges.push(Edge {
+                        to,
+                        kind: Kind::Taken,
+                        wraps: false,
+                        ..next
+                    });
+                    edges.push(Edge {
+                        kind: Kind::Skipped,
+                        ..next
+                    });
+                }
+                (None, _) => edges.push(next),
+            }
+        }
+        Cfg { blocks, edges }
+    }
+
+    /// The graph in Graphviz DOT, each block showing its lines. Wrapping
+    /// edges are dashed.
+    pub fn dot(&self, program: &[Line], name: &str) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "digraph {:?} {{", name);
+        let _ = writeln!(out, "    node [shape=box, fontname=monospace];");
+        for (b, block) in self.blocks.iter().enumerate() {
+            let text: String = program[block.start..block.end]
+                .iter()
+                .map(|line| format!("{}\\l", escape(&format::line(line))))
+                .collect();
+            let _ = writeln!(out, "    b{} [label=\"{}\"];", b, text);
+        }
+        for edge in &self.edges {
+            let sign = match &self.blocks[edge.from].condition {
+                Some(c) => c.to_string(),
+                None => String::new(),
+            };
+            let mut attributes = match edge.kind {
+                Kind::Next if edge.wraps => vec![String::from("label=\"wrap\"")],
+                Kind::Next => Vec::new(),
+                Kind::Jump => vec![String::from("label=\"jmp\"")],
+                Kind::Taken => vec![format!("label=\"{} jmp\"", sign)],
+                Kind::Skipped => vec![String::from("label=\"skip\"")],
+            };
+            if edge.wraps {
+                attributes.push(String::from("style=dashed"));
+            }
+            let attributes = if attributes.is_empty() {
+                String::new()
+            } else {
+                format!(" [{}]", attributes.join(", "))
+            };
+            let _ = writeln!(out, "    b{} -> b{}{};", edge.from, edge.to, attributes);
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+/// `text` with the characters that end or escape a quoted DOT string
+/// escaped.
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cfg, Kind};
+    use crate::machine::Machine;
+
+    fn edges(cfg: &Cfg) -> Vec<(usize, usize, Kind, bool)> {
+        cfg.edges
+            .iter()
+            .map(|e| (e.from, e.to, e.kind, e.wraps))
+            .collect()
+    }
+
+    #[test]
+    fn splits_fibonacci_into_blocks() {
+        let machine = Machine::load(include_str!("../program.asm")).unwrap();
+        let cfg = Cfg::build(machine.program());
+        let starts: Vec<usize> = cfg.blocks.iter().map(|b| b.start).collect();
+        // mov mov | loop: add teq | - jmp fibb | + jmp end | fibb: ... jmp loop | end: nop
+        assert_eq!(starts, vec![0, 2, 5, 6, 7, 16]);
+        assert_eq!(
+            edges(&cfg),
+            vec![
+                (0, 1, Kind::Next, false),
+                (1, 2, Kind::Next, false),
+                (2, 4, Kind::Taken, false),
+                (2, 3, Kind::Skipped, false),
+                (3, 5, Kind::Taken, false),
+                (3, 4, Kind::Skipped, false),
+                (4, 1, Kind::Jump, false),
+                (5, 0, Kind::Next, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn exports_dot() {
+        let machine = Machine::load("start:\n  teq acc 0\n  + add 1\n  jmp start").unwrap();
+        let cfg = Cfg::build(machine.program());
+        let dot = cfg.dot(machine.program(), "chip");
+        assert_eq!(
+            dot,
+            "\
+digraph \"chip\" {
+    node [shape=box, fontname=monospace];
+    b0 [label=\"start:\\lteq acc 0\\l\"];
+    b1 [label=\"+ add 1\\l\"];
+    b2 [label=\"jmp start\\l\"];
+    b0 -> b1;
+    b1 -> b2;
+    b2 -> b0 [label=\"jmp\"];
+}
+"
+        );
+    }
+
+    #[test]
+    fn escapes_quotes_and_backslashes() {
+        let machine = Machine::load("a\"b\\:\n  jmp a\"b\\").unwrap();
+        let cfg = Cfg::build(machine.program());
+        let dot = cfg.dot(machine.program(), "chip");
+        let label = r#"b0 [label="a\"b\\:\ljmp a\"b\\\l"];"#;
+        assert!(dot.contains(label), "{}", dot);
+    }
+}
@@ -0,0 +1,58 @@
+/*
+    Definitions for the microcontroller models a program can run on.
+*/
+
+use std::str::FromStr;
+
+/// The microcontrollers available in the game.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChipModel {
+    Mc4000,
+    #[default]
+    Mc6000,
+}
+
+impl ChipModel {
+    /// Registers and ports the program on this chip can name.
+    pub fn registers(&self) -> &'static [&'static str] {
+        match self {
+            ChipModel::Mc4000 => &["acc", "p0", "p1", "x0", "x1"],
+            ChipModel::Mc6000 => &["acc", "dat", "p0", "p1", "x0", "x1", "x2", "x3"],
+        }
+    }
+
+    /// How many lines of code fit in the chip's editor.
+    pub fn max_lines(&self) -> usize {
+        match self {
+            ChipModel::Mc4000 => 9,
+            ChipModel::Mc6000 => 14,
+        }
+    }
+
+    /// Price of the chip in yuan, used for the production cost score.
+    pub fn cost(&self) -> usize {
+        match self {
+            ChipModel::Mc4000 => 3,
+            ChipModel::Mc6000 => 5,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChipModel::Mc4000 => "MC4000",
+            ChipModel::Mc6000 => "MC6000",
+        }
+    }
+}
+
+impl FromStr for ChipModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "mc4000" => Ok(ChipModel::Mc4000),
+            "mc6000" => Ok(ChipModel::Mc6000),
+            _ => Err(format!("unknown chip model {:?}", s)),
+        }
+    }
+}
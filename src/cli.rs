@@ -0,0 +1,304 @@
+/*
+    Command line argument parsing for the interpreter binary.
+*/
+
+use std::time::Duration;
+
+use rust_shenzhenio::{ChipModel, Limits};
+
+pub const USAGE: &str = "\
+Usage: rust-shenzhenio <command> [options] <file>
+
+Commands:
+    run      Run a program and print its final state
+    check    Parse and validate a program without running it
+    lint     Warn about unreachable code, unused labels and other mistakes
+    trace    Run a program, printing the state after every step
+    score    Run a program and print its cost, power and lines of code
+    fmt      Print a program in canonical style
+    cfg      Print the program's control flow graph in Graphviz DOT
+    optimize Print the program with safe rewrites that save lines and power
+             applied, listing each rewrite on stderr
+    decompile
+             Print the program as structured pseudocode, with the original
+             lines as comments
+    compile  Compile a program in the structured language to assembly for
+             the --chip
+    superopt Search for the shortest or lowest power program that passes
+             the cases in a TOML spec; --timeout stops early with the best
+             found so far
+    debug    Step through a program interactively
+    verify   Run the solution to a puzzle file against its test cases
+    import   Load a solution saved by the game and list its chips; with
+             --timesteps, simulate the board for that long
+    lsp      Serve the Language Server Protocol on stdin and stdout, for
+             editors; takes no file
+    test     Run every .asm file in a directory, or one file, and check it
+             against its `# expect:` and `# expect-error:` comments
+    repl     Try instructions one line at a time on a --chip; takes no file
+    export   Print a saved solution with the code from --program swapped in,
+             ready to copy back into the game
+
+Options:
+    --set <register>=<value>   Set a register before running (repeatable)
+    --config <file>            Load the chip model, registers and inputs from a
+                               TOML file; --chip and --set take precedence
+    --chip <model>             Chip model, mc4000 or mc6000 (default mc6000)
+    --max-steps <n>            Stop after executing n steps
+    --timesteps <n>            Stop after n timesteps
+    --timeout <seconds>        Stop after this much real time
+    --program <chip>=<file>    With verify or export, use this program for a
+                               chip instead of the one the puzzle or solution
+                               has (repeatable)
+    -q, --quiet                Only print errors
+    -v, --verbose              Print the state after every step
+    --json                     With trace, print one JSON object per step
+    --check                    With fmt, fail instead of printing if the file
+                               isn't already formatted
+    --vcd <file>               Write a waveform of the run as a VCD file
+    --coverage                 With run, trace, score or verify, print the
+                               source with how often each line ran and list
+                               lines that never ran or never skipped
+    --lcov <file>              Write the same coverage as an lcov tracefile
+    --profile                  With run, trace, score or verify, print the
+                               source with how often each line ran and its
+                               share of the power, and the costliest loops
+    --svg <file>               Draw inputs and expected against actual outputs
+                               as an SVG timing diagram; with expected outputs
+                               in the config, runs that many timesteps
+    -h, --help                 Print this message";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Run,
+    Check,
+    Lint,
+    Trace,
+    Score,
+    Fmt,
+    Debug,
+    Verify,
+    Import,
+    Export,
+    Lsp,
+    Repl,
+    Test,
+    Cfg,
+    Optimize,
+    Superopt,
+    Compile,
+    Decompile,
+}
+
+#[derive(Debug, PartialEq, PartialOrd)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+#[derive(Debug)]
+pub struct Options {
+    pub command: Command,
+    pub path: String,
+    pub registers: Vec<(String, i64)>,
+    pub config: Option<String>,
+    pub chip: Option<ChipModel>,
+    pub limits: Limits,
+    pub verbosity: Verbosity,
+    pub json: bool,
+    pub check: bool,
+    pub vcd: Option<String>,
+    pub coverage: bool,
+    pub lcov: Option<String>,
+    pub profile: bool,
+    pub svg: Option<String>,
+    pub programs: Vec<(String, String)>,
+}
+
+/// Parse the arguments following the executable name.
+///
+/// `Ok(None)` means help was asked for.
+pub fn parse_args(args: &[String]) -> Result<Option<Options>, String> {
+    let mut args = args.iter();
+    let command = match args.next().map(|s| s.as_str()) {
+        Some("run") => Command::Run,
+        Some("check") => Command::Check,
+        Some("lint") => Command::Lint,
+        Some("trace") => Command::Trace,
+        Some("score") => Command::Score,
+        Some("fmt") => Command::Fmt,
+        Some("cfg") => Command::Cfg,
+        Some("optimize") => Command::Optimize,
+        Some("superopt") => Command::Superopt,
+        Some("compile") => Command::Compile,
+        Some("decompile") => Command::Decompile,
+        Some("debug") => Command::Debug,
+        Some("verify") => Command::Verify,
+        Some("import") => Command::Import,
+        Some("export") => Command::Export,
+        Some("lsp") => Command::Lsp,
+        Some("repl") => Command::Repl,
+        Some("test") => Command::Test,
+        Some("-h") | Some("--help") => return Ok(None),
+        Some(other) => return Err(format!("unknown command {:?}", other)),
+        None => return Err(String::from("no command given")),
+    };
+
+    let mut options = Options {
+        command,
+        path: String::new(),
+        registers: Vec::new(),
+        config: None,
+        chip: None,
+        limits: Limits::default(),
+        verbosity: Verbosity::Normal,
+        json: false,
+        check: false,
+        vcd: None,
+        coverage: false,
+        lcov: None,
+        profile: false,
+        svg: None,
+        programs: Vec::new(),
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-q" | "--quiet" => options.verbosity = Verbosity::Quiet,
+            "-v" | "--verbose" => options.verbosity = Verbosity::Verbose,
+            "--json" => options.json = true,
+            "--check" => options.check = true,
+            "--coverage" => options.coverage = true,
+            "--lcov" => options.lcov = Some(value(arg, args.next())?.to_owned()),
+            "--profile" => options.profile = true,
+            "--vcd" => options.vcd = Some(value(arg, args.next())?.to_owned()),
+            "--svg" => options.svg = Some(value(arg, args.next())?.to_owned()),
+            "--set" => options
+                .registers
+                .push(parse_assignment(value(arg, args.next())?)?),
+            "--program" => {
+                let arg = value(arg, args.next())?;
+                match arg.split_once('=') {
+                    Some((chip, path)) => options.programs.push((chip.to_owned(), path.to_owned())),
+                    None => return Err(format!("expected chip=file, got {:?}", arg)),
+                }
+            }
+            "--config" => options.config = Some(value(arg, args.next())?.to_owned()),
+            "--chip" => options.chip = Some(value(arg, args.next())?.parse()?),
+            "--max-steps" => options.limits.max_steps = Some(number(arg, args.next())?),
+            "--timesteps" => options.limits.max_timesteps = Some(number(arg, args.next())?),
+            "--timeout" => options.limits.timeout = Some(seconds(arg, args.next())?),
+            flag if flag.starts_with('-') => return Err(format!("unknown option {:?}", flag)),
+            path if options.path.is_empty() => options.path = path.to_owned(),
+            extra => return Err(format!("unexpected argument {:?}", extra)),
+        }
+    }
+
+    if options.path.is_empty() && !matches!(options.command, Command::Lsp | Command::Repl) {
+        return Err(String::from("no program file given"));
+    }
+    Ok(Some(options))
+}
+
+fn value<'a>(flag: &str, value: Option<&'a String>) -> Result<&'a str, String> {
+    match value {
+        Some(v) => Ok(v),
+        None => Err(format!("{} needs a value", flag)),
+    }
+}
+
+fn number(flag: &str, arg: Option<&String>) -> Result<usize, String> {
+    let v = value(flag, arg)?;
+    v.parse()
+        .map_err(|_| format!("{} needs a number, got {:?}", flag, v))
+}
+
+fn seconds(flag: &str, arg: Option<&String>) -> Result<Duration, String> {
+    let v = value(flag, arg)?;
+    match v
+        .parse::<f64>()
+        .ok()
+        .and_then(|s| Duration::try_from_secs_f64(s).ok())
+    {
+        Some(d) => Ok(d),
+        None => Err(format!("{} needs a number of seconds, got {:?}", flag, v)),
+    }
+}
+
+/// Parse `register=value`.
+fn parse_assignment(arg: &str) -> Result<(String, i64), String> {
+    let (name, v) = match arg.split_once('=') {
+        Some(v) => v,
+        None => return Err(format!("expected register=value, got {:?}", arg)),
+    };
+    match v.parse() {
+        Ok(n) => Ok((name.to_owned(), n)),
+        Err(_) => Err(format!("{:?} is not a valid value for {}", v, name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, Verbosity, parse_args};
+    use rust_shenzhenio::ChipModel;
+    use std::time::Duration;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_ascii_whitespace()
+            .map(|s| s.to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn parses_run_with_options() {
+        let options = parse_args(&args("run --set acc=-5 --chip mc4000 -q prog.asm"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(options.command, Command::Run);
+        assert_eq!(options.path, "prog.asm");
+        assert_eq!(options.registers, vec![(String::from("acc"), -5)]);
+        assert_eq!(options.chip, Some(ChipModel::Mc4000));
+        assert_eq!(options.verbosity, Verbosity::Quiet);
+    }
+
+    #[test]
+    fn parses_limits() {
+        let options = parse_args(&args("run --max-steps 10 --timeout 0.5 prog.asm"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(options.limits.max_steps, Some(10));
+        assert_eq!(options.limits.max_timesteps, None);
+        assert_eq!(options.limits.timeout, Some(Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn parses_verify() {
+        let options = parse_args(&args("verify --program amp=fast.asm puzzle.toml"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(options.command, Command::Verify);
+        assert_eq!(options.path, "puzzle.toml");
+        assert_eq!(
+            options.programs,
+            vec![(String::from("amp"), String::from("fast.asm"))]
+        );
+        assert!(parse_args(&args("verify --program amp puzzle.toml")).is_err());
+        assert_eq!(
+            parse_args(&args("lsp")).unwrap().unwrap().command,
+            Command::Lsp
+        );
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert!(parse_args(&args("launch prog.asm")).is_err());
+        assert!(parse_args(&args("run")).is_err());
+        assert!(parse_args(&args("run --set acc prog.asm")).is_err());
+        assert!(parse_args(&args("run --max-steps lots prog.asm")).is_err());
+        assert!(parse_args(&args("run --timeout -1 prog.asm")).is_err());
+        assert!(parse_args(&args("run a.asm b.asm")).is_err());
+        assert!(parse_args(&args("run --help")).unwrap().is_none());
+    }
+}
@@ -0,0 +1,920 @@
+/*
+    A compiler from a small structured language to assembly:
+
+        # pass p0 through once it is over 50, counting how often it is
+        count = 0
+        while true {
+            if p0 > 50 {
+                p1 = p0
+                count = count + 1
+            } else {
+                p1 = 0
+            }
+            x0 = count
+            sleep 1
+        }
+
+    Names that aren't registers of the chip are variables, kept in `dat`,
+    or in `acc` when nothing else needs it for arithmetic. Expressions are
+    worked out in `acc` with `+`, `-`, `*` and brackets, and conditions
+    compare two values with `==`, `!=`, `<`, `>`, `<=` or `>=`.
+*/
+
+use std::collections::HashMap;
+
+use crate::chip::ChipModel;
+use crate::instruction::{Arg, Instruction};
+use crate::parse::Line;
+use crate::register;
+
+const KEYWORDS: [&str; 5] = ["if", "else", "while", "sleep", "true"];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Symbol(&'static str),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Number(i64),
+    Name(String),
+    Neg(Box<Expr>),
+    Binary(Box<Expr>, Op, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Condition {
+    True,
+    Compare(Expr, &'static str, Expr),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Statement {
+    Assign {
+        line: usize,
+        name: String,
+        value: Expr,
+    },
+    Sleep {
+        line: usize,
+        duration: Expr,
+    },
+    If {
+        line: usize,
+        condition: Condition,
+        then: Vec<Statement>,
+        otherwise: Vec<Statement>,
+    },
+    While {
+        line: usize,
+        condition: Condition,
+        body: Vec<Statement>,
+    },
+}
+
+/// Compile `source` into a program for `chip`.
+pub fn compile(source: &str, chip: ChipModel) -> Result<Vec<Line>, String> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?,
+        next: 0,
+    };
+    let program = parser.block(false)?;
+
+    let ports: Vec<&str> = chip
+        .registers()
+        .iter()
+        .copied()
+        .filter(|r| !matches!(*r, "acc" | "dat"))
+        .collect();
+    let mut variables = Vec::new();
+    assigned(&program, &ports, &mut variables)?;
+    read(&program, &ports, &variables)?;
+
+    let mut compiler = Compiler {
+        registers: allocate(&program, &variables, chip)?,
+        lines: Vec::new(),
+        condition: None,
+        labels: 0,
+    };
+    compiler.block(&program)?;
+    Ok(compiler.lines)
+}
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, String> {
+    let mut tokens = Vec::new();
+    for (i, text) in source.lines().enumerate() {
+        let line = i + 1;
+        let text = text.split('#').next().unwrap_or("");
+        let mut chars = text.char_indices().peekable();
+        while let Some((start, c)) = chars.next() {
+            let mut word = |accept: fn(char) -> bool| {
+                let mut end = start + c.len_utf8();
+                while let Some(&(i, c)) = chars.peek() {
+                    if !accept(c) {
+                        break;
+                    }
+                    end = i + c.len_utf8();
+                    chars.next();
+                }
+                &text[start..end]
+            };
+            let token = match c {
+                _ if c.is_whitespace() => continue,
+                '0'..='9' => {
+                    let digits = word(|c| c.is_ascii_digit());
+                    match digits.parse() {
+                        Ok(n) if n <= register::MAX => Token::Number(n),
+                        _ => {
+                            return Err(format!(
+                                "line {}: {} is outside the range {} to {}",
+                                line,
+                                digits,
+                                register::MIN,
+                                register::MAX
+                            ));
+                        }
+                    }
+                }
+                _ if c.is_alphabetic() || c == '_' => {
+                    Token::Name(word(|c| c.is_alphanumeric() || c == '_').to_owned())
+                }
+                _ => {
+                    let pair = text.get(start..start + 2).unwrap_or("");
+                    let symbol = ["==", "!=", "<=", ">="]
+                        .into_iter()
+                        .find(|s| *s == pair)
+                        .or(["{", "}", "(", ")", "=", "<", ">", "+", "-", "*"]
+                            .into_iter()
+                            .find(|s| s.starts_with(c)));
+                    match symbol {
+                        Some(s) => {
+                            if s.len() == 2 {
+                                chars.next();
+                            }
+                            Token::Symbol(s)
+                        }
+                        None => return Err(format!("line {}: unexpected {:?}", line, c)),
+                    }
+                }
+            };
+            tokens.push((line, token));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(_, t)| t)
+    }
+
+    /// The line of the next token, or of the last one at the end.
+    fn line(&self) -> usize {
+        match self.tokens.get(self.next).or(self.tokens.last()) {
+            Some((line, _)) => *line,
+            None => 1,
+        }
+    }
+
+    fn error(&self, expected: &str) -> String {
+        let found = match self.peek() {
+            Some(Token::Number(n)) => n.to_string(),
+            Some(Token::Name(name)) => format!("`{}`", name),
+            Some(Token::Symbol(s)) => format!("`{}`", s),
+            None => String::from("the end of the file"),
+        };
+        format!(
+            "line {}: expected {}, found {}",
+            self.line(),
+            expected,
+            found
+        )
+    }
+
+    /// Move past `symbol` if it comes next.
+    fn eat(&mut self, symbol: &str) -> bool {
+        let found = match self.peek() {
+            Some(Token::Symbol(s)) => *s == symbol,
+            Some(Token::Name(name)) => name == symbol,
+            _ => false,
+        };
+        if found {
+            self.next += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), String> {
+        if self.eat(symbol) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("`{}`", symbol)))
+        }
+    }
+
+    /// Statements up to a closing `}`, or to the end of the file.
+    fn block(&mut self, braced: bool) -> Result<Vec<Statement>, String> {
+        let mut statements = Vec::new();
+        loop {
+            if braced && self.eat("}") {
+                return Ok(statements);
+            }
+            if !braced && self.peek().is_none() {
+                return Ok(statements);
+            }
+            statements.push(self.statement()?);
+        }
+    }
+
+    fn braced(&mut self) -> Result<Vec<Statement>, String> {
+        self.expect("{")?;
+        self.block(true)
+    }
+
+    fn statement(&mut self) -> Result<Statement, String> {
+        let line = self.line();
+        if self.eat("if") {
+            let condition = self.condition()?;
+            let then = self.braced()?;
+            let otherwise = if !self.eat("else") {
+                Vec::new()
+            } else if matches!(self.peek(), Some(Token::Name(n)) if n == "if") {
+                vec![self.statement()?]
+            } else {
+                self.braced()?
+            };
+            return Ok(Statement::If {
+                line,
+                condition,
+                then,
+                otherwise,
+            });
+        }
+        if self.eat("while") {
+            let condition = self.condition()?;
+            let body = self.braced()?;
+            return Ok(Statement::While {
+                line,
+                condition,
+                body,
+            });
+        }
+        if self.eat("sleep") {
+            let duration = self.expr()?;
+            return Ok(Statement::Sleep { line, duration });
+        }
+        match self.peek() {
+            Some(Token::Name(name)) if !KEYWORDS.contains(&name.as_str()) => {
+                let name = name.clone();
+                self.next += 1;
+                self.expect("=")?;
+                let value = self.expr()?;
+                Ok(Statement::Assign { line, name, value })
+            }
+            _ => Err(self.error("a statement")),
+        }
+    }
+
+    fn condition(&mut self) -> Result<Condition, String> {
+        if self.eat("true") {
+            return Ok(Condition::True);
+        }
+        let left = self.expr()?;
+        let compare = match self.peek() {
+            Some(Token::Symbol(s @ ("==" | "!=" | "<" | ">" | "<=" | ">="))) => *s,
+            _ => return Err(self.error("a comparison")),
+        };
+        self.next += 1;
+        Ok(Condition::Compare(left, compare, self.expr()?))
+    }
+
+    fn expr(&mut self) -> Result<Expr, String> {
+        let mut left = self.term()?;
+        loop {
+            let op = if self.eat("+") {
+                Op::Add
+            } else if self.eat("-") {
+                Op::Sub
+            } else {
+                return Ok(left);
+            };
+            left = Expr::Binary(Box::new(left), op, Box::new(self.term()?));
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr, String> {
+        let mut left = self.unary()?;
+        while self.eat("*") {
+            left = Expr::Binary(Box::new(left), Op::Mul, Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat("-") {
+            return Ok(match self.unary()? {
+                Expr::Number(n) => Expr::Number(-n),
+                e => Expr::Neg(Box::new(e)),
+            });
+        }
+        if self.eat("(") {
+            let e = self.expr()?;
+            self.expect(")")?;
+            return Ok(e);
+        }
+        let e = match self.peek() {
+            Some(Token::Number(n)) => Expr::Number(*n),
+            Some(Token::Name(name)) if !KEYWORDS.contains(&name.as_str()) => {
+                Expr::Name(name.clone())
+            }
+            _ => return Err(self.error("a number, name or `(`")),
+        };
+        self.next += 1;
+        Ok(e)
+    }
+}
+
+impl Expr {
+    /// Whether the expression is a single operand, needing no arithmetic.
+    fn is_operand(&self) -> bool {
+        matches!(self, Expr::Number(_) | Expr::Name(_))
+    }
+
+    /// The operand moved into `acc` first when working the expression out,
+    /// or `None` if it can't be worked out in `acc` alone.
+    fn first_load(&self) -> Option<&Expr> {
+        match self {
+            Expr::Number(_) | Expr::Name(_) => Some(self),
+            Expr::Neg(e) => e.first_load(),
+            Expr::Binary(left, _, right) if right.is_operand() => left.first_load(),
+            Expr::Binary(left, _, right) if left.is_operand() => right.first_load(),
+            Expr::Binary(..) => None,
+        }
+    }
+
+    fn names<'a>(&'a self, out: &mut Vec<&'a str>) {
+        match self {
+            Expr::Number(_) => (),
+            Expr::Name(name) => out.push(name),
+            Expr::Neg(e) => e.names(out),
+            Expr::Binary(left, _, right) => {
+                left.names(out);
+                right.names(out);
+            }
+        }
+    }
+}
+
+/// Collect the variables `statements` assign to, in order of first
+/// assignment.
+fn assigned(statements: &[Statement], ports: &[&str], out: &mut Vec<String>) -> Result<(), String> {
+    for statement in statements {
+        match statement {
+            Statement::Assign { line, name, .. } => {
+                if matches!(name.as_str(), "acc" | "dat") {
+                    return Err(format!(
+                        "line {}: {} holds variables, so it can't be assigned directly",
+                        line, name
+                    ));
+                }
+                if !ports.contains(&name.as_str()) && !out.contains(name) {
+                    out.push(name.clone());
+                }
+            }
+            Statement::Sleep { .. } => (),
+            Statement::If {
+                then, otherwise, ..
+            } => {
+                assigned(then, ports, out)?;
+                assigned(otherwise, ports, out)?;
+            }
+            Statement::While { body, .. } => assigned(body, ports, out)?,
+        }
+    }
+    Ok(())
+}
+
+/// Make sure every name read is a port or a variable.
+fn read(statements: &[Statement], ports: &[&str], variables: &[String]) -> Result<(), String> {
+    let check = |line: usize, e: &Expr| {
+        let mut names = Vec::new();
+        e.names(&mut names);
+        match names
+            .into_iter()
+            .find(|n| !ports.contains(n) && !variables.iter().any(|v| v == n))
+        {
+            Some(name) => Err(format!(
+                "line {}: {} is not a port and is never assigned",
+                line, name
+            )),
+            None => Ok(()),
+        }
+    };
+    for statement in statements {
+        match statement {
+            Statement::Assign { line, value, .. } => check(*line, value)?,
+            Statement::Sleep { line, duration } => check(*line, duration)?,
+            Statement::If {
+                line,
+                condition,
+                then,
+                otherwise,
+            } => {
+                if let Condition::Compare(left, _, right) = condition {
+                    check(*line, left)?;
+                    check(*line, right)?;
+                }
+                read(then, ports, variables)?;
+                read(otherwise, ports, variables)?;
+            }
+            Statement::While {
+                line,
+                condition,
+                body,
+            } => {
+                if let Condition::Compare(left, _, right) = condition {
+                    check(*line, left)?;
+                    check(*line, right)?;
+                }
+                read(body, ports, variables)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Every expression worked out in `acc`, with the variable it is assigned
+/// to, if any.
+fn arithmetic<'a>(statements: &'a [Statement], out: &mut Vec<(Option<&'a str>, &'a Expr)>) {
+    let condition = |c: &'a Condition, out: &mut Vec<_>| {
+        if let Condition::Compare(left, _, right) = c {
+            for e in [left, right] {
+                if !e.is_operand() {
+                    out.push((None, e));
+                }
+            }
+        }
+    };
+    for statement in statements {
+        match statement {
+            Statement::Assign { name, value, .. } if !value.is_operand() => {
+                out.push((Some(name), value));
+            }
+            Statement::Assign { .. } => (),
+            Statement::Sleep { duration, .. } if !duration.is_operand() => {
+                out.push((None, duration));
+            }
+            Statement::Sleep { .. } => (),
+            Statement::If {
+                condition: c,
+                then,
+                otherwise,
+                ..
+            } => {
+                condition(c, out);
+                arithmetic(then, out);
+                arithmetic(otherwise, out);
+            }
+            Statement::While {
+                condition: c, body, ..
+            } => {
+                condition(c, out);
+                arithmetic(body, out);
+            }
+        }
+    }
+}
+
+/// Pick a register for each variable. A variable can only share `acc`
+/// with the arithmetic if every expression updates it in place, like
+/// `n = n * 2 + 1`, so the rest go in `dat`.
+fn allocate(
+    program: &[Statement],
+    variables: &[String],
+    chip: ChipModel,
+) -> Result<HashMap<String, &'static str>, String> {
+    let mut uses = Vec::new();
+    arithmetic(program, &mut uses);
+    let in_place = |variable: &str, (to, e): &(Option<&str>, &Expr)| {
+        let mut names = Vec::new();
+        e.names(&mut names);
+        *to == Some(variable)
+            && e.first_load() == Some(&Expr::Name(variable.to_owned()))
+            && names.iter().filter(|n| **n == variable).count() == 1
+    };
+    let in_acc = variables
+        .iter()
+        .find(|v| uses.iter().all(|u| in_place(v, u)));
+
+    let mut free: Vec<&'static str> = vec!["acc", "dat"];
+    free.retain(|r| chip.registers().contains(r) && (*r != "acc" || in_acc.is_some()));
+    if variables.len() > free.len() {
+        let room = match free.len() {
+            0 => String::from("no register free for them"),
+            n => format!("room for only {} ({})", n, free.join(", ")),
+        };
+        let reason = if in_acc.is_none() && !uses.is_empty() {
+            "; acc is needed to work out expressions"
+        } else {
+            ""
+        };
+        return Err(format!(
+            "not enough registers for the variables {}: the {} has {}{}",
+            variables.join(", "),
+            chip.name(),
+            room,
+            reason
+        ));
+    }
+
+    let mut registers = HashMap::new();
+    let others = variables.iter().filter(|v| Some(*v) != in_acc);
+    if let Some(v) = in_acc {
+        registers.insert(v.clone(), "acc");
+    }
+    for (v, register) in others.zip(free.into_iter().filter(|r| *r != "acc")) {
+        registers.insert(v.clone(), register);
+    }
+    Ok(registers)
+}
+
+struct Compiler {
+    registers: HashMap<String, &'static str>,
+    lines: Vec<Line>,
+    /// The `+` or `-` put on lines while compiling a branch of an `if`.
+    condition: Option<Arg>,
+    labels: usize,
+}
+
+impl Compiler {
+    fn emit(&mut self, instr: Instruction, mut args: Vec<Arg>) {
+        args.extend(self.condition.clone());
+        self.lines.push((instr, args));
+    }
+
+    fn label(&mut self, name: &str) {
+        self.lines
+            .push((Instruction::Label, vec![Arg::Label(name.to_owned())]));
+    }
+
+    /// A number for naming the labels of the next `if` or `while`.
+    fn next_label(&mut self) -> usize {
+        self.labels += 1;
+        self.labels
+    }
+
+    /// The register a variable lives in, or the port itself.
+    fn register(&self, name: &str) -> Arg {
+        Arg::Register(match self.registers.get(name) {
+            Some(register) => register.to_string(),
+            None => name.to_owned(),
+        })
+    }
+
+    fn operand(&self, e: &Expr) -> Option<Arg> {
+        match e {
+            Expr::Number(n) => Some(Arg::Number(*n)),
+            Expr::Name(name) => Some(self.register(name)),
+            _ => None,
+        }
+    }
+
+    /// Work `e` out into `acc`.
+    fn compute(&mut self, e: &Expr, line: usize) -> Result<(), String> {
+        let acc = Arg::Register(String::from("acc"));
+        if let Some(a) = self.operand(e) {
+            if a != acc {
+                self.emit(Instruction::Mov, vec![a, acc]);
+            }
+            return Ok(());
+        }
+        let instr = |op: Op| match op {
+            Op::Add => Instruction::Add,
+            Op::Sub => Instruction::Sub,
+            Op::Mul => Instruction::Mul,
+        };
+        match e {
+            Expr::Neg(e) => {
+                self.compute(e, line)?;
+                self.emit(Instruction::Mul, vec![Arg::Number(-1)]);
+            }
+            Expr::Binary(left, op, right) => match (self.operand(left), self.operand(right)) {
+                (_, Some(b)) => {
+                    self.compute(left, line)?;
+                    self.emit(instr(*op), vec![b]);
+                }
+                (Some(a), None) => {
+                    self.compute(right, line)?;
+                    if *op == Op::Sub {
+                        // a - right is -right + a
+                        self.emit(Instruction::Mul, vec![Arg::Number(-1)]);
+                        self.emit(Instruction::Add, vec![a]);
+                    } else {
+                        self.emit(instr(*op), vec![a]);
+                    }
+                }
+                (None, None) => {
+                    return Err(format!(
+                        "line {}: an expression with arithmetic on both sides of an \
+                         operator needs a second register; store one side in a \
+                         variable first",
+                        line
+                    ));
+                }
+            },
+            _ => unreachable!("operands are handled above"),
+        }
+        Ok(())
+    }
+
+    /// Emit the test for `condition`, returning the prefix of lines that
+    /// should run when it holds.
+    fn test(&mut self, condition: &Condition, line: usize) -> Result<Option<Arg>, String> {
+        let (left, compare, right) = match condition {
+            Condition::True => return Ok(None),
+            Condition::Compare(left, compare, right) => (left, *compare, right),
+        };
+        let acc = Arg::Register(String::from("acc"));
+        let (a, b) = match (self.operand(left), self.operand(right)) {
+            (Some(a), Some(b)) => (a, b),
+            (None, Some(b)) => {
+                self.compute(left, line)?;
+                (acc, b)
+            }
+            (Some(a), None) => {
+                self.compute(right, line)?;
+                (a, acc)
+            }
+            (None, None) => {
+                return Err(format!(
+                    "line {}: a comparison with arithmetic on both sides needs a \
+                     second register; store one side in a variable first",
+                    line
+                ));
+            }
+        };
+        let (instr, holds) = match compare {
+            "==" => (Instruction::Teq, Arg::BranchTrue),
+            "!=" => (Instruction::Teq, Arg::BranchFalse),
+            "<" => (Instruction::Tlt, Arg::BranchTrue),
+            ">" => (Instruction::Tgt, Arg::BranchTrue),
+            "<=" => (Instruction::Tgt, Arg::BranchFalse),
+            _ => (Instruction::Tlt, Arg::BranchFalse),
+        };
+        self.emit(instr, vec![a, b]);
+        Ok(Some(holds))
+    }
+
+    fn block(&mut self, statements: &[Statement]) -> Result<(), String> {
+        for statement in statements {
+            self.statement(statement)?;
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, statement: &Statement) -> Result<(), String> {
+        let acc = Arg::Register(String::from("acc"));
+        match statement {
+            Statement::Assign { line, name, value } => {
+                let to = self.register(name);
+                match self.operand(value) {
+                    Some(a) if a == to => (),
+                    Some(a) => self.emit(Instruction::Mov, vec![a, to]),
+                    None => {
+                        self.compute(value, *line)?;
+                        if to != acc {
+                            self.emit(Instruction::Mov, vec![acc, to]);
+                        }
+                    }
+                }
+            }
+            Statement::Sleep { line, duration } => {
+                let a = match self.operand(duration) {
+                    Some(a) => a,
+                    None => {
+                        self.compute(duration, *line)?;
+                        acc
+                    }
+                };
+                self.emit(Instruction::Slp, vec![a]);
+            }
+            Statement::If {
+                line,
+                condition,
+                then,
+                otherwise,
+            } => {
+                let Some(holds) = self.test(condition, *line)? else {
+                    return self.block(then);
+                };
+                let opposite = match holds {
+                    Arg::BranchTrue => Arg::BranchFalse,
+                    _ => Arg::BranchTrue,
+                };
+                // Branches without control flow of their own become `+` and
+                // `-` lines instead of jumps.
+                let simple =
+                    |s: &Statement| matches!(s, Statement::Assign { .. } | Statement::Sleep { .. });
+                if then.iter().chain(otherwise).all(simple) {
+                    self.condition = Some(holds);
+                    self.block(then)?;
+                    self.condition = Some(opposite);
+                    self.block(otherwise)?;
+                    self.condition = None;
+                    return Ok(());
+                }
+                let n = self.next_label();
+                let (skip, end) = (format!("else{}", n), format!("end{}", n));
+                let target = if otherwise.is_empty() { &end } else { &skip };
+                self.lines
+                    .push((Instruction::Jmp, vec![Arg::Label(target.clone()), opposite]));
+                self.block(then)?;
+                if !otherwise.is_empty() {
+                    self.emit(Instruction::Jmp, vec![Arg::Label(end.clone())]);
+                    self.label(&skip);
+                    self.block(otherwise)?;
+                }
+                self.label(&end);
+            }
+            Statement::While {
+                line,
+                condition,
+                body,
+            } => {
+                let n = self.next_label();
+                let (start, end) = (format!("loop{}", n), format!("end{}", n));
+                self.label(&start);
+                let holds = self.test(condition, *line)?;
+                if let Some(holds) = &holds {
+                    let opposite = match holds {
+                        Arg::BranchTrue => Arg::BranchFalse,
+                        _ => Arg::BranchTrue,
+                    };
+                    self.lines
+                        .push((Instruction::Jmp, vec![Arg::Label(end.clone()), opposite]));
+                }
+                self.block(body)?;
+                self.emit(Instruction::Jmp, vec![Arg::Label(start)]);
+                if holds.is_some() {
+                    self.label(&end);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::compile;
+    use crate::chip::ChipModel;
+    use crate::format;
+    use crate::machine::Machine;
+
+    fn assembly(source: &str, chip: ChipModel) -> String {
+        format::print(&compile(source, chip).unwrap())
+    }
+
+    #[test]
+    fn compiles_loops_and_branches() {
+        let source = "\
+n = 0
+while n < p0 {
+    n = n + 1
+}
+if n > 50 {
+    p1 = 100
+} else {
+    n = n * 2
+    p1 = n
+}";
+        let program = assembly(source, ChipModel::Mc4000);
+        assert_eq!(
+            program,
+            "    mov 0 acc
+loop1:
+    tlt acc p0
+  - jmp end1
+    add 1
+    jmp loop1
+end1:
+    tgt acc 50
+  + mov 100 p1
+  - mul 2
+  - mov acc p1
+"
+        );
+        for (input, output) in [(7, 14), (60, 100), (0, 0)] {
+            let mut machine = Machine::load_chip(&program, ChipModel::Mc4000).unwrap();
+            machine.set_register("p0", input).unwrap();
+            machine.run();
+            assert_eq!(machine.register("p1"), Some(output), "p0 = {}", input);
+        }
+    }
+
+    #[test]
+    fn uses_jumps_around_nested_control_flow() {
+        let source = "\
+total = 0
+while true {
+    if p0 != 0 {
+        total = p0 - (total - 3)
+        if total > 10 { x0 = 1 }
+    } else if p1 == 0 {
+        sleep 2
+    }
+    sleep total * 2
+}";
+        let program = assembly(source, ChipModel::Mc6000);
+        assert_eq!(
+            program,
+            "    mov 0 dat
+loop1:
+    teq p0 0
+  + jmp else2
+    mov dat acc
+    sub 3
+    mul -1
+    add p0
+    mov acc dat
+    tgt dat 10
+  + mov 1 x0
+    jmp end2
+else2:
+    teq p1 0
+  + slp 2
+end2:
+    mov dat acc
+    mul 2
+    slp acc
+    jmp loop1
+"
+        );
+        Machine::load(&program).unwrap();
+    }
+
+    #[test]
+    fn explains_what_does_not_fit() {
+        let counters = "a = a + 1\nb = b + 1";
+        assert_eq!(
+            compile(counters, ChipModel::Mc6000),
+            Err(String::from(
+                "not enough registers for the variables a, b: the MC6000 has room for \
+                 only 1 (dat); \
+                 acc is needed to work out expressions"
+            ))
+        );
+        assert_eq!(
+            compile("a = p0\nb = p1", ChipModel::Mc4000),
+            Err(String::from(
+                "not enough registers for the variables a, b: the MC4000 has room for \
+                 only 1 (acc)"
+            ))
+        );
+        assert_eq!(
+            compile("a = p0 * 2", ChipModel::Mc4000),
+            Err(String::from(
+                "not enough registers for the variables a: the MC4000 has no register \
+                 free for them; \
+                 acc is needed to work out expressions"
+            ))
+        );
+        assert!(compile("a = a + 1", ChipModel::Mc4000).is_ok());
+        assert_eq!(
+            compile("x0 = (p0 + 1) * (p1 + 1)", ChipModel::Mc6000)
+                .unwrap_err()
+                .split(';')
+                .next(),
+            Some(
+                "line 1: an expression with arithmetic on both sides of an operator needs a second register"
+            )
+        );
+        assert_eq!(
+            compile("p1 = q", ChipModel::Mc6000),
+            Err(String::from(
+                "line 1: q is not a port and is never assigned"
+            ))
+        );
+        assert_eq!(
+            compile("if p0 > 1 {\n  p1 = 1\n", ChipModel::Mc6000),
+            Err(String::from(
+                "line 2: expected a statement, found the end of the file"
+            ))
+        );
+        assert!(compile("p1 = 1000", ChipModel::Mc6000).is_err());
+    }
+}
@@ -0,0 +1,219 @@
+/*
+    Starting conditions for a run, loaded from a configuration file.
+*/
+
+use std::time::Duration;
+
+use crate::chip::ChipModel;
+use crate::json::Json;
+use crate::limits::Limits;
+use crate::machine::Machine;
+use crate::toml::{self, Table, Value};
+
+/// The chip model, initial register values and input values to run a
+/// program with.
+///
+/// ```toml
+/// chip = "mc4000"
+///
+/// [registers]
+/// acc = 5
+///
+/// [inputs]
+/// p0 = [0, 0, 50, 100]
+///
+/// [expected]
+/// p1 = [0, 0, 0, 100]
+///
+/// [limits]
+/// max_steps = 100000
+/// max_timesteps = 500
+/// timeout_ms = 2000
+/// ```
+///
+/// Each input lists the value a register is driven to at each timestep, and
+/// each expected output the value a register should hold at the end of each
+/// timestep. The same settings can be given as JSON, with an object for each
+/// table:
+///
+/// ```json
+/// {"chip": "mc4000", "registers": {"acc": 5}, "inputs": {"p0": [0, 0, 50, 100]}}
+/// ```
+#[derive(Debug, Default, PartialEq)]
+pub struct Config {
+    pub chip: Option<ChipModel>,
+    pub registers: Vec<(String, i64)>,
+    pub inputs: Vec<(String, Vec<i64>)>,
+    pub expected: Vec<(String, Vec<i64>)>,
+    pub limits: Limits,
+}
+
+impl Config {
+    pub fn parse(source: &str) -> Result<Config, String> {
+        Config::from_tables(toml::parse(source)?)
+    }
+
+    /// Read the same settings from a JSON object.
+    pub fn parse_json(source: &str) -> Result<Config, String> {
+        let entries = match Json::parse(source)? {
+            Json::Object(v) => v,
+            _ => return Err(String::from("expected an object")),
+        };
+        let mut tables = vec![Table {
+            name: String::new(),
+            entries: Vec::new(),
+        }];
+        for (key, value) in entries {
+            match value {
+                Json::Object(inner) => {
+                    let entries = inner
+                        .into_iter()
+                        .map(|(k, v)| Ok((k, value_of(v)?)))
+                        .collect::<Result<_, String>>()
+                        .map_err(|e| format!("{}: {}", key, e))?;
+                    tables.push(Table { name: key, entries });
+                }
+                v => {
+                    let v = value_of(v).map_err(|e| format!("{}: {}", key, e))?;
+                    tables[0].entries.push((key, v));
+                }
+            }
+        }
+        Config::from_tables(tables)
+    }
+
+    fn from_tables(tables: Vec<Table>) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut seen: Vec<(&str, &str)> = Vec::new();
+        for table in &tables {
+            for (key, value) in &table.entries {
+                if seen.contains(&(table.name.as_str(), key.as_str())) {
+                    return Err(format!("{} is set twice", key));
+                }
+                seen.push((&table.name, key));
+                let context = |e: String| format!("{}: {}", key, e);
+                match table.name.as_str() {
+                    "" if key == "chip" => {
+                        config.chip = Some(value.as_str().map_err(context)?.parse()?);
+                    }
+                    "registers" => {
+                        let v = value.as_integer().map_err(context)?;
+                        config.registers.push((key.to_owned(), v));
+                    }
+                    "inputs" => {
+                        let v = value.as_integers().map_err(context)?;
+                        config.inputs.push((key.to_owned(), v));
+                    }
+                    "expected" => {
+                        let v = value.as_integers().map_err(context)?;
+                        config.expected.push((key.to_owned(), v));
+                    }
+                    "limits" => {
+                        let v = value.as_integer().map_err(context)?;
+                        let v = usize::try_from(v)
+                            .map_err(|_| context(format!("{} is negative", v)))?;
+                        match key.as_str() {
+                            "max_steps" => config.limits.max_steps = Some(v),
+                            "max_timesteps" => config.limits.max_timesteps = Some(v),
+                            "timeout_ms" => {
+                                config.limits.timeout = Some(Duration::from_millis(v as u64))
+                            }
+                            _ => return Err(format!("unknown limit {:?}", key)),
+                        }
+                    }
+                    "" => return Err(format!("unknown setting {:?}", key)),
+                    name => return Err(format!("unknown section [{}]", name)),
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    /// Set the registers and inputs on a machine loaded for this config,
+    /// checking that every expected output is a register it has.
+    pub fn apply(&self, machine: &mut Machine) -> Result<(), String> {
+        for (name, value) in &self.registers {
+            machine.set_register(name, *value)?;
+        }
+        for (name, values) in &self.inputs {
+            machine.set_input(name, values.to_owned())?;
+        }
+        for (name, _) in &self.expected {
+            if machine.register(name).is_none() {
+                return Err(format!(
+                    "{} has no register called {:?}",
+                    machine.chip().name(),
+                    name
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A JSON value as the TOML value it stands for.
+fn value_of(json: Json) -> Result<Value, String> {
+    match json {
+        Json::Number(v) => Ok(Value::Integer(v)),
+        Json::String(v) => Ok(Value::String(v)),
+        Json::Bool(v) => Ok(Value::Boolean(v)),
+        Json::Array(values) => values
+            .into_iter()
+            .map(value_of)
+            .collect::<Result<_, _>>()
+            .map(Value::Array),
+        v => Err(format!("unsupported value {}", v)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+    use crate::chip::ChipModel;
+    use crate::machine::Machine;
+
+    #[test]
+    fn applies_to_machine() {
+        let source = "chip = \"MC4000\"\n[registers]\nacc = -5\n[inputs]\np0 = [100]\n";
+        let config = Config::parse(source).unwrap();
+        assert_eq!(config.chip, Some(ChipModel::Mc4000));
+
+        let mut machine = Machine::load_chip("add p0", config.chip.unwrap()).unwrap();
+        config.apply(&mut machine).unwrap();
+        machine.run();
+        assert_eq!(machine.register("acc"), Some(95));
+    }
+
+    #[test]
+    fn reads_json() {
+        let source = r#"{"chip": "mc4000", "registers": {"acc": -5}, "inputs": {"p0": [100]},
+                         "limits": {"max_steps": 10}}"#;
+        let config = Config::parse_json(source).unwrap();
+        let toml = "chip = \"MC4000\"\n[registers]\nacc = -5\n[inputs]\np0 = [100]\n\
+                    [limits]\nmax_steps = 10\n";
+        assert_eq!(config, Config::parse(toml).unwrap());
+        assert!(Config::parse_json("[1]").is_err());
+        assert!(Config::parse_json(r#"{"registers": {"acc": null}}"#).is_err());
+    }
+
+    #[test]
+    fn rejects_settings_given_twice() {
+        assert!(Config::parse("[registers]\nacc = 1\n[registers]\nacc = 2").is_err());
+        assert!(Config::parse_json(r#"{"chip": "mc4000", "chip": "mc6000"}"#).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_settings() {
+        assert!(Config::parse("speed = 3").is_err());
+        assert!(Config::parse("[outputs]\np1 = 3").is_err());
+        assert!(Config::parse("[registers]\nacc = \"five\"").is_err());
+        assert!(Config::parse("[limits]\nmax_steps = -1").is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_expected_outputs() {
+        let config = Config::parse("[expected]\np9 = [1]").unwrap();
+        let mut machine = Machine::load("nop").unwrap();
+        assert!(config.apply(&mut machine).is_err());
+    }
+}
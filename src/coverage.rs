@@ -0,0 +1,244 @@
+/*
+    Which lines of a program ran, summed over any number of runs, as
+    annotated source or an lcov tracefile.
+*/
+
+use std::fmt::Write;
+
+use crate::instruction::{Arg, Instruction};
+use crate::machine::Machine;
+
+/// Counts for one instruction of the program.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// Position in the parsed program.
+    pub index: usize,
+    /// The source line, or the position in the program for machines built
+    /// without source.
+    pub line: usize,
+    /// Whether the line has a `+` or `-` prefix.
+    pub conditional: bool,
+    pub executed: usize,
+    pub skipped: usize,
+}
+
+/// Execution counts for every instruction in a program. Labels are left out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Coverage {
+    entries: Vec<Entry>,
+}
+
+impl Coverage {
+    /// Empty counts for the program `machine` runs.
+    pub fn new(machine: &Machine) -> Coverage {
+        let entries = machine
+            .program()
+            .iter()
+            .enumerate()
+            .filter(|(_, (instr, _))| *instr != Instruction::Label)
+            .map(|(i, (_, args))| Entry {
+                index: i,
+                line: machine.line_of(i).unwrap_or(i + 1),
+                conditional: args
+                    .iter()
+                    .any(|a| matches!(a, Arg::BranchTrue | Arg::BranchFalse)),
+                executed: 0,
+                skipped: 0,
+            })
+            .collect();
+        Coverage { entries }
+    }
+
+    /// The counts from one run.
+    pub fn of(machine: &Machine) -> Coverage {
+        let mut coverage = Coverage::new(machine);
+        coverage.add(machine);
+        coverage
+    }
+
+    /// Add the counts from a run of the same program.
+    pub fn add(&mut self, machine: &Machine) {
+        for entry in self.entries.iter_mut() {
+            entry.executed += machine.executions()[entry.index];
+            entry.skipped += machine.skips()[entry.index];
+        }
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.iter()
+    }
+
+    /// Lines that need attention: ones that never ran, and conditional lines
+    /// that always went the same way.
+    pub fn findings(&self) -> Vec<String> {
+        self.entries()
+            .filter_map(|e| match (e.executed, e.skipped) {
+                (0, 0) => Some(format!("line {}: never reached", e.line)),
+                (0, skipped) => Some(format!(
+                    "line {}: skipped every time it was reached ({} times)",
+                    e.line, skipped
+                )),
+                (_, 0) if e.conditional => Some(format!(
+                    "line {}: ran every time it was reached, never skipped",
+                    e.line
+                )),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The source with each instruction's count in front of it, `#####` for
+    /// lines that never ran and `-` for lines with no instruction, followed
+    /// by the findings and totals.
+    pub fn report(&self, source: &str) -> String {
+        let mut out = String::new();
+        for (i, text) in source.lines().enumerate() {
+            match self.entries().find(|e| e.line == i + 1) {
+                Some(e) => {
+                    let count = match e.executed {
+                        0 => String::from("#####"),
+                        n => n.to_string(),
+                    };
+                    let _ = write!(out, "{:>9}:{:>5}:{}", count, i + 1, text);
+                    if e.conditional {
+                        let _ = write!(out, "  [skipped {}]", e.skipped);
+                    }
+                    out.push('\n');
+                }
+                None => {
+                    let _ = writeln!(out, "{:>9}:{:>5}:{}", "-", i + 1, text);
+                }
+            }
+        }
+        out.push('\n');
+        for finding in self.findings() {
+            let _ = writeln!(out, "{}", finding);
+        }
+        let (lines, hit) = self.lines();
+        let (branches, taken) = self.branches();
+        let _ = writeln!(
+            out,
+            "lines:    {} of {} ran ({}%)",
+            hit,
+            lines,
+            coverage_percent(hit, lines)
+        );
+        let _ = writeln!(
+            out,
+            "branches: {} of {} taken ({}%)",
+            taken,
+            branches,
+            coverage_percent(taken, branches)
+        );
+        out
+    }
+
+    /// An lcov record for the program at `path`. Each conditional line is a
+    /// branch with two outcomes: it ran, or it was skipped.
+    pub fn lcov(&self, path: &str) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "TN:");
+        let _ = writeln!(out, "SF:{}", path);
+        for (block, e) in self.entries().filter(|e| e.conditional).enumerate() {
+            let _ = writeln!(out, "BRDA:{},{},0,{}", e.line, block, e.executed);
+            let _ = writeln!(out, "BRDA:{},{},1,{}", e.line, block, e.skipped);
+        }
+        let (branches, taken) = self.branches();
+        let _ = writeln!(out, "BRF:{}", branches);
+        let _ = writeln!(out, "BRH:{}", taken);
+        for e in self.entries() {
+            let _ = writeln!(out, "DA:{},{}", e.line, e.executed);
+        }
+        let (lines, hit) = self.lines();
+        let _ = writeln!(out, "LF:{}", lines);
+        let _ = writeln!(out, "LH:{}", hit);
+        let _ = writeln!(out, "end_of_record");
+        out
+    }
+
+    /// How many instructions there are and how many of them ran.
+    fn lines(&self) -> (usize, usize) {
+        let hit = self.entries().filter(|e| e.executed > 0).count();
+        (self.entries.len(), hit)
+    }
+
+    /// How many branch outcomes there are and how many of them happened.
+    fn branches(&self) -> (usize, usize) {
+        let conditional: Vec<&Entry> = self.entries().filter(|e| e.conditional).collect();
+        let taken = conditional
+            .iter()
+            .map(|e| (e.executed > 0) as usize + (e.skipped > 0) as usize)
+            .sum();
+        (2 * conditional.len(), taken)
+    }
+}
+
+/// How much of `whole` is covered by `part`, in whole percent. With nothing
+/// to cover, everything is covered: 100.
+fn coverage_percent(part: usize, whole: usize) -> usize {
+    (part * 100).checked_div(whole).unwrap_or(100)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Coverage;
+    use crate::machine::Machine;
+
+    const PROGRAM: &str = "\
+mov 2 acc
+loop:
+  sub 1
+  teq acc 0
+  - jmp loop
+  + nop
+  + jmp done
+  add 5
+done:";
+
+    fn coverage() -> Coverage {
+        let mut coverage = Coverage::new(&Machine::load(PROGRAM).unwrap());
+        for _ in 0..2 {
+            let mut machine = Machine::load(PROGRAM).unwrap();
+            machine.run();
+            coverage.add(&machine);
+        }
+        coverage
+    }
+
+    #[test]
+    fn finds_lines_that_never_ran() {
+        let coverage = coverage();
+        assert_eq!(
+            coverage.findings(),
+            vec![
+                "line 6: ran every time it was reached, never skipped",
+                "line 7: ran every time it was reached, never skipped",
+                "line 8: never reached",
+            ]
+        );
+        let report = coverage.report(PROGRAM);
+        assert!(report.contains("        4:    3:  sub 1\n"), "{}", report);
+        assert!(
+            report.contains("        2:    5:  - jmp loop  [skipped 2]\n"),
+            "{}",
+            report
+        );
+        assert!(report.contains("    #####:    8:  add 5\n"), "{}", report);
+        assert!(report.contains("        -:    9:done:\n"), "{}", report);
+        assert!(report.contains("lines:    6 of 7 ran (85%)"), "{}", report);
+        assert!(
+            report.contains("branches: 4 of 6 taken (66%)"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn writes_lcov() {
+        let lcov = coverage().lcov("loop.asm");
+        assert!(lcov.starts_with("TN:\nSF:loop.asm\nBRDA:5,0,0,2\nBRDA:5,0,1,2\n"));
+        assert!(lcov.contains("BRF:6\nBRH:4\n"));
+        assert!(lcov.contains("DA:8,0\n"));
+        assert!(lcov.ends_with("LF:7\nLH:6\nend_of_record\n"));
+    }
+}
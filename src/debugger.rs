@@ -0,0 +1,532 @@
+/*
+    An interactive step debugger for a single machine.
+*/
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Write};
+
+use crate::limits::{Limits, Outcome, Run};
+use crate::machine::{Machine, Snapshot};
+
+/// Lines of source shown either side of the current line by `list`.
+const CONTEXT: usize = 3;
+
+/// How many past states are kept for stepping backwards. The oldest are
+/// dropped first.
+const MAX_HISTORY: usize = 100_000;
+
+const HELP: &str = "\
+Commands:
+    step [n], s [n]          Execute the next n instructions (default 1)
+    continue, c              Run until a breakpoint, watchpoint or the end
+    back [n]                 Undo the last n instructions (default 1)
+    back-timestep, bt        Go back to the start of the last timestep that ran
+    rewind <register>        Go back to just before the register was last written
+    break <label|line>, b    Stop before the instruction at a label or line
+    delete <label|line>      Remove a breakpoint
+    watch <register>, w      Stop after the register changes
+    unwatch <register>       Remove a watchpoint
+    info                     List breakpoints and watchpoints
+    print [register], p      Show registers, pins and flags, or one register
+    list, l                  Show the source around the current line
+    help, h                  Show this message
+    quit, q                  Leave the debugger
+An empty line repeats the previous command.";
+
+/// Why execution handed control back to the user.
+#[derive(Debug, PartialEq)]
+pub enum Stop {
+    /// A single step finished.
+    Stepped,
+    /// About to run the instruction at this program index.
+    Breakpoint(usize),
+    /// A watched register changed.
+    Watch {
+        register: String,
+        old: i64,
+        new: i64,
+    },
+    Halted,
+    /// One of the run limits was reached.
+    Limit(Outcome),
+}
+
+/// A machine plus the breakpoints and watchpoints set on it.
+pub struct Debugger {
+    machine: Machine,
+    source: Vec<String>,
+    breakpoints: Vec<usize>,
+    watches: Vec<String>,
+    limits: Limits,
+    /// The state before each instruction executed so far, newest last.
+    history: VecDeque<Snapshot>,
+}
+
+impl Debugger {
+    /// Debug `machine`, which was loaded from `source`.
+    pub fn new(machine: Machine, source: &str) -> Debugger {
+        Debugger {
+            machine,
+            source: source.lines().map(|l| l.to_owned()).collect(),
+            breakpoints: Vec::new(),
+            watches: Vec::new(),
+            limits: Limits::default(),
+            history: VecDeque::new(),
+        }
+    }
+
+    /// Limits that `resume` gives up at, so a runaway loop without
+    /// breakpoints can't hang the session.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    /// Turn a label name or source line number into a program index. A line
+    /// without an instruction resolves to the next instruction after it.
+    fn resolve(&self, target: &str) -> Result<usize, String> {
+        if let Ok(line) = target.parse::<usize>() {
+            let program = self.machine.program();
+            return (0..program.len())
+                .find(|i| self.machine.line_of(*i).is_some_and(|l| l >= line))
+                .ok_or_else(|| format!("no instruction at or after line {}", line));
+        }
+        self.machine
+            .label(target)
+            .ok_or_else(|| format!("no label called {:?}", target))
+    }
+
+    /// Set a breakpoint on a label or line, returning the program index it
+    /// landed on.
+    pub fn add_breakpoint(&mut self, target: &str) -> Result<usize, String> {
+        let index = self.resolve(target)?;
+        if !self.breakpoints.contains(&index) {
+            self.breakpoints.push(index);
+        }
+        Ok(index)
+    }
+
+    pub fn remove_breakpoint(&mut self, target: &str) -> Result<(), String> {
+        let index = self.resolve(target)?;
+        let before = self.breakpoints.len();
+        self.breakpoints.retain(|b| *b != index);
+        if self.breakpoints.len() == before {
+            return Err(format!("no breakpoint at {}", target));
+        }
+        Ok(())
+    }
+
+    pub fn watch(&mut self, register: &str) -> Result<(), String> {
+        if self.machine.register(register).is_none() {
+            return Err(format!("no register called {:?}", register));
+        }
+        if !self.watches.iter().any(|w| w == register) {
+            self.watches.push(register.to_owned());
+        }
+        Ok(())
+    }
+
+    pub fn unwatch(&mut self, register: &str) -> Result<(), String> {
+        let before = self.watches.len();
+        self.watches.retain(|w| w != register);
+        if self.watches.len() == before {
+            return Err(format!("{} is not being watched", register));
+        }
+        Ok(())
+    }
+
+    /// Execute one instruction, reporting a watched register that changed.
+    pub fn step(&mut self) -> Stop {
+        let before: Vec<Option<i64>> = self
+            .watches
+            .iter()
+            .map(|w| self.machine.register(w))
+            .collect();
+        if self.machine.is_halted() {
+            return Stop::Halted;
+        }
+        if self.history.len() == MAX_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(self.machine.snapshot());
+        self.machine.step();
+        for (name, old) in self.watches.iter().zip(before) {
+            let new = self.machine.register(name);
+            if let (Some(old), Some(new)) = (old, new)
+                && old != new
+            {
+                return Stop::Watch {
+                    register: name.to_owned(),
+                    old,
+                    new,
+                };
+            }
+        }
+        if self.machine.is_halted() {
+            return Stop::Halted;
+        }
+        Stop::Stepped
+    }
+
+    /// Undo the last instruction.
+    pub fn step_back(&mut self) -> Result<(), String> {
+        match self.history.pop_back() {
+            Some(snapshot) => {
+                self.machine.restore(&snapshot);
+                Ok(())
+            }
+            None => Err(String::from("already at the start of the recorded history")),
+        }
+    }
+
+    /// Go back to the first instruction of the timestep the last executed
+    /// instruction ran in. From the middle of a timestep that is its start;
+    /// from the start of one it is the start of the one before.
+    pub fn step_back_timestep(&mut self) -> Result<(), String> {
+        let timestep = match self.history.back() {
+            Some(snapshot) => snapshot.timestep(),
+            None => return Err(String::from("already at the start of the recorded history")),
+        };
+        while let Some(snapshot) = self.history.pop_back() {
+            if self.history.back().is_none_or(|s| s.timestep() != timestep) {
+                self.machine.restore(&snapshot);
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Go back to just before the last instruction that wrote `register`,
+    /// returning its program index.
+    pub fn rewind_to_write(&mut self, register: &str) -> Result<usize, String> {
+        if self.machine.register(register).is_none() {
+            return Err(format!("no register called {:?}", register));
+        }
+        let current = self.machine.snapshot();
+        for i in (0..self.history.len()).rev() {
+            self.machine.restore(&self.history[i]);
+            if self.machine.next_write() == Some(register) {
+                self.history.truncate(i);
+                return Ok(self.machine.pc());
+            }
+        }
+        self.machine.restore(&current);
+        Err(format!(
+            "{} has not been written in the recorded history",
+            register
+        ))
+    }
+
+    /// Run until a breakpoint, a watchpoint, a limit or the end of the
+    /// program. Always executes at least one instruction, so continuing from
+    /// a breakpoint moves past it.
+    pub fn resume(&mut self) -> Stop {
+        let mut run = Run::start(&self.machine);
+        loop {
+            if let Some(outcome) = self.limits.check(&self.machine, &mut run) {
+                return Stop::Limit(outcome);
+            }
+            match self.step() {
+                Stop::Stepped => (),
+                stop => return stop,
+            }
+            if self.breakpoints.contains(&self.machine.pc()) {
+                return Stop::Breakpoint(self.machine.pc());
+            }
+        }
+    }
+
+    /// Read commands from `input` until `quit` or end of input.
+    pub fn run(&mut self, mut input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        let mut previous = String::new();
+        self.show_line(&mut output)?;
+        loop {
+            write!(output, "(dbg) ")?;
+            output.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let mut command = line.trim().to_owned();
+            if command.is_empty() {
+                command = previous.clone();
+            }
+            previous = command.clone();
+
+            let words: Vec<&str> = command.split_ascii_whitespace().collect();
+            let result = match words.as_slice() {
+                [] => Ok(()),
+                ["quit" | "q"] => return Ok(()),
+                ["help" | "h"] => writeln!(output, "{}", HELP),
+                ["step" | "s"] => self.command_step(1, &mut output),
+                ["step" | "s", n] => match n.parse() {
+                    Ok(n) => self.command_step(n, &mut output),
+                    Err(_) => writeln!(output, "error: {:?} is not a number", n),
+                },
+                ["continue" | "c"] => {
+                    let stop = self.resume();
+                    self.report(&stop, &mut output)
+                }
+                ["back"] => self.command_back(1, &mut output),
+                ["back", n] => match n.parse() {
+                    Ok(n) => self.command_back(n, &mut output),
+                    Err(_) => writeln!(output, "error: {:?} is not a number", n),
+                },
+                ["back-timestep" | "bt"] => match self.step_back_timestep() {
+                    Ok(()) => self.show_line(&mut output),
+                    Err(e) => writeln!(output, "error: {}", e),
+                },
+                ["rewind", register] => match self.rewind_to_write(register) {
+                    Ok(_) => {
+                        writeln!(output, "Before the last write to {}", register)?;
+                        self.show_line(&mut output)
+                    }
+                    Err(e) => writeln!(output, "error: {}", e),
+                },
+                ["break" | "b", target] => match self.add_breakpoint(target) {
+                    Ok(index) => writeln!(output, "Breakpoint at {}", self.describe(index)),
+                    Err(e) => writeln!(output, "error: {}", e),
+                },
+                ["delete", target] => match self.remove_breakpoint(target) {
+                    Ok(()) => writeln!(output, "Deleted breakpoint at {}", target),
+                    Err(e) => writeln!(output, "error: {}", e),
+                },
+                ["watch" | "w", register] => match self.watch(register) {
+                    Ok(()) => writeln!(output, "Watching {}", register),
+                    Err(e) => writeln!(output, "error: {}", e),
+                },
+                ["unwatch", register] => match self.unwatch(register) {
+                    Ok(()) => writeln!(output, "No longer watching {}", register),
+                    Err(e) => writeln!(output, "error: {}", e),
+                },
+                ["info"] => self.show_info(&mut output),
+                ["print" | "p"] => self.show_state(&mut output),
+                ["print" | "p", register] => match self.machine.register(register) {
+                    Some(v) => writeln!(output, "{} = {}", register, v),
+                    None => writeln!(output, "error: no register called {:?}", register),
+                },
+                ["list" | "l"] => self.show_source(&mut output),
+                _ => writeln!(output, "error: unknown command {:?}, try help", command),
+            };
+            result?;
+        }
+    }
+
+    fn command_step(&mut self, n: usize, output: &mut impl Write) -> io::Result<()> {
+        let mut stop = Stop::Stepped;
+        for _ in 0..n {
+            stop = self.step();
+            if stop != Stop::Stepped {
+                break;
+            }
+        }
+        self.report(&stop, output)
+    }
+
+    fn command_back(&mut self, n: usize, output: &mut impl Write) -> io::Result<()> {
+        for _ in 0..n {
+            if let Err(e) = self.step_back() {
+                writeln!(output, "error: {}", e)?;
+                break;
+            }
+        }
+        self.show_line(output)
+    }
+
+    fn report(&self, stop: &Stop, output: &mut impl Write) -> io::Result<()> {
+        match stop {
+            Stop::Stepped => (),
+            Stop::Breakpoint(_) => writeln!(output, "Breakpoint hit")?,
+            Stop::Watch { register, old, new } => writeln!(
+                output,
+                "Watchpoint: {} changed {} -> {}",
+                register, old, new
+            )?,
+            Stop::Halted => {
+                return writeln!(
+                    output,
+                    "Program halted after {} steps. {}",
+                    self.machine.steps(),
+                    self.machine
+                );
+            }
+            Stop::Limit(outcome) => writeln!(output, "Stopped: {:?}", outcome)?,
+        }
+        self.show_line(output)
+    }
+
+    /// `line 5: teq acc 9` for a program index.
+    fn describe(&self, index: usize) -> String {
+        match self.machine.line_of(index) {
+            Some(line) => format!(
+                "line {}: {}",
+                line,
+                self.source.get(line - 1).map(|s| s.trim()).unwrap_or("")
+            ),
+            None => format!("instruction {}", index),
+        }
+    }
+
+    fn show_line(&self, output: &mut impl Write) -> io::Result<()> {
+        if self.machine.is_halted() {
+            return writeln!(output, "Program has halted.");
+        }
+        writeln!(output, "=> {}", self.describe(self.machine.pc()))
+    }
+
+    fn show_state(&self, output: &mut impl Write) -> io::Result<()> {
+        let machine = &self.machine;
+        let group = |prefix: &[&str]| -> String {
+            machine
+                .chip()
+                .registers()
+                .iter()
+                .filter(|name| prefix.iter().any(|p| name.starts_with(p)))
+                .map(|name| format!("{}={}", name, machine.register(name).unwrap()))
+                .collect::<Vec<String>>()
+                .join(" ")
+        };
+        writeln!(output, "registers: {}", group(&["acc", "dat"]))?;
+        writeln!(output, "pins:      {}", group(&["p"]))?;
+        writeln!(output, "xbus:      {}", group(&["x"]))?;
+        writeln!(
+            output,
+            "flag: {}  pc: {}  steps: {}  timestep: {}",
+            machine.flag(),
+            machine.pc(),
+            machine.steps(),
+            machine.timestep()
+        )
+    }
+
+    fn show_source(&self, output: &mut impl Write) -> io::Result<()> {
+        let current = match self.machine.line() {
+            Some(v) => v,
+            None => return self.show_line(output),
+        };
+        let breakpoint_lines: Vec<usize> = self
+            .breakpoints
+            .iter()
+            .filter_map(|b| self.machine.line_of(*b))
+            .collect();
+        let first = current.saturating_sub(CONTEXT).max(1);
+        let last = (current + CONTEXT).min(self.source.len());
+        for n in first..=last {
+            let marker = match (n == current, breakpoint_lines.contains(&n)) {
+                (true, _) => "=>",
+                (false, true) => " *",
+                (false, false) => "  ",
+            };
+            writeln!(output, "{} {:>4} {}", marker, n, self.source[n - 1])?;
+        }
+        Ok(())
+    }
+
+    fn show_info(&self, output: &mut impl Write) -> io::Result<()> {
+        if self.breakpoints.is_empty() {
+            writeln!(output, "No breakpoints.")?;
+        }
+        for b in &self.breakpoints {
+            writeln!(output, "breakpoint at {}", self.describe(*b))?;
+        }
+        if self.watches.is_empty() {
+            writeln!(output, "No watchpoints.")?;
+        } else {
+            writeln!(output, "watching {}", self.watches.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Debugger, Stop};
+    use crate::machine::Machine;
+
+    const FIBONACCI: &str = include_str!("../program.asm");
+
+    fn debugger() -> Debugger {
+        Debugger::new(Machine::load(FIBONACCI).unwrap(), FIBONACCI)
+    }
+
+    #[test]
+    fn breakpoints_by_label_and_line() {
+        let mut debugger = debugger();
+        let fibb = debugger.add_breakpoint("fibb").unwrap();
+        assert_eq!(debugger.resume(), Stop::Breakpoint(fibb));
+        assert_eq!(debugger.machine().register("acc"), Some(1));
+
+        // Lines 18 and 19 are blank and a comment, so this lands on line 20.
+        let index = debugger.add_breakpoint("18").unwrap();
+        assert_eq!(debugger.machine().line_of(index), Some(20));
+        assert!(debugger.add_breakpoint("nowhere").is_err());
+    }
+
+    #[test]
+    fn watchpoint_reports_change() {
+        let mut debugger = debugger();
+        debugger.watch("x2").unwrap();
+        assert_eq!(
+            debugger.resume(),
+            Stop::Watch {
+                register: String::from("x2"),
+                old: 0,
+                new: 1
+            }
+        );
+        assert!(debugger.watch("y9").is_err());
+    }
+
+    #[test]
+    fn stepping_backwards() {
+        let mut debugger = debugger();
+        assert!(debugger.step_back().is_err());
+        for _ in 0..30 {
+            debugger.step();
+        }
+        let later = debugger.machine().snapshot();
+        debugger.step();
+        debugger.step_back().unwrap();
+        assert_eq!(debugger.machine().snapshot(), later);
+
+        // x2 is written by `mov acc x2` on line 17.
+        let index = debugger.rewind_to_write("x2").unwrap();
+        assert_eq!(debugger.machine().line_of(index), Some(17));
+        assert_eq!(debugger.machine().pc(), index);
+        assert!(debugger.machine().steps() < 30);
+        assert!(debugger.rewind_to_write("p1").is_err());
+    }
+
+    #[test]
+    fn stepping_back_a_timestep() {
+        let program = "add 1\nslp 1\nadd 2\nadd 3\nslp 1\nadd 4";
+        let mut debugger = Debugger::new(Machine::load(program).unwrap(), program);
+        while debugger.step() == Stop::Stepped {}
+        assert_eq!(debugger.machine().timestep(), 2);
+
+        // Back to the start of timestep 2, then of timestep 1, then 0.
+        debugger.step_back_timestep().unwrap();
+        assert_eq!(debugger.machine().line(), Some(6));
+        debugger.step_back_timestep().unwrap();
+        assert_eq!(debugger.machine().line(), Some(3));
+        assert_eq!(debugger.machine().register("acc"), Some(1));
+        debugger.step_back_timestep().unwrap();
+        assert_eq!(debugger.machine().line(), Some(1));
+        assert!(debugger.step_back_timestep().is_err());
+    }
+
+    #[test]
+    fn scripted_session() {
+        let mut debugger = debugger();
+        let script = "b end\nc\np acc\nl\n\nq\n";
+        let mut output = Vec::new();
+        debugger.run(script.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("Breakpoint at line 22: end:"), "{}", output);
+        assert!(output.contains("acc = 9"), "{}", output);
+        assert!(output.contains("=>   22 end:"), "{}", output);
+    }
+}
@@ -0,0 +1,518 @@
+/*
+    A decompiler: rebuilds loops and if/else from labels, jumps and `+`/`-`
+    lines, and prints the program as pseudocode beside the lines it came
+    from. Jumps that don't fit a loop or an if are left as `goto`.
+*/
+
+use std::fmt::Write;
+
+use crate::format;
+use crate::instruction::{Arg, Instruction, condition};
+use crate::machine::Machine;
+
+/// A line of pseudocode and the program entries it stands for.
+struct Row {
+    depth: usize,
+    code: String,
+    sources: Vec<usize>,
+}
+
+/// The loop being decompiled, for turning jumps into `break` and
+/// `continue`.
+#[derive(Clone, Copy)]
+struct Loop<'a> {
+    start: &'a str,
+    exit: Option<&'a str>,
+}
+
+struct Decompiler<'a> {
+    machine: &'a Machine,
+    rows: Vec<Row>,
+}
+
+/// The program `machine` runs as pseudocode, with each line of `source`
+/// as a comment beside the pseudocode it became.
+pub fn decompile(machine: &Machine, source: &str) -> String {
+    let mut decompiler = Decompiler {
+        machine,
+        rows: Vec::new(),
+    };
+    decompiler.block(0, machine.program().len(), 0, None);
+
+    let text: Vec<&str> = source.lines().collect();
+    let original = |i: usize| match machine.line_of(i).and_then(|l| text.get(l - 1)) {
+        Some(line) => line.trim().to_owned(),
+        None => format::line(&machine.program()[i]),
+    };
+    let indent = |depth: usize| "    ".repeat(depth);
+    let width = decompiler
+        .rows
+        .iter()
+        .map(|r| indent(r.depth).len() + r.code.len())
+        .max()
+        .unwrap_or(0)
+        + 2;
+    let mut out = String::new();
+    for row in &decompiler.rows {
+        let code = format!("{}{}", indent(row.depth), row.code);
+        if row.sources.is_empty() {
+            let _ = writeln!(out, "{}", code);
+        }
+        for (n, i) in row.sources.iter().enumerate() {
+            let code = if n == 0 { code.as_str() } else { "" };
+            let _ = writeln!(out, "{:width$}# {}", code, original(*i), width = width);
+        }
+    }
+    out
+}
+
+impl<'a> Decompiler<'a> {
+    fn row(&mut self, depth: usize, code: impl Into<String>, sources: Vec<usize>) {
+        self.rows.push(Row {
+            depth,
+            code: code.into(),
+            sources,
+        });
+    }
+
+    fn program(&self) -> &'a [(Instruction, Vec<Arg>)] {
+        self.machine.program()
+    }
+
+    fn label(&self, i: usize) -> Option<&'a str> {
+        match &self.program()[i] {
+            (Instruction::Label, args) => match &args[0] {
+                Arg::Label(name) => Some(name),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Where the `jmp` at `i` goes.
+    fn target(&self, i: usize) -> Option<usize> {
+        match &self.program()[i] {
+            (Instruction::Jmp, args) => self.machine.label(&args[0].to_string()),
+            _ => None,
+        }
+    }
+
+    fn is_jump(&self, i: usize, conditional: bool) -> bool {
+        let (instr, args) = &self.program()[i];
+        *instr == Instruction::Jmp && condition(args).is_some() == conditional
+    }
+
+    /// Every `jmp` that goes to `label`.
+    fn references(&self, label: usize) -> Vec<usize> {
+        (0..self.program().len())
+            .filter(|i| self.target(*i) == Some(label))
+            .collect()
+    }
+
+    /// Whether a `jmp` outside `start..end` goes into it.
+    fn entered(&self, start: usize, end: usize) -> bool {
+        (0..self.program().len()).any(|i| {
+            !(start..end).contains(&i) && self.target(i).is_some_and(|t| (start..end).contains(&t))
+        })
+    }
+
+    fn block(&mut self, start: usize, end: usize, depth: usize, current: Option<Loop<'a>>) {
+        let mut i = start;
+        while i < end {
+            i = self.statement(i, end, depth, current);
+        }
+    }
+
+    /// Decompile `start..end` knowing the flag is `flag` on the way in, so a
+    /// conditional `jmp` right at the start always jumps if it matches.
+    fn block_with(
+        &mut self,
+        start: usize,
+        end: usize,
+        depth: usize,
+        current: Option<Loop<'a>>,
+        flag: Option<&Arg>,
+    ) {
+        let known = flag.is_some()
+            && start < end
+            && self.is_jump(start, true)
+            && condition(&self.program()[start].1) == flag;
+        if known {
+            self.jump(start, vec![start], None, depth, current);
+            self.block(start + 1, end, depth, current);
+        } else {
+            self.block(start, end, depth, current);
+        }
+    }
+
+    /// Decompile from `i`, returning where to carry on.
+    fn statement(
+        &mut self,
+        i: usize,
+        end: usize,
+        depth: usize,
+        current: Option<Loop<'a>>,
+    ) -> usize {
+        let (instr, args) = &self.program()[i];
+        if *instr == Instruction::Label {
+            if let Some(next) = self.loop_at(i, end, depth) {
+                return next;
+            }
+            let name = self.label(i).unwrap_or_default();
+            self.row(depth, format!("{}:", name), vec![i]);
+            return i + 1;
+        }
+        if *instr == Instruction::Jmp {
+            let holds = condition(args).map(|prefix| format!("flag == {}", prefix));
+            self.jump(i, vec![i], holds, depth, current);
+            return i + 1;
+        }
+        if condition(args).is_some() {
+            return self.prefixed(i, None, end, depth);
+        }
+        if instr.is_test() && i + 1 < end {
+            if self.is_jump(i + 1, true) {
+                if let Some(next) = self.if_at(i, end, depth, current) {
+                    return next;
+                }
+                let prefix = condition(&self.program()[i + 1].1);
+                let holds = prefix.map(|p| compare(&self.program()[i], p, true));
+                self.jump(i + 1, vec![i, i + 1], holds, depth, current);
+                return i + 2;
+            }
+            if condition(&self.program()[i + 1].1).is_some() {
+                return self.prefixed(i + 1, Some(i), end, depth);
+            }
+        }
+        self.row(depth, statement(&self.program()[i]), vec![i]);
+        i + 1
+    }
+
+    /// A label, the lines after it and an unconditional `jmp` back to it as
+    /// a `while` loop. A test and conditional `jmp` to just after the loop
+    /// straight after the label become its condition.
+    fn loop_at(&mut self, i: usize, end: usize, depth: usize) -> Option<usize> {
+        let back = (i + 1..end)
+            .rev()
+            .find(|j| self.is_jump(*j, false) && self.target(*j) == Some(i))?;
+        if self.entered(i + 1, back + 1)
+            || self.references(i).iter().any(|r| !(i..=back).contains(r))
+        {
+            return None;
+        }
+        let after = back + 1;
+        let exit = (after < self.program().len())
+            .then(|| self.label(after))
+            .flatten();
+        let current = Loop {
+            start: self.label(i)?,
+            exit,
+        };
+
+        let program = self.program();
+        let tested = i + 2 < back
+            && program[i + 1].0.is_test()
+            && condition(&program[i + 1].1).is_none()
+            && self.is_jump(i + 2, true)
+            && self.target(i + 2) == Some(after);
+        if tested {
+            let jump = condition(&program[i + 2].1)?;
+            let header = format!("while {} {{", compare(&program[i + 1], jump, false));
+            self.row(depth, header, vec![i, i + 1, i + 2]);
+            let flag = opposite(&program[i + 1], jump);
+            self.block_with(i + 3, back, depth + 1, Some(current), flag.as_ref());
+        } else {
+            self.row(depth, "while true {", vec![i]);
+            self.block(i + 1, back, depth + 1, Some(current));
+        }
+
+        // The label after the loop goes too if only the loop jumps to it.
+        let consumed = after < end
+            && exit.is_some()
+            && self
+                .references(after)
+                .iter()
+                .all(|r| (i..=back).contains(r));
+        if consumed {
+            self.row(depth, "}", vec![back, after]);
+            Some(after + 1)
+        } else {
+            self.row(depth, "}", vec![back]);
+            Some(after)
+        }
+    }
+
+    /// A test and a conditional `jmp` forward over some lines as an `if`.
+    /// If those lines end by jumping over more, the `if` has an `else`.
+    fn if_at(
+        &mut self,
+        i: usize,
+        end: usize,
+        depth: usize,
+        current: Option<Loop<'a>>,
+    ) -> Option<usize> {
+        let over = self.target(i + 1).filter(|t| *t > i + 1 && *t < end)?;
+        if self.references(over) != [i + 1] || self.entered(i + 2, over) {
+            return None;
+        }
+        let program = self.program();
+        let jump = condition(&program[i + 1].1)?;
+        let header = format!("if {} {{", compare(&program[i], jump, false));
+
+        let otherwise = (over > i + 2 && self.is_jump(over - 1, false))
+            .then(|| self.target(over - 1))
+            .flatten()
+            .filter(|u| {
+                *u > over
+                    && *u < end
+                    && self.references(*u) == [over - 1]
+                    && !self.entered(over + 1, *u)
+            });
+        // Unless the test was tcp, not jumping means the flag is the other
+        // one.
+        let then = opposite(&program[i], jump);
+        self.row(depth, header, vec![i, i + 1]);
+        match otherwise {
+            Some(u) => {
+                self.block_with(i + 2, over - 1, depth + 1, current, then.as_ref());
+                self.row(depth, "} else {", vec![over - 1, over]);
+                self.block_with(over + 1, u, depth + 1, current, Some(jump));
+                self.row(depth, "}", vec![u]);
+                Some(u + 1)
+            }
+            None => {
+                self.block_with(i + 2, over, depth + 1, current, then.as_ref());
+                self.row(depth, "}", vec![over]);
+                Some(over + 1)
+            }
+        }
+    }
+
+    /// A `jmp` as `break`, `continue` or `goto`, done only when `holds`.
+    fn jump(
+        &mut self,
+        i: usize,
+        sources: Vec<usize>,
+        holds: Option<String>,
+        depth: usize,
+        current: Option<Loop<'a>>,
+    ) {
+        let name = self.program()[i].1[0].to_string();
+        let action = match current {
+            Some(l) if l.exit == Some(name.as_str()) => String::from("break"),
+            Some(l) if l.start == name => String::from("continue"),
+            _ => format!("goto {}", name),
+        };
+        match holds {
+            None => self.row(depth, action, sources),
+            Some(holds) => self.row(depth, format!("if {} {{ {} }}", holds, action), sources),
+        }
+    }
+
+    /// A run of `+` and `-` lines as an `if` with the `+` lines and an
+    /// `else` with the `-` lines. A test in the run ends it, as the lines
+    /// after it depend on its result instead.
+    fn prefixed(&mut self, start: usize, test: Option<usize>, end: usize, depth: usize) -> usize {
+        let program = self.program();
+        let (mut plus, mut minus) = (Vec::new(), Vec::new());
+        let mut i = start;
+        while i < end && !self.is_jump(i, true) {
+            let (instr, args) = &program[i];
+            match condition(args) {
+                Some(Arg::BranchTrue) => plus.push(i),
+                Some(_) => minus.push(i),
+                None => break,
+            }
+            i += 1;
+            if instr.is_test() {
+                break;
+            }
+        }
+
+        let (when_plus, when_minus, both) = match test {
+            Some(t) => (
+                compare(&program[t], &Arg::BranchTrue, true),
+                compare(&program[t], &Arg::BranchFalse, true),
+                program[t].0 != Instruction::Tcp,
+            ),
+            None => (String::from("flag == +"), String::from("flag == -"), false),
+        };
+        let sources: Vec<usize> = test.into_iter().collect();
+        let (first, first_lines, second) = if plus.is_empty() {
+            (when_minus, &minus, None)
+        } else {
+            let second = (!minus.is_empty()).then(|| {
+                if both {
+                    String::from("} else {")
+                } else {
+                    format!("}} else if {} {{", when_minus)
+                }
+            });
+            (when_plus, &plus, second)
+        };
+        self.row(depth, format!("if {} {{", first), sources);
+        for j in first_lines {
+            self.row(depth + 1, statement(&program[*j]), vec![*j]);
+        }
+        if let Some(second) = second {
+            self.row(depth, second, Vec::new());
+            for j in &minus {
+                self.row(depth + 1, statement(&program[*j]), vec![*j]);
+            }
+        }
+        self.row(depth, "}", Vec::new());
+        i
+    }
+}
+
+/// What the test `line` compared to leave the flag at `prefix`, or with
+/// `holds` false, to leave it anything else.
+fn compare(line: &(Instruction, Vec<Arg>), prefix: &Arg, holds: bool) -> String {
+    let (instr, args) = line;
+    let plus = (*prefix == Arg::BranchTrue) == holds;
+    let op = match (instr, plus) {
+        (Instruction::Teq, true) => "==",
+        (Instruction::Teq, false) => "!=",
+        (Instruction::Tgt, true) => ">",
+        (Instruction::Tgt, false) => "<=",
+        (Instruction::Tlt, true) => "<",
+        (Instruction::Tlt, false) => ">=",
+        // Equal values leave neither + nor -.
+        _ => match (*prefix == Arg::BranchTrue, holds) {
+            (true, true) => ">",
+            (false, true) => "<",
+            (true, false) => "<=",
+            (false, false) => ">=",
+        },
+    };
+    format!("{} {} {}", args[0], op, args[1])
+}
+
+/// The flag after the test `line` didn't leave it at `prefix`, if that
+/// can only be the other one.
+fn opposite(line: &(Instruction, Vec<Arg>), prefix: &Arg) -> Option<Arg> {
+    match (&line.0, prefix) {
+        (Instruction::Tcp, _) => None,
+        (_, Arg::BranchTrue) => Some(Arg::BranchFalse),
+        _ => Some(Arg::BranchTrue),
+    }
+}
+
+/// Pseudocode for a line that doesn't jump, without its `+` or `-`.
+fn statement(line: &(Instruction, Vec<Arg>)) -> String {
+    let (instr, args) = line;
+    match instr {
+        Instruction::Mov => format!("{} = {}", args[1], args[0]),
+        Instruction::Add => format!("acc = acc + {}", args[0]),
+        Instruction::Sub => format!("acc = acc - {}", args[0]),
+        Instruction::Mul => format!("acc = acc * {}", args[0]),
+        Instruction::Not => String::from("acc = not(acc)"),
+        Instruction::Dgt => format!("acc = digit(acc, {})", args[0]),
+        Instruction::Dst => format!("acc = set_digit(acc, {}, {})", args[0], args[1]),
+        Instruction::Slp => format!("sleep {}", args[0]),
+        Instruction::Nop => String::from("nop"),
+        Instruction::Tcp => format!("flag = compare({}, {})", args[0], args[1]),
+        Instruction::Teq | Instruction::Tgt | Instruction::Tlt => {
+            format!("flag = {} ? + : -", compare(line, &Arg::BranchTrue, true))
+        }
+        Instruction::Label | Instruction::Jmp => format::line(line),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decompile;
+    use crate::chip::ChipModel;
+    use crate::compile::compile;
+    use crate::format;
+    use crate::machine::Machine;
+
+    fn decompiled(source: &str) -> String {
+        decompile(&Machine::load(source).unwrap(), source)
+    }
+
+    #[test]
+    fn undoes_the_compiler() {
+        let source = "\
+count = 0
+while true {
+    if p0 > 50 {
+        p1 = p0
+        count = count + 1
+    } else {
+        p1 = 0
+    }
+    while p1 != 0 {
+        p1 = 0
+    }
+    sleep count
+}";
+        let program = format::print(&compile(source, ChipModel::Mc6000).unwrap());
+        assert_eq!(
+            decompiled(&program),
+            "\
+acc = 0                # mov 0 acc
+while true {           # loop1:
+    if p0 > 50 {       # tgt p0 50
+        p1 = p0        # + mov p0 p1
+        acc = acc + 1  # + add 1
+    } else {
+        p1 = 0         # - mov 0 p1
+    }
+    while p1 != 0 {    # loop2:
+                       # teq p1 0
+                       # + jmp end2
+        p1 = 0         # mov 0 p1
+    }                  # jmp loop2
+                       # end2:
+    sleep acc          # slp acc
+}                      # jmp loop1
+"
+        );
+    }
+
+    #[test]
+    fn turns_jumps_into_break_and_continue() {
+        let source = "\
+start:
+  teq acc 3
+  - jmp else
+  mov 1 x0
+  jmp done
+else:
+  tcp acc 0
+  + jmp start
+  - add 1
+done:
+  mov acc dat
+  jmp start";
+        assert_eq!(
+            decompiled(source),
+            "\
+while true {                     # start:
+    if acc == 3 {                # teq acc 3
+                                 # - jmp else
+        x0 = 1                   # mov 1 x0
+    } else {                     # jmp done
+                                 # else:
+        if acc > 0 { continue }  # tcp acc 0
+                                 # + jmp start
+        if flag == - {
+            acc = acc + 1        # - add 1
+        }
+    }                            # done:
+    dat = acc                    # mov acc dat
+}                                # jmp start
+"
+        );
+        let fibonacci = decompiled(include_str!("../program.asm"));
+        // Inside the if, the flag can only be +, so the jump always happens.
+        assert!(
+            fibonacci
+                .lines()
+                .any(|l| l.starts_with("        break ") && l.ends_with("# + jmp end")),
+            "{}",
+            fibonacci
+        );
+    }
+}
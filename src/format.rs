@@ -0,0 +1,293 @@
+/*
+    Re-emitting program source in a consistent style.
+*/
+
+use crate::instruction::{Arg, Instruction};
+use crate::parse;
+
+const INDENT: &str = "    ";
+
+/// A line of source, as the formatter sees it.
+enum Line {
+    Blank,
+    Comment(String),
+    Label(String),
+    Instruction {
+        condition: Option<char>,
+        text: String,
+    },
+}
+
+/// Re-emit a program in the canonical style:
+///
+/// ```asm
+/// # comments before a label sit flush left with it
+/// loop:
+///     teq acc 9
+///   - add 1
+///   + jmp end
+/// ```
+///
+/// Labels get a line of their own, flush left. Instructions are indented
+/// four spaces, with `+` and `-` hanging in the indent so the mnemonics line
+/// up. Tokens are separated by single spaces, other comments are indented
+/// with the code, and runs of blank lines collapse to one.
+///
+/// Fails on lines that don't parse, with the line number.
+pub fn format(source: &str) -> Result<String, String> {
+    let mut lines = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        let mut trimmed = line.trim();
+        // A label sharing its line with an instruction gets split from it.
+        if let Some((name, rest)) = parse::label(trimmed) {
+            lines.push(Line::Label(format!("{}:", name)));
+            trimmed = rest.trim();
+            if trimmed.is_empty() {
+                continue;
+            }
+        }
+        let tokens: Vec<&str> = trimmed.split_ascii_whitespace().collect();
+        let line = match tokens.first() {
+            None => Line::Blank,
+            Some(t) if t.starts_with('#') => Line::Comment(trimmed.to_owned()),
+            Some(t) => {
+                parse::validate(&parse::split_line(trimmed))
+                    .map_err(|e| format!("line {}: {}", i + 1, e))?;
+                match *t {
+                    "+" | "-" => Line::Instruction {
+                        condition: t.chars().next(),
+                        text: tokens[1..].join(" "),
+                    },
+                    _ => Line::Instruction {
+                        condition: None,
+                        text: tokens.join(" "),
+                    },
+                }
+            }
+        };
+        lines.push(line);
+    }
+
+    let mut out: Vec<String> = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        match line {
+            Line::Blank => {
+                if out.last().is_some_and(|l| !l.is_empty()) {
+                    out.push(String::new());
+                }
+            }
+            Line::Comment(text) => {
+                let rest = lines[i + 1..]
+                    .iter()
+                    .find(|l| !matches!(l, Line::Comment(_)));
+                match rest {
+                    Some(Line::Label(_)) => out.push(text.to_owned()),
+                    _ => out.push(format!("{}{}", INDENT, text)),
+                }
+            }
+            Line::Label(text) => out.push(text.to_owned()),
+            Line::Instruction {
+                condition: Some(c),
+                text,
+            } => out.push(format!("{}{} {}", &INDENT[2..], c, text)),
+            Line::Instruction {
+                condition: None,
+                text,
+            } => out.push(format!("{}{}", INDENT, text)),
+        }
+    }
+
+    while out.last().is_some_and(|l| l.is_empty()) {
+        out.pop();
+    }
+    Ok(out.iter().map(|l| format!("{}\n", l)).collect())
+}
+
+/// Write one parsed line back as source, without indentation.
+pub fn line(line: &(Instruction, Vec<Arg>)) -> String {
+    let (instr, args) = line;
+    if *instr == Instruction::Label {
+        return format!("{}:", args[0]);
+    }
+    let mut words = Vec::new();
+    for arg in args {
+        if matches!(arg, Arg::BranchTrue | Arg::BranchFalse) {
+            words.insert(0, arg.to_string());
+        }
+    }
+    words.push(instr.to_string());
+    words.extend(
+        args.iter()
+            .filter(|a| !matches!(a, Arg::BranchTrue | Arg::BranchFalse))
+            .map(|a| a.to_string()),
+    );
+    words.join(" ")
+}
+
+/// Write a parsed program back as source in the canonical style, so that
+/// parsing the result gives the same program.
+pub fn print(program: &[(Instruction, Vec<Arg>)]) -> String {
+    let mut out = String::new();
+    for entry in program {
+        let text = line(entry);
+        match entry {
+            (Instruction::Label, _) => out.push_str(&text),
+            (_, args)
+                if args
+                    .iter()
+                    .any(|a| matches!(a, Arg::BranchTrue | Arg::BranchFalse)) =>
+            {
+                out.push_str(&INDENT[2..]);
+                out.push_str(&text);
+            }
+            _ => {
+                out.push_str(INDENT);
+                out.push_str(&text);
+            }
+        }
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format, print};
+    use crate::instruction::{Arg, Instruction};
+    use crate::parse;
+    use crate::register;
+
+    /// A small xorshift generator, so the property tests need no crates and
+    /// fail the same way every run.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+            items[self.below(items.len())]
+        }
+    }
+
+    const REGISTERS: [&str; 8] = ["acc", "dat", "p0", "p1", "x0", "x1", "x2", "x3"];
+    const LABELS: [&str; 5] = ["loop", "end", "a", "start_2", "L9"];
+
+    fn operand(rng: &mut Rng) -> Arg {
+        if rng.below(2) == 0 {
+            let span = (register::MAX - register::MIN + 1) as usize;
+            Arg::Number(register::MIN + rng.below(span) as i64)
+        } else {
+            Arg::Register(rng.pick(&REGISTERS).to_owned())
+        }
+    }
+
+    fn random_program(rng: &mut Rng) -> Vec<(Instruction, Vec<Arg>)> {
+        let mut program = Vec::new();
+        for _ in 0..rng.below(20) {
+            let mnemonic = rng.pick(&parse::MNEMONICS);
+            let instr = parse::mnemonic(mnemonic).unwrap();
+            if rng.below(6) == 0 {
+                let label = Arg::Label(rng.pick(&LABELS).to_owned());
+                program.push((Instruction::Label, vec![label]));
+                continue;
+            }
+            let mut args = match instr {
+                Instruction::Jmp => vec![Arg::Label(rng.pick(&LABELS).to_owned())],
+                Instruction::Mov => {
+                    vec![operand(rng), Arg::Register(rng.pick(&REGISTERS).to_owned())]
+                }
+                _ => (0..instr.arity()).map(|_| operand(rng)).collect(),
+            };
+            match rng.below(3) {
+                0 => args.push(Arg::BranchTrue),
+                1 => args.push(Arg::BranchFalse),
+                _ => (),
+            }
+            program.push((instr, args));
+        }
+        program
+    }
+
+    #[test]
+    fn formats_canonically() {
+        let source = "\
+mov  1 x1
+
+
+# the main loop
+loop:
+  add 1
+	teq   acc 9
+- jmp loop
+        +   nop
+   # done
+";
+        let expected = "    mov 1 x1
+
+# the main loop
+loop:
+    add 1
+    teq acc 9
+  - jmp loop
+  + nop
+    # done
+";
+        assert_eq!(format(source).unwrap(), expected);
+        assert_eq!(format(expected).unwrap(), expected);
+    }
+
+    #[test]
+    fn prints_a_program() {
+        let program = vec![
+            (Instruction::Label, vec![Arg::Label(String::from("loop"))]),
+            (
+                Instruction::Teq,
+                vec![Arg::Register(String::from("acc")), Arg::Number(-5)],
+            ),
+            (
+                Instruction::Jmp,
+                vec![Arg::Label(String::from("loop")), Arg::BranchFalse],
+            ),
+        ];
+        assert_eq!(print(&program), "loop:\n    teq acc -5\n  - jmp loop\n");
+    }
+
+    #[test]
+    fn printed_programs_parse_back_the_same() {
+        let mut rng = Rng(0x5eed_1234_abcd_9876);
+        for _ in 0..2000 {
+            let program = random_program(&mut rng);
+            let printed = print(&program);
+            let parsed: Vec<_> = match parse::program(&printed) {
+                Ok(v) => v.into_iter().map(|(_, line)| line).collect(),
+                Err(e) => panic!("{}\n{}", e, printed),
+            };
+            assert_eq!(parsed, program, "{}", printed);
+            assert_eq!(format(&printed).unwrap(), printed);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        let err = format("nop\nmov 1\n").unwrap_err();
+        assert!(err.starts_with("line 2"), "{}", err);
+        let err = format("nop\nloop: bogus 1 2\n").unwrap_err();
+        assert!(err.starts_with("line 2"), "{}", err);
+    }
+
+    #[test]
+    fn splits_labels_from_instructions() {
+        let source = "loop:  add   1\nend: - jmp loop\ndone: # finished\n";
+        let expected = "loop:\n    add 1\nend:\n  - jmp loop\ndone:\n    # finished\n";
+        assert_eq!(format(source).unwrap(), expected);
+    }
+}
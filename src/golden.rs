@@ -0,0 +1,180 @@
+/*
+    Regression tests written into programs as comments:
+
+        # expect: acc=9 x2=55 steps=113
+        # expect-error: unknown register
+
+    The first runs the program to the end and compares registers and
+    counters, the second expects loading to fail with that message.
+*/
+
+use crate::chip::ChipModel;
+use crate::limits::{Limits, Outcome};
+use crate::machine::Machine;
+
+/// Step limit for programs run without one, so a program that never halts
+/// fails instead of hanging the run. This counts steps over the whole run,
+/// not per timestep like `machine::MAX_STEPS_PER_TIMESTEP`, so it is larger:
+/// a program that sleeps can rightly run for many timesteps before halting.
+pub const MAX_STEPS: usize = 1_000_000;
+
+/// Counters that can be expected alongside registers.
+const COUNTERS: [&str; 4] = ["steps", "power", "timestep", "lines"];
+
+/// What a program's comments say should happen when it runs.
+#[derive(Debug, Default, PartialEq)]
+pub struct Expectations {
+    /// Registers and counters once the program halts, in the order written.
+    values: Vec<(String, i64)>,
+    /// Text the load error must contain.
+    error: Option<String>,
+}
+
+impl Expectations {
+    /// Collect every `# expect:` and `# expect-error:` comment in `source`.
+    pub fn parse(source: &str) -> Result<Expectations, String> {
+        let mut expectations = Expectations::default();
+        for (i, line) in source.lines().enumerate() {
+            let comment = match line.trim().strip_prefix('#') {
+                Some(v) => v.trim(),
+                None => continue,
+            };
+            if let Some(message) = comment.strip_prefix("expect-error:") {
+                if expectations.error.is_some() {
+                    return Err(format!("line {}: more than one expect-error", i + 1));
+                }
+                expectations.error = Some(message.trim().to_owned());
+            } else if let Some(values) = comment.strip_prefix("expect:") {
+                for pair in values.split_ascii_whitespace() {
+                    let value = pair
+                        .split_once('=')
+                        .and_then(|(name, value)| Some((name, value.parse::<i64>().ok()?)));
+                    match value {
+                        Some((name, value)) => {
+                            expectations.values.push((name.to_owned(), value));
+                        }
+                        None => {
+                            return Err(format!(
+                                "line {}: expected name=number, got {:?}",
+                                i + 1,
+                                pair
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        if expectations.error.is_some() && !expectations.values.is_empty() {
+            return Err(String::from(
+                "a program can't expect both values and an error",
+            ));
+        }
+        Ok(expectations)
+    }
+
+    /// Whether there is nothing to check.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty() && self.error.is_none()
+    }
+
+    /// Load and run `source`, returning every way it didn't do what was
+    /// expected. An empty list means it passed.
+    pub fn check(&self, source: &str, chip: ChipModel, limits: &Limits) -> Vec<String> {
+        let mut machine = match (Machine::load_chip(source, chip), &self.error) {
+            (Ok(_), Some(expected)) => {
+                return vec![format!(
+                    "expected an error containing {:?}, but it loaded",
+                    expected
+                )];
+            }
+            (Err(e), Some(expected)) if !e.contains(expected.as_str()) => {
+                return vec![format!(
+                    "expected an error containing {:?}, got {:?}",
+                    expected, e
+                )];
+            }
+            (Err(_), Some(_)) => return Vec::new(),
+            (Err(e), None) => return vec![e],
+            (Ok(machine), None) => machine,
+        };
+
+        match machine.run_with(limits, |_| ()) {
+            Outcome::Halted => (),
+            outcome => return vec![format!("did not halt: {:?}", outcome)],
+        }
+        let mut mismatches = Vec::new();
+        for (name, expected) in &self.values {
+            let actual = match name.as_str() {
+                "steps" => Some(machine.steps() as i64),
+                "power" => Some(machine.power() as i64),
+                "timestep" => Some(machine.timestep() as i64),
+                "lines" => Some(machine.lines_of_code() as i64),
+                register => machine.register(register),
+            };
+            match actual {
+                Some(actual) if actual == *expected => (),
+                Some(actual) => {
+                    mismatches.push(format!("{}: expected {}, got {}", name, expected, actual))
+                }
+                None => mismatches.push(format!(
+                    "{} is not a register of the {} or one of {}",
+                    name,
+                    chip.name(),
+                    COUNTERS.join(", ")
+                )),
+            }
+        }
+        mismatches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Expectations;
+    use crate::chip::ChipModel;
+    use crate::limits::Limits;
+
+    fn check(source: &str) -> Vec<String> {
+        let limits = Limits {
+            max_steps: Some(1000),
+            ..Limits::default()
+        };
+        let expectations = Expectations::parse(source).unwrap();
+        expectations.check(source, ChipModel::Mc6000, &limits)
+    }
+
+    #[test]
+    fn fibonacci_passes() {
+        let source = include_str!("../program.asm");
+        assert!(!Expectations::parse(source).unwrap().is_empty());
+        assert_eq!(check(source), Vec::<String>::new());
+    }
+
+    #[test]
+    fn reports_mismatches() {
+        let source = "mov 3 acc\n# expect: acc=4 dat=0 power=1 zz=1";
+        assert_eq!(
+            check(source),
+            vec![
+                "acc: expected 4, got 3",
+                "zz is not a register of the MC6000 or one of steps, power, timestep, lines",
+            ]
+        );
+        assert_eq!(
+            check("loop:\njmp loop\n# expect: acc=0"),
+            vec!["did not halt: StepLimit"]
+        );
+    }
+
+    #[test]
+    fn expected_errors() {
+        assert!(check("mov 1 zz\n# expect-error: unknown register").is_empty());
+        assert_eq!(
+            check("mov 1 acc\n# expect-error: unknown register"),
+            vec!["expected an error containing \"unknown register\", but it loaded"]
+        );
+        assert!(Expectations::parse("# expect: acc=nine").is_err());
+        assert!(Expectations::parse("# expect: acc=1\n# expect-error: x").is_err());
+        assert!(Expectations::parse("# a comment\nnop").unwrap().is_empty());
+    }
+}
@@ -2,8 +2,10 @@
     Definitions of instructions for the machines.
 */
 
+use std::fmt;
+
 /// Instructions for the Shenzhen I/O assembly language.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Instruction {
     Label,
     Nop,
@@ -16,22 +18,154 @@ pub enum Instruction {
     Teq,
     Tgt,
     Tlt,
+    Slp,
+    Tcp,
+    Dgt,
+    Dst,
     /*
     Empty,
     Comment,
-    Slp,
     Slx,
-    Dgt,
-    Dst
     */
 }
 
+impl Instruction {
+    /// How many arguments the instruction takes, not counting a `+`/`-` prefix.
+    pub fn arity(&self) -> usize {
+        match self {
+            Instruction::Label | Instruction::Nop | Instruction::Not => 0,
+            Instruction::Add
+            | Instruction::Sub
+            | Instruction::Mul
+            | Instruction::Jmp
+            | Instruction::Slp
+            | Instruction::Dgt => 1,
+            Instruction::Mov
+            | Instruction::Teq
+            | Instruction::Tgt
+            | Instruction::Tlt
+            | Instruction::Tcp
+            | Instruction::Dst => 2,
+        }
+    }
+
+    /// Whether the instruction sets the flag for `+` and `-` lines.
+    pub fn is_test(&self) -> bool {
+        matches!(
+            self,
+            Instruction::Teq | Instruction::Tgt | Instruction::Tlt | Instruction::Tcp
+        )
+    }
+
+    /// The operands as the game's manual writes them: `R` for a register,
+    /// `I` for an integer and `L` for a label.
+    pub fn signature(&self) -> &'static str {
+        match self {
+            Instruction::Label => "L:",
+            Instruction::Nop => "nop",
+            Instruction::Mov => "mov R/I R",
+            Instruction::Add => "add R/I",
+            Instruction::Sub => "sub R/I",
+            Instruction::Mul => "mul R/I",
+            Instruction::Not => "not",
+            Instruction::Jmp => "jmp L",
+            Instruction::Teq => "teq R/I R/I",
+            Instruction::Tgt => "tgt R/I R/I",
+            Instruction::Tlt => "tlt R/I R/I",
+            Instruction::Slp => "slp R/I",
+            Instruction::Tcp => "tcp R/I R/I",
+            Instruction::Dgt => "dgt R/I",
+            Instruction::Dst => "dst R/I R/I",
+        }
+    }
+
+    /// What the instruction does, in a sentence or two.
+    pub fn description(&self) -> &'static str {
+        match self {
+            Instruction::Label => "Marks a position that jmp can jump to.",
+            Instruction::Nop => "Does nothing.",
+            Instruction::Mov => "Copies the first operand into the second.",
+            Instruction::Add => "Adds the operand to acc.",
+            Instruction::Sub => "Subtracts the operand from acc.",
+            Instruction::Mul => "Multiplies acc by the operand.",
+            Instruction::Not => "Sets acc to 100 if it is 0, and to 0 otherwise.",
+            Instruction::Jmp => "Continues execution at the label.",
+            Instruction::Teq => {
+                "Sets the flag for + lines if the operands are equal, \
+                 and for - lines otherwise."
+            }
+            Instruction::Tgt => {
+                "Sets the flag for + lines if the first operand is greater \
+                 than the second, and for - lines otherwise."
+            }
+            Instruction::Tlt => {
+                "Sets the flag for + lines if the first operand is less \
+                 than the second, and for - lines otherwise."
+            }
+            Instruction::Slp => "Sleeps for the given number of timesteps.",
+            Instruction::Tcp => {
+                "Sets the flag for + lines if the first operand is greater \
+                 than the second, for - lines if it is less, and for neither \
+                 if they are equal."
+            }
+            Instruction::Dgt => "Sets acc to its digit at the position given by the operand.",
+            Instruction::Dst => {
+                "Sets the digit of acc at the position given by the first \
+                 operand to the second operand."
+            }
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    /// The mnemonic as written in source. Labels aren't written with one, so
+    /// they show as `label`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mnemonic = match self {
+            Instruction::Label => "label",
+            Instruction::Nop => "nop",
+            Instruction::Mov => "mov",
+            Instruction::Add => "add",
+            Instruction::Sub => "sub",
+            Instruction::Mul => "mul",
+            Instruction::Not => "not",
+            Instruction::Jmp => "jmp",
+            Instruction::Teq => "teq",
+            Instruction::Tgt => "tgt",
+            Instruction::Tlt => "tlt",
+            Instruction::Slp => "slp",
+            Instruction::Tcp => "tcp",
+            Instruction::Dgt => "dgt",
+            Instruction::Dst => "dst",
+        };
+        write!(f, "{}", mnemonic)
+    }
+}
+
 /// Types that an argument to an instruction can be.
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
 pub enum Arg {
-    Number(u64),
+    Number(i64),
     Register(String),
     Label(String),
     BranchTrue,
     BranchFalse,
 }
+
+/// The `+` or `-` condition among a line's arguments, if it has one.
+pub fn condition(args: &[Arg]) -> Option<&Arg> {
+    args.iter()
+        .find(|a| matches!(a, Arg::BranchTrue | Arg::BranchFalse))
+}
+
+impl fmt::Display for Arg {
+    /// The argument as written in source, with conditions as `+` and `-`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Arg::Number(n) => write!(f, "{}", n),
+            Arg::Register(name) | Arg::Label(name) => write!(f, "{}", name),
+            Arg::BranchTrue => write!(f, "+"),
+            Arg::BranchFalse => write!(f, "-"),
+        }
+    }
+}
@@ -0,0 +1,37 @@
+/*
+    An interpreter for the pseudo-assembly language featured in Shenzhen I/O.
+*/
+
+pub mod board;
+pub mod cfg;
+pub mod chip;
+pub mod compile;
+pub mod config;
+pub mod coverage;
+pub mod debugger;
+pub mod decompile;
+pub mod format;
+pub mod golden;
+pub mod instruction;
+pub mod json;
+pub mod limits;
+pub mod lint;
+pub mod lsp;
+pub mod machine;
+pub mod optimize;
+pub mod parse;
+pub mod profile;
+pub mod puzzle;
+pub mod register;
+pub mod repl;
+pub mod save;
+pub mod superopt;
+pub mod svg;
+pub mod toml;
+pub mod trace;
+pub mod vcd;
+
+pub use chip::ChipModel;
+pub use instruction::{Arg, Instruction};
+pub use limits::{Limits, Outcome};
+pub use machine::Machine;
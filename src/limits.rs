@@ -0,0 +1,147 @@
+/*
+    Guards against programs that never halt.
+*/
+
+use std::time::{Duration, Instant};
+
+use crate::machine::{MAX_STEPS_PER_TIMESTEP, Machine};
+
+/// How far `Machine::run_with` may go before giving up. Every limit is off
+/// by default.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Limits {
+    /// Stop once this many instructions have been executed.
+    pub max_steps: Option<usize>,
+    /// Stop before running anything in this timestep or later, or once the
+    /// program has run too long in one timestep without sleeping.
+    pub max_timesteps: Option<usize>,
+    /// Stop once the run has taken this long in real time.
+    pub timeout: Option<Duration>,
+}
+
+/// Why a run stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The program counter ran off the end of the program.
+    Halted,
+    StepLimit,
+    TimestepLimit,
+    Timeout,
+}
+
+/// The clock is only read every this many steps, as reading it costs more
+/// than executing most instructions.
+const CLOCK_INTERVAL: usize = 1024;
+
+/// Where a run started, so `Limits::check` can tell how far it has gone.
+#[derive(Debug)]
+pub(crate) struct Run {
+    started: Instant,
+    /// The timestep the machine was last seen in, and its step count when
+    /// that timestep began.
+    timestep: usize,
+    since: usize,
+}
+
+impl Run {
+    pub(crate) fn start(machine: &Machine) -> Run {
+        Run {
+            started: Instant::now(),
+            timestep: machine.next_timestep(),
+            since: machine.steps(),
+        }
+    }
+}
+
+impl Limits {
+    /// Whether `machine` has hit one of the limits during `run`.
+    pub(crate) fn check(&self, machine: &Machine, run: &mut Run) -> Option<Outcome> {
+        if self.max_steps.is_some_and(|max| machine.steps() >= max) {
+            return Some(Outcome::StepLimit);
+        }
+        if let Some(max) = self.max_timesteps {
+            if machine.next_timestep() >= max {
+                return Some(Outcome::TimestepLimit);
+            }
+            // The clock only moves on `slp`, so a loop that never sleeps
+            // would otherwise never reach the limit.
+            if machine.next_timestep() != run.timestep || machine.steps() < run.since {
+                run.timestep = machine.next_timestep();
+                run.since = machine.steps();
+            } else if machine.steps() - run.since >= MAX_STEPS_PER_TIMESTEP {
+                return Some(Outcome::TimestepLimit);
+            }
+        }
+        if machine.steps().is_multiple_of(CLOCK_INTERVAL)
+            && self.timeout.is_some_and(|t| run.started.elapsed() >= t)
+        {
+            return Some(Outcome::Timeout);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Limits, Outcome};
+    use crate::machine::{MAX_STEPS_PER_TIMESTEP, Machine};
+    use std::time::Duration;
+
+    const FOREVER: &str = "loop:\n  jmp loop";
+
+    #[test]
+    fn step_limit() {
+        let mut machine = Machine::load(FOREVER).unwrap();
+        let limits = Limits {
+            max_steps: Some(50),
+            ..Limits::default()
+        };
+        assert_eq!(machine.run_with(&limits, |_| ()), Outcome::StepLimit);
+        assert_eq!(machine.steps(), 50);
+    }
+
+    #[test]
+    fn timestep_limit() {
+        let mut machine = Machine::load("loop:\n  slp 3\n  jmp loop").unwrap();
+        let limits = Limits {
+            max_timesteps: Some(10),
+            ..Limits::default()
+        };
+        assert_eq!(machine.run_with(&limits, |_| ()), Outcome::TimestepLimit);
+        assert_eq!(machine.timestep(), 9);
+    }
+
+    #[test]
+    fn timestep_limit_without_sleep() {
+        let mut machine = Machine::load(FOREVER).unwrap();
+        let limits = Limits {
+            max_timesteps: Some(10),
+            ..Limits::default()
+        };
+        assert_eq!(machine.run_with(&limits, |_| ()), Outcome::TimestepLimit);
+        assert_eq!(machine.steps(), MAX_STEPS_PER_TIMESTEP);
+        assert_eq!(machine.timestep(), 0);
+    }
+
+    #[test]
+    fn timeout() {
+        let mut machine = Machine::load(FOREVER).unwrap();
+        let limits = Limits {
+            timeout: Some(Duration::from_millis(10)),
+            ..Limits::default()
+        };
+        assert_eq!(machine.run_with(&limits, |_| ()), Outcome::Timeout);
+    }
+
+    #[test]
+    fn halts_within_limits() {
+        let mut machine = Machine::load("add 1").unwrap();
+        let limits = Limits {
+            max_steps: Some(50),
+            ..Limits::default()
+        };
+        let mut seen = 0;
+        assert_eq!(machine.run_with(&limits, |_| seen += 1), Outcome::Halted);
+        assert_eq!(seen, 1);
+    }
+}
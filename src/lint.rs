@@ -0,0 +1,229 @@
+/*
+    Warnings about programs that load but probably don't do what was meant.
+*/
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::chip::ChipModel;
+use crate::instruction::{Arg, Instruction, condition};
+use crate::machine::{self, Machine};
+use crate::parse;
+
+/// A problem found in a program.
+#[derive(Debug, PartialEq)]
+pub struct Warning {
+    /// The source line, or the position in the program for machines built
+    /// without source.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Load and check `source`, returning warnings in source order.
+///
+/// A `mov` into a literal stops a program loading, so it is reported here
+/// and the line checked as a `nop` instead, to still find everything else.
+pub fn lint_source(source: &str, chip: ChipModel) -> Result<Vec<Warning>, String> {
+    let mut warnings = Vec::new();
+    let mut lines = Vec::new();
+    for (i, text) in source.lines().enumerate() {
+        let (label, rest) = match parse::label(text) {
+            Some((name, rest)) => (format!("{}:", name), rest),
+            None => (String::new(), text),
+        };
+        let (instr, args) = parse::split_line(rest);
+        let operands: Vec<&String> = args.iter().filter(|a| *a != "+" && *a != "-").collect();
+        if parse::mnemonic(&instr) == Some(Instruction::Mov)
+            && operands.len() == 2
+            && operands[1].parse::<i64>().is_ok()
+        {
+            warnings.push(Warning {
+                line: i + 1,
+                message: format!("mov can't write to the literal {}", operands[1]),
+            });
+            let prefix = args.iter().find(|a| *a == "+" || *a == "-");
+            lines.push(format!("{} {} nop", label, prefix.map_or("", |p| p.as_str())));
+        } else {
+            lines.push(text.to_owned());
+        }
+    }
+
+    let machine = Machine::load_chip(&lines.join("\n"), chip)?;
+    warnings.extend(lint(&machine));
+    warnings.sort_by_key(|w| w.line);
+    Ok(warnings)
+}
+
+/// Check a loaded program, returning warnings in source order.
+pub fn lint(machine: &Machine) -> Vec<Warning> {
+    let program = machine.program();
+    let line = |i: usize| machine.line_of(i).unwrap_or(i + 1);
+    let mut warnings = Vec::new();
+    let mut warn = |i: usize, message: String| {
+        warnings.push(Warning {
+            line: line(i),
+            message,
+        })
+    };
+
+    let mut defined: HashMap<&Arg, usize> = HashMap::new();
+    let jumped_to: Vec<&Arg> = program
+        .iter()
+        .filter(|(instr, _)| *instr == Instruction::Jmp)
+        .map(|(_, args)| &args[0])
+        .collect();
+    let has_test = program.iter().any(|(instr, _)| instr.is_test());
+    // Where the unconditional jmp that cuts off the following lines is.
+    let mut after_jump: Option<usize> = None;
+
+    for (i, (instr, args)) in program.iter().enumerate() {
+        if *instr == Instruction::Label {
+            let label = &args[0];
+            let name = match label {
+                Arg::Label(name) => name,
+                _ => continue,
+            };
+            if let Some(first) = defined.insert(label, i) {
+                warn(
+                    i,
+                    format!(
+                        "label {} is already defined on line {}; jumps go to this one",
+                        name,
+                        line(first)
+                    ),
+                );
+            }
+            if !jumped_to.contains(&label) {
+                warn(i, format!("label {} is never jumped to", name));
+            } else {
+                after_jump = None;
+            }
+            continue;
+        }
+
+        // Everything up to the next label is cut off.
+        if let Some(jump) = after_jump {
+            warn(
+                i,
+                format!("unreachable: follows the jmp on line {}", line(jump)),
+            );
+        } else if *instr == Instruction::Jmp && condition(args).is_none() {
+            after_jump = Some(i);
+        }
+
+        // The flag starts false and only tests change it. Chips start over
+        // after their last line, so any test can come before any line.
+        if let Some(c) = condition(args)
+            && !has_test
+        {
+            let message = match c {
+                Arg::BranchTrue => "this + line can never run: the program has no test instruction",
+                _ => "this - line always runs: the program has no test instruction",
+            };
+            warn(i, message.to_owned());
+        }
+    }
+
+    // Pins are driven from outside, so only internal registers count.
+    let written: Vec<&str> = program
+        .iter()
+        .filter_map(machine::written_register)
+        .collect();
+    let mut reported = Vec::new();
+    for (i, line) in program.iter().enumerate() {
+        for name in machine::read_registers(line) {
+            if name.starts_with('p') || name.starts_with('x') {
+                continue;
+            }
+            if !written.contains(&name) && !reported.contains(&name) {
+                reported.push(name);
+                warn(
+                    i,
+                    format!("{} is read but never written, so it is always 0", name),
+                );
+            }
+        }
+    }
+
+    warnings.sort_by_key(|w| w.line);
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lint, lint_source};
+    use crate::chip::ChipModel;
+    use crate::machine::Machine;
+
+    fn messages(source: &str) -> Vec<String> {
+        let machine = Machine::load(source).unwrap();
+        lint(&machine).iter().map(|w| w.to_string()).collect()
+    }
+
+    #[test]
+    fn clean_program() {
+        let source = "mov 1 acc\nloop:\nteq acc 9\n- add 1\n+ jmp end\njmp loop\nend:\nnop";
+        assert_eq!(messages(source), Vec::<String>::new());
+    }
+
+    #[test]
+    fn conditions_before_a_test_run_after_wrapping() {
+        assert_eq!(
+            messages("+ mov 1 p1\nteq p0 0\nslp 1"),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn finds_common_mistakes() {
+        let source = "\
++ mov 1 acc
+start:
+jmp start
+add dat
+jmp start
+unused:
+mov acc p1
+start:
+- nop";
+        assert_eq!(
+            messages(source),
+            vec![
+                "line 1: this + line can never run: the program has no test instruction",
+                "line 4: unreachable: follows the jmp on line 3",
+                "line 4: dat is read but never written, so it is always 0",
+                "line 5: unreachable: follows the jmp on line 3",
+                "line 6: label unused is never jumped to",
+                "line 7: unreachable: follows the jmp on line 3",
+                "line 8: label start is already defined on line 2; jumps go to this one",
+                "line 9: this - line always runs: the program has no test instruction",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_mov_into_a_literal() {
+        let source = "loop: mov 1 5\nadd dat\n+ mov acc 3";
+        let warnings: Vec<String> = lint_source(source, ChipModel::Mc6000)
+            .unwrap()
+            .iter()
+            .map(|w| w.to_string())
+            .collect();
+        assert_eq!(
+            warnings,
+            vec![
+                "line 1: mov can't write to the literal 5",
+                "line 1: label loop is never jumped to",
+                "line 2: dat is read but never written, so it is always 0",
+                "line 3: mov can't write to the literal 3",
+                "line 3: this + line can never run: the program has no test instruction",
+            ]
+        );
+    }
+}
@@ -0,0 +1,771 @@
+/*
+    A Language Server Protocol server, so editors can check programs as
+    they're written.
+*/
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use crate::chip::ChipModel;
+use crate::instruction::Instruction;
+use crate::json::Json;
+use crate::lint;
+use crate::machine::Machine;
+use crate::parse;
+
+/// The largest message body the server will read, so a bad
+/// `Content-Length` can't make it allocate without limit.
+const MAX_MESSAGE: usize = 1 << 24;
+
+const ERROR: i64 = 1;
+const WARNING: i64 = 2;
+
+/// Completion item kinds from the protocol.
+const KEYWORD: i64 = 14;
+const VARIABLE: i64 = 6;
+const REFERENCE: i64 = 18;
+
+/// What a word in the source is.
+#[derive(Debug, PartialEq)]
+enum Kind {
+    Condition,
+    Mnemonic(Instruction),
+    Register,
+    Number,
+    /// Where a label is defined, without its colon.
+    Label,
+    /// A label named by `jmp`.
+    LabelRef,
+    /// A word in the place of a mnemonic that isn't one.
+    Unknown,
+}
+
+/// A word in the source and where it is. Columns count UTF-16 code units,
+/// as positions in the protocol do.
+#[derive(Debug)]
+struct Token<'a> {
+    line: usize,
+    start: usize,
+    end: usize,
+    text: &'a str,
+    kind: Kind,
+}
+
+/// Whitespace separated words of a line with the columns they span.
+fn words(line: &str) -> Vec<(usize, usize, &str)> {
+    let mut words = Vec::new();
+    let mut start = None;
+    let mut column = 0;
+    for (i, c) in line.char_indices() {
+        match (c.is_whitespace(), start) {
+            (false, None) => start = Some((i, column)),
+            (true, Some((from, at))) => {
+                words.push((at, column, &line[from..i]));
+                start = None;
+            }
+            _ => (),
+        }
+        column += c.len_utf16();
+    }
+    if let Some((from, at)) = start {
+        words.push((at, column, &line[from..]));
+    }
+    words
+}
+
+/// Split `source` into classified tokens, skipping comments.
+fn tokenize(source: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    for (line, text) in source.lines().enumerate() {
+        // Labels are split off the same way `parse::entries` does it, so a
+        // label and the instruction after it needn't be spaced apart.
+        let mut from = 0;
+        if let Some((name, _)) = parse::label(text) {
+            let at = text.len() - text.trim_start().len();
+            let start = text[..at].encode_utf16().count();
+            tokens.push(Token {
+                line,
+                start,
+                end: start + name.encode_utf16().count(),
+                text: name,
+                kind: Kind::Label,
+            });
+            // An instruction can follow it on the same line.
+            from = at + name.len() + 1;
+        }
+        let offset = text[..from].encode_utf16().count();
+        let words: Vec<_> = words(&text[from..])
+            .into_iter()
+            .map(|(start, end, word)| (start + offset, end + offset, word))
+            .collect();
+        if words.first().is_some_and(|(_, _, w)| w.starts_with('#')) {
+            continue;
+        }
+
+        let mut mnemonic = None;
+        for (start, end, text) in words {
+            let kind = match mnemonic {
+                None if text == "+" || text == "-" => Kind::Condition,
+                None => {
+                    let instruction = parse::mnemonic(text);
+                    mnemonic = Some(text);
+                    match instruction {
+                        Some(v) => Kind::Mnemonic(v),
+                        None => Kind::Unknown,
+                    }
+                }
+                Some("jmp") => Kind::LabelRef,
+                Some(_) if text.parse::<i64>().is_ok() => Kind::Number,
+                Some(_) => Kind::Register,
+            };
+            tokens.push(Token {
+                line,
+                start,
+                end,
+                text,
+                kind,
+            });
+        }
+    }
+    tokens
+}
+
+fn position(line: usize, character: usize) -> Json {
+    Json::object([("line", line.into()), ("character", character.into())])
+}
+
+fn range(line: usize, start: usize, end: usize) -> Json {
+    Json::object([
+        ("start", position(line, start)),
+        ("end", position(line, end)),
+    ])
+}
+
+fn diagnostic(line: usize, start: usize, end: usize, severity: i64, message: String) -> Json {
+    Json::object([
+        ("range", range(line, start, end)),
+        ("severity", severity.into()),
+        ("source", "shenzhen-io".into()),
+        ("message", message.into()),
+    ])
+}
+
+/// Problems in `source`: errors for anything that stops it loading, then
+/// lint warnings once it does. Lines are parsed by `parse::entries`, as
+/// `Machine::load` does, so the two agree on what is valid.
+fn diagnostics(source: &str, chip: ChipModel) -> Vec<Json> {
+    let mut found = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        if let Err(e) = parse::entries(line) {
+            let spans = words(line);
+            let start = spans.first().map_or(0, |w| w.0);
+            let end = spans.last().map_or(0, |w| w.1);
+            found.push(diagnostic(i, start, end, ERROR, e));
+        }
+    }
+
+    let tokens = tokenize(source);
+    let labels: Vec<&str> = tokens
+        .iter()
+        .filter(|t| t.kind == Kind::Label)
+        .map(|t| t.text)
+        .collect();
+    for token in &tokens {
+        let message = match token.kind {
+            Kind::Register if !chip.registers().contains(&token.text) => format!(
+                "unknown register {:?}; the {} has {}",
+                token.text,
+                chip.name(),
+                chip.registers().join(", ")
+            ),
+            Kind::LabelRef if !labels.contains(&token.text) => {
+                format!("undefined label {:?}", token.text)
+            }
+            _ => continue,
+        };
+        found.push(diagnostic(
+            token.line,
+            token.start,
+            token.end,
+            ERROR,
+            message,
+        ));
+    }
+
+    if found.is_empty()
+        && let Ok(machine) = Machine::load_chip(source, chip)
+    {
+        let lines: Vec<&str> = source.lines().collect();
+        for warning in lint::lint(&machine) {
+            let line = warning.line - 1;
+            let spans = words(lines.get(line).unwrap_or(&""));
+            let start = spans.first().map_or(0, |w| w.0);
+            let end = spans.last().map_or(0, |w| w.1);
+            found.push(diagnostic(line, start, end, WARNING, warning.message));
+        }
+    }
+    found
+}
+
+fn register_doc(name: &str) -> &'static str {
+    match name.chars().next() {
+        Some('a') => "The accumulator. Arithmetic instructions read and write it.",
+        Some('d') => "A general purpose register.",
+        Some('p') => "A simple I/O pin. Holds a level from 0 to 100 between timesteps.",
+        Some('x') => "An XBus pin. Reads wait until another chip writes a value.",
+        _ => "",
+    }
+}
+
+/// The language server. Documents are kept in full and rechecked on every
+/// change.
+pub struct Server {
+    chip: ChipModel,
+    documents: HashMap<String, String>,
+    shutdown: bool,
+    exited: bool,
+}
+
+impl Server {
+    /// A server for programs on `chip`, unless the editor picks another
+    /// model with the `chip` initialization option.
+    pub fn new(chip: ChipModel) -> Server {
+        Server {
+            chip,
+            documents: HashMap::new(),
+            shutdown: false,
+            exited: false,
+        }
+    }
+
+    /// Serve messages from `input` until the editor sends `exit`.
+    pub fn run(&mut self, mut input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        while !self.exited {
+            let message = match read_message(&mut input)? {
+                Some(v) => v,
+                None => break,
+            };
+            let replies = match Json::parse(&message) {
+                Ok(v) => self.handle(&v),
+                Err(e) => vec![error(Json::Null, -32700, &e)],
+            };
+            for reply in replies {
+                write_message(&mut output, &reply)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle one message, returning the responses and notifications to
+    /// send back.
+    pub fn handle(&mut self, message: &Json) -> Vec<Json> {
+        let method = message.get("method").and_then(Json::as_str).unwrap_or("");
+        let params = message.get("params").unwrap_or(&Json::Null);
+        let id = message.get("id");
+        if self.shutdown && method != "exit" {
+            return match id {
+                Some(id) => vec![error(id.clone(), -32600, "the server is shutting down")],
+                None => Vec::new(),
+            };
+        }
+
+        let result = match method {
+            "initialize" => {
+                let chip = params
+                    .get("initializationOptions")
+                    .and_then(|o| o.get("chip"))
+                    .and_then(Json::as_str);
+                if let Some(chip) = chip.and_then(|c| c.parse().ok()) {
+                    self.chip = chip;
+                }
+                self.initialize()
+            }
+            "shutdown" => {
+                self.shutdown = true;
+                Json::Null
+            }
+            "exit" => {
+                self.exited = true;
+                return Vec::new();
+            }
+            "textDocument/didOpen" | "textDocument/didChange" => {
+                let document = params.get("textDocument");
+                let uri = document.and_then(|d| d.get("uri")).and_then(Json::as_str);
+                // With full sync the last change holds the whole text.
+                let text = match params.get("contentChanges").and_then(Json::as_array) {
+                    Some(changes) => changes.last().and_then(|c| c.get("text")),
+                    None => document.and_then(|d| d.get("text")),
+                };
+                return match (uri, text.and_then(Json::as_str)) {
+                    (Some(uri), Some(text)) => {
+                        self.documents.insert(uri.to_owned(), text.to_owned());
+                        vec![self.publish(uri)]
+                    }
+                    _ => Vec::new(),
+                };
+            }
+            "textDocument/didClose" => {
+                let uri = params.get("textDocument").and_then(|d| d.get("uri"));
+                if let Some(uri) = uri.and_then(Json::as_str) {
+                    self.documents.remove(uri);
+                }
+                return Vec::new();
+            }
+            "textDocument/hover" => self.hover(params),
+            "textDocument/definition" => self.definition(params),
+            "textDocument/references" => self.references(params),
+            "textDocument/completion" => self.completion(params),
+            _ => match id {
+                Some(id) => return vec![error(id.clone(), -32601, "method not found")],
+                None => return Vec::new(),
+            },
+        };
+        match id {
+            Some(id) => vec![Json::object([
+                ("jsonrpc", "2.0".into()),
+                ("id", id.clone()),
+                ("result", result),
+            ])],
+            None => Vec::new(),
+        }
+    }
+
+    fn initialize(&self) -> Json {
+        Json::object([
+            (
+                "capabilities",
+                Json::object([
+                    ("positionEncoding", "utf-16".into()),
+                    ("textDocumentSync", Json::Number(1)),
+                    ("hoverProvider", true.into()),
+                    ("definitionProvider", true.into()),
+                    ("referencesProvider", true.into()),
+                    (
+                        "completionProvider",
+                        Json::object([("triggerCharacters", Json::Array(vec![" ".into()]))]),
+                    ),
+                ]),
+            ),
+            (
+                "serverInfo",
+                Json::object([("name", "rust-shenzhenio".into())]),
+            ),
+        ])
+    }
+
+    fn publish(&self, uri: &str) -> Json {
+        let diagnostics = diagnostics(&self.documents[uri], self.chip);
+        Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("method", "textDocument/publishDiagnostics".into()),
+            (
+                "params",
+                Json::object([
+                    ("uri", uri.into()),
+                    ("diagnostics", Json::Array(diagnostics)),
+                ]),
+            ),
+        ])
+    }
+
+    /// The document and cursor position a request is about.
+    fn at<'a>(&'a self, params: &'a Json) -> Option<(&'a str, &'a str, usize, usize)> {
+        let uri = params.get("textDocument")?.get("uri")?.as_str()?;
+        let position = params.get("position")?;
+        let line = position.get("line")?.as_i64()? as usize;
+        let character = position.get("character")?.as_i64()? as usize;
+        Some((uri, self.documents.get(uri)?, line, character))
+    }
+
+    fn hover(&self, params: &Json) -> Json {
+        let (_, source, line, character) = match self.at(params) {
+            Some(v) => v,
+            None => return Json::Null,
+        };
+        let tokens = tokenize(source);
+        let token = match token_at(&tokens, line, character) {
+            Some(v) => v,
+            None => return Json::Null,
+        };
+        let text = match &token.kind {
+            Kind::Mnemonic(instruction) => format!(
+                "```\n{}\n```\n{}",
+                instruction.signature(),
+                instruction.description()
+            ),
+            Kind::Condition => format!(
+                "Runs this line only if the last test was {}.",
+                if token.text == "+" { "true" } else { "false" }
+            ),
+            Kind::Register if self.chip.registers().contains(&token.text) => {
+                format!("`{}`: {}", token.text, register_doc(token.text))
+            }
+            Kind::Register => format!("The {} has no register {}.", self.chip.name(), token.text),
+            Kind::Label | Kind::LabelRef => match definition(&tokens, token.text) {
+                Some(def) => format!("Label `{}`, defined on line {}.", token.text, def.line + 1),
+                None => format!("Label `{}` is not defined.", token.text),
+            },
+            Kind::Number | Kind::Unknown => return Json::Null,
+        };
+        Json::object([
+            (
+                "contents",
+                Json::object([("kind", "markdown".into()), ("value", text.into())]),
+            ),
+            ("range", range(token.line, token.start, token.end)),
+        ])
+    }
+
+    fn definition(&self, params: &Json) -> Json {
+        let (uri, source, line, character) = match self.at(params) {
+            Some(v) => v,
+            None => return Json::Null,
+        };
+        let tokens = tokenize(source);
+        match token_at(&tokens, line, character) {
+            Some(token) if matches!(token.kind, Kind::Label | Kind::LabelRef) => {
+                match definition(&tokens, token.text) {
+                    Some(def) => location(uri, def),
+                    None => Json::Null,
+                }
+            }
+            _ => Json::Null,
+        }
+    }
+
+    fn references(&self, params: &Json) -> Json {
+        let (uri, source, line, character) = match self.at(params) {
+            Some(v) => v,
+            None => return Json::Null,
+        };
+        let declaration = params
+            .get("context")
+            .and_then(|c| c.get("includeDeclaration"))
+            .and_then(Json::as_bool)
+            .unwrap_or(true);
+        let tokens = tokenize(source);
+        let name = match token_at(&tokens, line, character) {
+            Some(token) if matches!(token.kind, Kind::Label | Kind::LabelRef) => token.text,
+            _ => return Json::Null,
+        };
+        let found = tokens
+            .iter()
+            .filter(|t| t.text == name)
+            .filter(|t| t.kind == Kind::LabelRef || (declaration && t.kind == Kind::Label))
+            .map(|t| location(uri, t))
+            .collect();
+        Json::Array(found)
+    }
+
+    fn completion(&self, params: &Json) -> Json {
+        let (_, source, line, character) = match self.at(params) {
+            Some(v) => v,
+            None => return Json::Null,
+        };
+        let text = source.lines().nth(line).unwrap_or("");
+        let before = utf16_prefix(text, character);
+        let mut words: Vec<&str> = before.split_whitespace().collect();
+        // The word being typed doesn't count.
+        if !before.ends_with(char::is_whitespace) {
+            words.pop();
+        }
+        if words.first().is_some_and(|w| w.ends_with(':')) {
+            words.remove(0);
+        }
+        if words.first().is_some_and(|w| *w == "+" || *w == "-") {
+            words.remove(0);
+        }
+
+        let item = |label: &str, kind: i64, detail: &str| {
+            Json::object([
+                ("label", label.into()),
+                ("kind", kind.into()),
+                ("detail", detail.into()),
+            ])
+        };
+        let items: Vec<Json> = match words.first() {
+            Some(w) if w.starts_with('#') || w.contains(':') => Vec::new(),
+            None => parse::MNEMONICS
+                .iter()
+                .map(|m| item(m, KEYWORD, parse::mnemonic(m).unwrap().signature()))
+                .collect(),
+            Some(&"jmp") => tokenize(source)
+                .iter()
+                .filter(|t| t.kind == Kind::Label)
+                .map(|t| item(t.text, REFERENCE, "label"))
+                .collect(),
+            Some(_) => self
+                .chip
+                .registers()
+                .iter()
+                .map(|r| item(r, VARIABLE, register_doc(r)))
+                .collect(),
+        };
+        Json::Array(items)
+    }
+}
+
+/// The start of `text` up to the UTF-16 column `column`.
+fn utf16_prefix(text: &str, column: usize) -> &str {
+    let mut units = 0;
+    for (i, c) in text.char_indices() {
+        if units >= column {
+            return &text[..i];
+        }
+        units += c.len_utf16();
+    }
+    text
+}
+
+fn token_at<'a, 'b>(
+    tokens: &'b [Token<'a>],
+    line: usize,
+    character: usize,
+) -> Option<&'b Token<'a>> {
+    tokens
+        .iter()
+        .find(|t| t.line == line && t.start <= character && character <= t.end)
+}
+
+/// Where `name` is defined. With more than one definition, jumps go to the
+/// last.
+fn definition<'a, 'b>(tokens: &'b [Token<'a>], name: &str) -> Option<&'b Token<'a>> {
+    tokens
+        .iter()
+        .rev()
+        .find(|t| t.kind == Kind::Label && t.text == name)
+}
+
+fn location(uri: &str, token: &Token) -> Json {
+    Json::object([
+        ("uri", uri.into()),
+        ("range", range(token.line, token.start, token.end)),
+    ])
+}
+
+fn error(id: Json, code: i64, message: &str) -> Json {
+    Json::object([
+        ("jsonrpc", "2.0".into()),
+        ("id", id),
+        (
+            "error",
+            Json::object([("code", code.into()), ("message", message.into())]),
+        ),
+    ])
+}
+
+/// Read one message body, framed by a `Content-Length` header. Returns
+/// `None` at the end of the input. A body that isn't UTF-8 is skipped, as
+/// one bad message shouldn't stop the server.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    loop {
+        let mut length = None;
+        loop {
+            let mut header = String::new();
+            if input.read_line(&mut header)? == 0 {
+                return Ok(None);
+            }
+            let header = header.trim();
+            if header.is_empty() {
+                if length.is_some() {
+                    break;
+                }
+                continue;
+            }
+            if let Some((name, value)) = header.split_once(':')
+                && name.eq_ignore_ascii_case("content-length")
+            {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+        let length = length.unwrap_or_default();
+        if length > MAX_MESSAGE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("message of {} bytes is over the limit of {}", length, MAX_MESSAGE),
+            ));
+        }
+        let mut body = vec![0; length];
+        input.read_exact(&mut body)?;
+        match String::from_utf8(body) {
+            Ok(v) => return Ok(Some(v)),
+            Err(e) => eprintln!("skipping a message that isn't UTF-8: {}", e),
+        }
+    }
+}
+
+fn write_message(output: &mut impl Write, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Server;
+    use crate::chip::ChipModel;
+    use crate::json::Json;
+
+    const URI: &str = "file:///prog.asm";
+
+    fn open(server: &mut Server, text: &str) -> Json {
+        let message = Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("method", "textDocument/didOpen".into()),
+            (
+                "params",
+                Json::object([(
+                    "textDocument",
+                    Json::object([("uri", URI.into()), ("text", text.into())]),
+                )]),
+            ),
+        ]);
+        server.handle(&message).remove(0)
+    }
+
+    fn request(server: &mut Server, method: &str, line: usize, character: usize) -> Json {
+        let message = Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("id", Json::Number(1)),
+            ("method", method.into()),
+            (
+                "params",
+                Json::object([
+                    ("textDocument", Json::object([("uri", URI.into())])),
+                    (
+                        "position",
+                        Json::object([("line", line.into()), ("character", character.into())]),
+                    ),
+                ]),
+            ),
+        ]);
+        let reply = server.handle(&message).remove(0);
+        reply.get("result").unwrap().clone()
+    }
+
+    fn messages(diagnostics: &Json) -> Vec<(i64, String)> {
+        let list = diagnostics
+            .get("params")
+            .unwrap()
+            .get("diagnostics")
+            .unwrap();
+        list.as_array()
+            .unwrap()
+            .iter()
+            .map(|d| {
+                let line = d.get("range").unwrap().get("start").unwrap().get("line");
+                (
+                    line.and_then(Json::as_i64).unwrap(),
+                    d.get("message").and_then(Json::as_str).unwrap().to_owned(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reports_errors_and_warnings() {
+        let mut server = Server::new(ChipModel::Mc4000);
+        let published = open(&mut server, "mov 1 dat\njmp nowhere\nfoo 3");
+        let found = messages(&published);
+        assert_eq!(found.len(), 3, "{:?}", found);
+        assert_eq!(found[0].0, 2);
+        assert!(found[0].1.contains("unknown instruction"));
+        assert!(found[1].1.contains("unknown register \"dat\""));
+        assert!(found[2].1.contains("undefined label"));
+
+        let published = open(&mut server, "loop:\nadd 1\nunused:\njmp loop");
+        assert_eq!(
+            messages(&published),
+            vec![(2, String::from("label unused is never jumped to"))]
+        );
+
+        // Loads from the command line, so isn't an error here either.
+        let published = open(&mut server, "loop:add 1\n  jmp loop");
+        assert_eq!(messages(&published), vec![]);
+    }
+
+    #[test]
+    fn hovers_and_navigates_labels() {
+        let mut server = Server::new(ChipModel::Mc6000);
+        open(&mut server, "loop:\n  teq acc 1\n  + jmp loop\n  jmp loop");
+
+        let hover = request(&mut server, "textDocument/hover", 1, 3);
+        let text = hover.get("contents").unwrap().get("value").unwrap();
+        assert!(text.as_str().unwrap().contains("teq R/I R/I"));
+
+        let definition = request(&mut server, "textDocument/definition", 2, 8);
+        let start = definition.get("range").unwrap().get("start").unwrap();
+        assert_eq!(start.get("line").and_then(Json::as_i64), Some(0));
+
+        let references = request(&mut server, "textDocument/references", 0, 1);
+        assert_eq!(references.as_array().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn completes_registers_for_the_chip() {
+        let mut server = Server::new(ChipModel::Mc4000);
+        open(&mut server, "mov 1 \n");
+        let items = request(&mut server, "textDocument/completion", 0, 6);
+        let labels: Vec<&str> = items
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|i| i.get("label").and_then(Json::as_str).unwrap())
+            .collect();
+        assert_eq!(labels, ["acc", "p0", "p1", "x0", "x1"]);
+    }
+
+    #[test]
+    fn speaks_the_wire_protocol() {
+        let body = r#"{"jsonrpc":"2.0","id":7,"method":"initialize","params":{"initializationOptions":{"chip":"mc4000"}}}"#;
+        let exit = r#"{"jsonrpc":"2.0","method":"exit"}"#;
+        let input = format!(
+            "Content-Length: {}\r\n\r\n{}Content-Length: {}\r\n\r\n{}",
+            body.len(),
+            body,
+            exit.len(),
+            exit
+        );
+        let mut server = Server::new(ChipModel::Mc6000);
+        let mut output = Vec::new();
+        server.run(input.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let (header, reply) = output.split_once("\r\n\r\n").unwrap();
+        assert_eq!(header, format!("Content-Length: {}", reply.len()));
+        let reply = Json::parse(reply).unwrap();
+        assert_eq!(reply.get("id").and_then(Json::as_i64), Some(7));
+        assert_eq!(server.chip, ChipModel::Mc4000);
+    }
+
+    #[test]
+    fn columns_count_utf16_code_units() {
+        let mut server = Server::new(ChipModel::Mc6000);
+        let published = open(&mut server, "mov 😀😀😀 acc");
+        let diagnostics = published.get("params").unwrap().get("diagnostics").unwrap();
+        let range = diagnostics.as_array().unwrap()[0].get("range").unwrap();
+        let end = range.get("end").unwrap().get("character");
+        assert_eq!(end.and_then(Json::as_i64), Some(10));
+
+        let hover = request(&mut server, "textDocument/hover", 0, 13);
+        let text = hover.get("contents").unwrap().get("value").unwrap();
+        assert!(text.as_str().unwrap().starts_with("`acc`"), "{:?}", text);
+    }
+
+    #[test]
+    fn skips_messages_that_are_not_utf8() {
+        let exit = r#"{"jsonrpc":"2.0","method":"exit"}"#;
+        let mut input = b"Content-Length: 2\r\n\r\n\xff\xfe".to_vec();
+        input.extend(format!("Content-Length: {}\r\n\r\n{}", exit.len(), exit).bytes());
+        let mut server = Server::new(ChipModel::Mc6000);
+        server.run(input.as_slice(), Vec::new()).unwrap();
+        assert!(server.exited);
+    }
+
+    #[test]
+    fn refuses_oversized_messages() {
+        let input = "Content-Length: 99999999999\r\n\r\n{}";
+        let mut server = Server::new(ChipModel::Mc6000);
+        let err = server.run(input.as_bytes(), Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
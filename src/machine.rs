@@ -1,274 +1,1075 @@
-use crate::{instruction::Arg, instruction::Instruction, register::Register};
+use crate::limits::{Limits, Outcome, Run};
+use crate::register::{self, Register};
+use crate::{chip::ChipModel, instruction::Arg, instruction::Instruction, parse};
+use std::cmp::Ordering;
 use std::collections::HashMap;
+use std::fmt;
+
+/// A chip that runs this many instructions in one timestep without sleeping
+/// or blocking is assumed to be stuck.
+pub(crate) const MAX_STEPS_PER_TIMESTEP: usize = 10_000;
+
+/// A single chip running a parsed program.
+///
+/// The machine owns its registers, label table, program counter and branch
+/// flag. Time is measured in two ways: `steps` counts executed instructions,
+/// and `timestep` is the game clock, which only moves forward when the
+/// program sleeps with `slp`.
+#[derive(Debug)]
+pub struct Machine {
+    chip: ChipModel,
+    program: Vec<(Instruction, Vec<Arg>)>,
+    /// Source line number of each entry in `program`, when loaded from source.
+    lines: Vec<usize>,
+    labels: HashMap<Arg, usize>,
+    registers: HashMap<String, Register>,
+    inputs: Vec<(String, Vec<i64>)>,
+    pc: usize,
+    flag: Flag,
+    steps: usize,
+    power: usize,
+    /// How many times each program entry has run.
+    executed: Vec<usize>,
+    /// How many times each `+`/`-` line was reached but didn't run.
+    skipped: Vec<usize>,
+    timestep: usize,
+    wake_at: usize,
+    /// Go back to the first line after the last one, as chips in the game do,
+    /// instead of halting.
+    wrap: bool,
+    /// A register written through `Io` whose value hasn't been taken yet.
+    sending: Option<String>,
+}
+
+/// What happened when the machine was asked to step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    Ran,
+    /// Waiting on a read from outside the chip, or for something outside to
+    /// take a written value; nothing changed.
+    Blocked,
+    Halted,
+}
+
+/// Which conditional lines run. Tests pick between `+` and `-` lines, except
+/// `tcp` between equal values, which switches both off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Flag {
+    Plus,
+    Minus,
+    Neither,
+}
+
+impl Flag {
+    /// Whether a line with these arguments runs under this flag.
+    pub fn runs(self, args: &[Arg]) -> bool {
+        let plus = args.contains(&Arg::BranchTrue);
+        let minus = args.contains(&Arg::BranchFalse);
+        match self {
+            Flag::Plus => !minus,
+            Flag::Minus => !plus,
+            Flag::Neither => !plus && !minus,
+        }
+    }
+}
+
+impl From<bool> for Flag {
+    fn from(plus: bool) -> Flag {
+        if plus { Flag::Plus } else { Flag::Minus }
+    }
+}
+
+impl fmt::Display for Flag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            Flag::Plus => "+",
+            Flag::Minus => "-",
+            Flag::Neither => "none",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+/// Registers that are wired up outside the chip.
+pub trait Io {
+    /// Whether reads and writes of `name` should go through this.
+    fn connected(&self, name: &str) -> bool;
+    /// Whether every read in `names` can happen now. A name appears once
+    /// for each time the instruction reads it.
+    fn ready(&self, names: &[&str]) -> bool;
+    fn read(&mut self, name: &str) -> Option<i64>;
+    fn write(&mut self, name: &str, value: i64);
+    /// Whether what was last written to `name` has been taken, so the
+    /// writer can go on.
+    fn delivered(&self, name: &str) -> bool;
+}
+
+/// A chip with nothing attached, where every register is plain storage.
+struct Detached;
+
+impl Io for Detached {
+    fn connected(&self, _: &str) -> bool {
+        false
+    }
+
+    fn ready(&self, _: &[&str]) -> bool {
+        true
+    }
+
+    fn read(&mut self, _: &str) -> Option<i64> {
+        Some(0)
+    }
+
+    fn write(&mut self, _: &str, _: i64) {}
+
+    fn delivered(&self, _: &str) -> bool {
+        true
+    }
+}
+
+/// Everything about a machine that changes as it runs, for rewinding it to
+/// an earlier point.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    /// Register values in the order the chip lists them.
+    registers: Vec<i64>,
+    pc: usize,
+    flag: Flag,
+    steps: usize,
+    power: usize,
+    timestep: usize,
+    wake_at: usize,
+    sending: Option<String>,
+    executed: Vec<usize>,
+    skipped: Vec<usize>,
+}
+
+impl Snapshot {
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    /// The timestep the next instruction ran in after this snapshot was taken.
+    pub fn timestep(&self) -> usize {
+        self.timestep.max(self.wake_at)
+    }
+}
+
+impl Machine {
+    /// Build a machine from an already parsed program.
+    ///
+    /// Fails if the program refers to a register or label that doesn't exist.
+    pub fn new(program: Vec<(Instruction, Vec<Arg>)>) -> Result<Machine, String> {
+        Machine::with_chip(program, ChipModel::default())
+    }
+
+    /// Build a machine for a specific chip model from an already parsed program.
+    pub fn with_chip(
+        program: Vec<(Instruction, Vec<Arg>)>,
+        chip: ChipModel,
+    ) -> Result<Machine, String> {
+        let machine = Machine::build(program, chip);
+        machine.check()?;
+        Ok(machine)
+    }
+
+    fn build(program: Vec<(Instruction, Vec<Arg>)>, chip: ChipModel) -> Machine {
+        // we want the labels to be based off of the distilled program,
+        // instead of being based off of the initial parsing.
+        let mut labels: HashMap<Arg, usize> = HashMap::new();
+        // A label without a name is left for `check` to report.
+        for (i, (instr, args)) in program.iter().enumerate() {
+            if let (Instruction::Label, Some(label)) = (instr, args.first()) {
+                labels.insert(label.to_owned(), i);
+            }
+        }
+
+        let registers: HashMap<String, Register> = chip
+            .registers()
+            .iter()
+            .map(|name| (name.to_string(), Register { value: 0 }))
+            .collect();
+
+        let len = program.len();
+        Machine {
+            chip,
+            program,
+            lines: Vec::new(),
+            labels,
+            registers,
+            inputs: Vec::new(),
+            pc: 0,
+            flag: Flag::Minus,
+            steps: 0,
+            power: 0,
+            executed: vec![0; len],
+            skipped: vec![0; len],
+            timestep: 0,
+            wake_at: 0,
+            wrap: false,
+            sending: None,
+        }
+    }
+
+    /// Parse `source` and build a machine from it.
+    ///
+    /// Every line is checked before anything runs, so a program that loads
+    /// will not panic on unknown instructions, registers or labels.
+    pub fn load(source: &str) -> Result<Machine, String> {
+        Machine::load_chip(source, ChipModel::default())
+    }
+
+    /// Parse `source` and build a machine for a specific chip model from it.
+    pub fn load_chip(source: &str, chip: ChipModel) -> Result<Machine, String> {
+        let (lines, program) = parse::program(source)?.into_iter().unzip();
+
+        let mut machine = Machine::build(program, chip);
+        machine.lines = lines;
+        machine.check()?;
+        Ok(machine)
+    }
+
+    /// Make sure every line has the operands its instruction takes, and that
+    /// every register and label the program refers to exists.
+    fn check(&self) -> Result<(), String> {
+        for (i, (instr, args)) in self.program.iter().enumerate() {
+            let at = match self.line_of(i) {
+                Some(line) => format!("line {}: ", line),
+                None => String::new(),
+            };
+            if let Err(e) = operands(instr, args) {
+                return Err(format!("{}{}", at, e));
+            }
+            for arg in args {
+                match arg {
+                    Arg::Register(name) if !self.registers.contains_key(name) => {
+                        return Err(format!("{}unknown register {:?} in {:?}", at, name, instr));
+                    }
+                    Arg::Label(_)
+                        if *instr == Instruction::Jmp && !self.labels.contains_key(arg) =>
+                    {
+                        return Err(format!("{}undefined label {:?}", at, arg));
+                    }
+                    _ => (),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Execute the next instruction.
+    ///
+    /// If the machine is asleep the clock is moved forward to when it wakes
+    /// up first. Returns `false` without doing anything once the program
+    /// counter has run off the end of the program.
+    pub fn step(&mut self) -> bool {
+        self.step_with(&mut Detached) == Step::Ran
+    }
+
+    /// Execute the next instruction, with the registers `io` claims wired to
+    /// the outside world. Reads of those registers go through `io` and
+    /// writes are passed on to it after the instruction runs.
+    ///
+    /// Nothing happens if the instruction needs to read something `io` isn't
+    /// ready to give, such as an XBus port nobody has written to yet, or
+    /// while a value the last instruction wrote hasn't been taken.
+    pub fn step_with(&mut self, io: &mut dyn Io) -> Step {
+        if let Some(name) = &self.sending {
+            if !io.delivered(name) {
+                return Step::Blocked;
+            }
+            self.sending = None;
+        }
+        if self.is_halted() {
+            return Step::Halted;
+        }
+        if self.wake_at > self.timestep {
+            self.advance_to(self.wake_at);
+        }
+
+        let (instr, args) = &self.program[self.pc];
+        let runs = *instr != Instruction::Label && self.will_execute(args);
+        let mut read = None;
+        if runs {
+            let reads: Vec<&str> = read_registers(&self.program[self.pc])
+                .into_iter()
+                .filter(|r| io.connected(r))
+                .collect();
+            if !io.ready(&reads) {
+                return Step::Blocked;
+            }
+            if !reads.is_empty() {
+                // Each read takes its own value, so `teq x0 x0` compares two.
+                let mut line = self.program[self.pc].clone();
+                for (i, arg) in line.1.iter_mut().enumerate() {
+                    if *instr == Instruction::Mov && i == 1 {
+                        continue;
+                    }
+                    if let Arg::Register(name) = arg
+                        && io.connected(name)
+                    {
+                        let Some(value) = io.read(name) else {
+                            return Step::Blocked;
+                        };
+                        self.registers.insert(name.to_owned(), Register { value });
+                        *arg = Arg::Number(value);
+                    }
+                }
+                read = Some(line);
+            }
+        }
+        let line = read.as_ref().unwrap_or(&self.program[self.pc]);
+        let (instr, args) = line;
+        if runs {
+            self.power += 1;
+            self.executed[self.pc] += 1;
+            if *instr == Instruction::Slp {
+                let duration = value_of(&args[0], &self.registers).max(0);
+                self.wake_at = self.timestep + duration as usize;
+            }
+        } else if *instr != Instruction::Label {
+            self.skipped[self.pc] += 1;
+        }
+
+        let (pc, flag) = exec(line, &mut self.registers, &self.labels, self.pc, &self.flag);
+        if runs
+            && let Some(name) = written_register(line)
+            && io.connected(name)
+        {
+            io.write(name, self.registers[name].value);
+            self.sending = Some(name.to_owned());
+        }
+
+        self.pc = pc;
+        if self.wrap && self.pc >= self.program.len() {
+            self.pc = 0;
+        }
+        self.flag = flag;
+        self.steps += 1;
+        Step::Ran
+    }
+
+    /// Run until the program counter runs off the end of the program.
+    ///
+    /// Returns the number of steps executed by this call.
+    pub fn run(&mut self) -> usize {
+        let start = self.steps;
+        while self.step() {}
+        self.steps - start
+    }
+
+    /// Run until the program halts or one of `limits` is reached, calling
+    /// `observe` after every step.
+    pub fn run_with(&mut self, limits: &Limits, mut observe: impl FnMut(&Machine)) -> Outcome {
+        let mut run = Run::start(self);
+        while !self.is_halted() {
+            if let Some(outcome) = limits.check(self, &mut run) {
+                return outcome;
+            }
+            self.step();
+            observe(self);
+        }
+        Outcome::Halted
+    }
+
+    /// Run for `timesteps` game timesteps, or until the program halts.
+    ///
+    /// Returns the number of steps executed by this call, or an error if the
+    /// program runs too long in one timestep without sleeping.
+    pub fn run_for(&mut self, timesteps: usize) -> Result<usize, String> {
+        let start = self.steps;
+        let end = self.timestep + timesteps;
+        let (mut current, mut since) = (self.next_timestep(), self.steps);
+        while !self.is_halted() {
+            if self.wake_at >= end {
+                self.advance_to(end);
+                break;
+            }
+            if self.next_timestep() != current {
+                (current, since) = (self.next_timestep(), self.steps);
+            } else if self.steps - since >= MAX_STEPS_PER_TIMESTEP {
+                return Err(format!(
+                    "ran {} instructions in timestep {} without sleeping",
+                    MAX_STEPS_PER_TIMESTEP, current
+                ));
+            }
+            self.step();
+        }
+        Ok(self.steps - start)
+    }
+
+    /// Loop back to the first line after the last, as chips in the game do,
+    /// instead of halting there.
+    pub fn set_wrap(&mut self, wrap: bool) {
+        self.wrap = wrap;
+    }
+
+    /// Move the clock forward to `timestep` without running anything, as
+    /// happens to a chip that is blocked while the rest of the board moves on.
+    pub fn advance_clock(&mut self, timestep: usize) {
+        if timestep > self.timestep {
+            self.advance_to(timestep);
+        }
+    }
+
+    /// Move the clock forward and drive any input registers to their values
+    /// for the new timestep.
+    fn advance_to(&mut self, timestep: usize) {
+        self.timestep = timestep;
+        for (name, values) in &self.inputs {
+            if let Some(value) = values.get(timestep).or(values.last()) {
+                self.registers
+                    .insert(name.to_owned(), Register { value: *value });
+            }
+        }
+    }
+
+    /// Whether the line with these arguments would run under the current
+    /// branch flag.
+    fn will_execute(&self, args: &[Arg]) -> bool {
+        self.flag.runs(args)
+    }
+
+    /// Capture the machine's state so it can be restored later.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            registers: self
+                .chip
+                .registers()
+                .iter()
+                .map(|name| self.registers[*name].value)
+                .collect(),
+            pc: self.pc,
+            flag: self.flag,
+            steps: self.steps,
+            power: self.power,
+            timestep: self.timestep,
+            wake_at: self.wake_at,
+            sending: self.sending.clone(),
+            executed: self.executed.clone(),
+            skipped: self.skipped.clone(),
+        }
+    }
+
+    /// Put the machine back into a state captured by `snapshot`.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        for (name, value) in self.chip.registers().iter().zip(&snapshot.registers) {
+            self.registers
+                .insert(name.to_string(), Register { value: *value });
+        }
+        self.pc = snapshot.pc;
+        self.flag = snapshot.flag;
+        self.steps = snapshot.steps;
+        self.power = snapshot.power;
+        self.timestep = snapshot.timestep;
+        self.wake_at = snapshot.wake_at;
+        self.sending.clone_from(&snapshot.sending);
+        self.executed.clone_from(&snapshot.executed);
+        self.skipped.clone_from(&snapshot.skipped);
+    }
+
+    /// The register the next instruction will write to, if it runs and
+    /// writes one.
+    pub fn next_write(&self) -> Option<&str> {
+        let (_, args) = self.program.get(self.pc)?;
+        if !self.will_execute(args) {
+            return None;
+        }
+        written_register(&self.program[self.pc])
+    }
+
+    /// The value held in the register called `name`, if it exists.
+    pub fn register(&self, name: &str) -> Option<i64> {
+        self.registers.get(name).map(|r| r.value)
+    }
+
+    pub fn registers(&self) -> &HashMap<String, Register> {
+        &self.registers
+    }
+
+    /// Overwrite the value held in the register called `name`.
+    pub fn set_register(&mut self, name: &str, value: i64) -> Result<(), String> {
+        if !(register::MIN..=register::MAX).contains(&value) {
+            return Err(format!(
+                "{} is outside the range {} to {}",
+                value,
+                register::MIN,
+                register::MAX
+            ));
+        }
+        match self.registers.get_mut(name) {
+            Some(register) => {
+                register.value = value;
+                Ok(())
+            }
+            None => Err(format!(
+                "{} has no register called {:?}",
+                self.chip.name(),
+                name
+            )),
+        }
+    }
+
+    /// Drive the register called `name` from outside the chip: at timestep
+    /// `t` it holds `values[t]`, and keeps the last value once they run out.
+    pub fn set_input(&mut self, name: &str, values: Vec<i64>) -> Result<(), String> {
+        for value in &values {
+            self.set_register(name, *value)?;
+        }
+        self.inputs.retain(|(n, _)| n != name);
+        self.inputs.push((name.to_owned(), values));
+        self.advance_to(self.timestep);
+        Ok(())
+    }
+
+    pub fn chip(&self) -> ChipModel {
+        self.chip
+    }
+
+    /// Index into the parsed program of the next instruction to run.
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Whether `+` lines run under the flag set by the last test.
+    pub fn branch(&self) -> bool {
+        self.flag == Flag::Plus
+    }
+
+    /// The flag set by the last `teq`, `tgt`, `tlt` or `tcp`.
+    pub fn flag(&self) -> Flag {
+        self.flag
+    }
+
+    /// Number of instructions executed so far.
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    /// Number of instructions that actually ran, which is what the game
+    /// charges power for. Labels and skipped `+`/`-` lines are free.
+    pub fn power(&self) -> usize {
+        self.power
+    }
+
+    /// How many times each program entry has run. Labels never do.
+    pub fn executions(&self) -> &[usize] {
+        &self.executed
+    }
+
+    /// How many times each `+`/`-` line was reached but skipped because of
+    /// the flag.
+    pub fn skips(&self) -> &[usize] {
+        &self.skipped
+    }
+
+    /// Lines of code as the game counts them: every instruction, but not
+    /// lines holding only a label.
+    pub fn lines_of_code(&self) -> usize {
+        self.program
+            .iter()
+            .filter(|(instr, _)| *instr != Instruction::Label)
+            .count()
+    }
+
+    /// The current game timestep.
+    pub fn timestep(&self) -> usize {
+        self.timestep
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.pc >= self.program.len()
+    }
+
+    /// The timestep the next instruction will run in, which is later than
+    /// the current one while the machine is asleep.
+    pub fn next_timestep(&self) -> usize {
+        self.timestep.max(self.wake_at)
+    }
+
+    /// Whether the last instruction put the machine to sleep.
+    pub fn is_asleep(&self) -> bool {
+        self.wake_at > self.timestep
+    }
+
+    pub fn program(&self) -> &[(Instruction, Vec<Arg>)] {
+        &self.program
+    }
+
+    /// The source line the program entry at `index` came from. Only known
+    /// for machines built with `load`.
+    pub fn line_of(&self, index: usize) -> Option<usize> {
+        self.lines.get(index).copied()
+    }
+
+    /// The source line of the next instruction to run.
+    pub fn line(&self) -> Option<usize> {
+        self.line_of(self.pc)
+    }
+
+    /// Index into the parsed program of the label called `name`.
+    pub fn label(&self, name: &str) -> Option<usize> {
+        self.labels.get(&Arg::Label(name.to_owned())).copied()
+    }
+}
+
+/// Check that a line's operands come first and are of the kinds its
+/// instruction takes, followed by at most one condition, so that `exec` can
+/// index them without looking.
+fn operands(instr: &Instruction, args: &[Arg]) -> Result<(), String> {
+    if *instr == Instruction::Label {
+        return match args {
+            [Arg::Label(_)] => Ok(()),
+            _ => Err(format!("a label takes one name, not {:?}", args)),
+        };
+    }
+    let given = args
+        .iter()
+        .filter(|a| !matches!(a, Arg::BranchTrue | Arg::BranchFalse))
+        .count();
+    if given != instr.arity() {
+        return Err(format!(
+            "{:?} takes {} argument(s), {} given",
+            instr,
+            instr.arity(),
+            given
+        ));
+    }
+    for (i, arg) in args.iter().enumerate() {
+        let fits = match (instr, i) {
+            (_, i) if i >= instr.arity() => matches!(arg, Arg::BranchTrue | Arg::BranchFalse),
+            (Instruction::Jmp, _) => matches!(arg, Arg::Label(_)),
+            (Instruction::Mov, 1) => matches!(arg, Arg::Register(_)),
+            _ => matches!(arg, Arg::Register(_) | Arg::Number(_)),
+        };
+        if !fits {
+            return Err(format!("{:?} can't take {:?} as argument {}", instr, arg, i + 1));
+        }
+        // Arithmetic on values outside the range could overflow.
+        if let Arg::Number(v) = arg
+            && !(register::MIN..=register::MAX).contains(v)
+        {
+            return Err(format!(
+                "{} is outside the range {} to {}",
+                v,
+                register::MIN,
+                register::MAX
+            ));
+        }
+    }
+    if args.len() > instr.arity() + 1 {
+        return Err(String::from("more than one condition prefix"));
+    }
+    Ok(())
+}
+
+impl fmt::Display for Machine {
+    /// One line of state: the program counter, the branch flag and every
+    /// register in the order the chip lists them.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pc={} flag={}", self.pc, self.flag)?;
+        for name in self.chip.registers() {
+            write!(f, " {}={}", name, self.registers[*name].value)?;
+        }
+        Ok(())
+    }
+}
 
 /// Given a line, execute it, alter registers if need be, update the program counter.
+///
+/// Panics if the line uses a register missing from `registers` or jumps to
+/// a label missing from `labels`; `Machine` checks for both when it's built.
 pub fn exec(
     line: &(Instruction, Vec<Arg>),
     registers: &mut HashMap<String, Register>,
     labels: &HashMap<Arg, usize>,
     pc: usize,
-    branch: &bool,
-) -> (usize, bool) {
-    let unchanged_branch: bool = *branch;
+    branch: &Flag,
+) -> (usize, Flag) {
+    let unchanged_branch: Flag = *branch;
     let (instr, args) = line;
 
-    if args.contains(&Arg::BranchTrue) && *branch == false {
-        return (pc + 1, unchanged_branch);
-    } else if args.contains(&Arg::BranchFalse) && *branch == true {
+    if !branch.runs(args) {
         return (pc + 1, unchanged_branch);
     }
 
     match instr {
-        Instruction::Nop | Instruction::Label => {
-            return (pc + 1, unchanged_branch);
-        }
+        Instruction::Nop | Instruction::Label | Instruction::Slp => (pc + 1, unchanged_branch),
         Instruction::Add => {
             // add R/I
-            let first_arg = args[0].clone();
-            let acc = registers.get("acc").unwrap();
-            let mut value = acc.value;
-            match first_arg {
-                Arg::Register(first) => {
-                    let reg_value = get_register_value(first, registers);
-                    value += reg_value.value;
-                    let _ = registers.insert("acc".to_owned(), Register { value: value });
-                }
-                Arg::Number(i) => {
-                    value += i;
-                    let _ = registers.insert("acc".to_owned(), Register { value: value });
-                }
-                _ => (),
-            };
-            return (pc + 1, unchanged_branch);
+            let value = register::clamp(
+                get_register_value("acc".to_owned(), registers).value
+                    + value_of(&args[0], registers),
+            );
+            let _ = registers.insert("acc".to_owned(), Register { value });
+            (pc + 1, unchanged_branch)
         }
         Instruction::Sub => {
             // sub R/I
-            let first_arg = args[0].clone();
-            let acc = registers.get("acc").unwrap();
-            let mut value = acc.value;
-            match first_arg {
-                Arg::Register(first) => {
-                    let reg_value = get_register_value(first, registers);
-                    //let reg_value = registers.get(&first).unwrap();
-                    value -= reg_value.value;
-                    let _ = registers.insert("acc".to_owned(), Register { value: value });
-                }
-                Arg::Number(i) => {
-                    value -= i;
-                    let _ = registers.insert("acc".to_owned(), Register { value: value });
-                }
-                _ => panic!("Incorrect argument given."),
-            };
-            return (pc + 1, unchanged_branch);
+            let value = register::clamp(
+                get_register_value("acc".to_owned(), registers).value
+                    - value_of(&args[0], registers),
+            );
+            let _ = registers.insert("acc".to_owned(), Register { value });
+            (pc + 1, unchanged_branch)
         }
         Instruction::Not => {
-            let acc = registers.get("acc").unwrap().value;
-            match acc {
-                0 => {
-                    let _ = registers.insert("acc".to_owned(), Register { value: 100 });
-                }
-                _ => {
-                    let _ = registers.insert("acc".to_owned(), Register { value: 0 });
-                }
-            }
-            return (pc + 1, unchanged_branch);
+            let value = match get_register_value("acc".to_owned(), registers).value {
+                0 => 100,
+                _ => 0,
+            };
+            let _ = registers.insert("acc".to_owned(), Register { value });
+            (pc + 1, unchanged_branch)
         }
         Instruction::Mul => {
             // mul R/I
-            let first_arg = args[0].clone();
-            let acc = registers.get("acc").unwrap();
-            let mut value = acc.value;
-            match first_arg {
-                Arg::Register(first) => {
-                    let reg_value = get_register_value(first, registers);
-                    value *= reg_value.value;
-                    let _ = registers.insert("acc".to_owned(), Register { value: value });
-                }
-                Arg::Number(i) => {
-                    value *= i;
-                    let _ = registers.insert("acc".to_owned(), Register { value: value });
-                }
-                _ => (),
-            };
-            return (pc + 1, unchanged_branch);
+            let value = register::clamp(
+                get_register_value("acc".to_owned(), registers).value
+                    * value_of(&args[0], registers),
+            );
+            let _ = registers.insert("acc".to_owned(), Register { value });
+            (pc + 1, unchanged_branch)
         }
         Instruction::Mov => {
             // mov R/I R
-            let first_arg = args[0].clone();
-            let second_arg = args[1].clone();
-            match (first_arg, second_arg) {
-                (Arg::Register(first), Arg::Register(second)) => {
-                    let first_reg = get_register_value(first, registers);
-                    let _ = registers.insert(
-                        second,
-                        Register {
-                            value: first_reg.value,
-                        },
-                    );
-                }
-                (Arg::Number(i), Arg::Register(second)) => {
-                    // check that the register exists.
-                    let _ = get_register_value(second.to_owned(), registers);
-                    let _ = registers.insert(
-                        second.clone(),
-                        Register {
-                            value: i.to_owned(),
-                        },
-                    );
-                }
-                _ => (),
-            };
-            return (pc + 1, unchanged_branch);
+            if let Arg::Register(second) = &args[1] {
+                let value = value_of(&args[0], registers);
+                // check that the register exists.
+                let _ = get_register_value(second.to_owned(), registers);
+                let _ = registers.insert(second.to_owned(), Register { value });
+            }
+            (pc + 1, unchanged_branch)
         }
         Instruction::Teq => {
             // teq R/I R/I
-            let first_arg = args[0].clone();
-            let second_arg = args[1].clone();
-            let new_branch_val: bool;
-            match (first_arg, second_arg) {
-                (Arg::Register(first), Arg::Register(second)) => {
-                    // Comparison between registers.
-                    let first_reg = get_register_value(first, registers);
-                    let secnd_reg = get_register_value(second, registers);
-                    new_branch_val = match first_reg.value == secnd_reg.value {
-                        true => true,
-                        _ => false,
-                    };
-                    return (pc + 1, new_branch_val);
-                }
-                (Arg::Register(first), Arg::Number(i)) => {
-                    // Comparison between a register and a number.
-                    let first_reg = get_register_value(first, registers);
-                    new_branch_val = match first_reg.value == i {
-                        true => true,
-                        _ => false,
-                    };
-                    return (pc + 1, new_branch_val);
-                }
-                (Arg::Number(i), Arg::Register(second)) => {
-                    // Comparison a number and a register.
-                    let secnd_reg = get_register_value(second, registers);
-                    new_branch_val = match secnd_reg.value == i {
-                        true => true,
-                        _ => false,
-                    };
-                    return (pc + 1, new_branch_val);
-                }
-                (Arg::Number(i), Arg::Number(j)) => {
-                    // Comparison between a number and a number.
-                    new_branch_val = match j == i {
-                        true => true,
-                        _ => false,
-                    };
-                    return (pc + 1, new_branch_val);
-                }
-                _ => panic!("Failed to parse arguments to teq."),
-            }
+            let new_branch_val = value_of(&args[0], registers) == value_of(&args[1], registers);
+            (pc + 1, Flag::from(new_branch_val))
         }
         Instruction::Tgt => {
             // tgt R/I R/I
             // Test to see if the value of the first operand is greater than the value
             // of the second operand.
-            let first_arg = args[0].clone();
-            let second_arg = args[1].clone();
-            let new_branch_val: bool;
-            match (first_arg, second_arg) {
-                (Arg::Register(first), Arg::Register(second)) => {
-                    let first_reg = get_register_value(first, registers);
-                    let secnd_reg = get_register_value(second, registers);
-                    new_branch_val = match first_reg.value > secnd_reg.value {
-                        true => true,
-                        _ => false,
-                    };
-                    return (pc + 1, new_branch_val);
-                }
-                (Arg::Number(i), Arg::Number(j)) => {
-                    new_branch_val = match i > j {
-                        true => true,
-                        _ => false,
-                    };
-                    return (pc + 1, new_branch_val);
-                }
-                (Arg::Register(first), Arg::Number(i)) => {
-                    let first_reg = get_register_value(first, registers);
-                    new_branch_val = match first_reg.value > i {
-                        true => true,
-                        _ => false,
-                    };
-                    return (pc + 1, new_branch_val);
-                }
-                (Arg::Number(i), Arg::Register(second)) => {
-                    let secnd_reg = get_register_value(second, registers);
-                    new_branch_val = match i > secnd_reg.value {
-                        true => true,
-                        _ => false,
-                    };
-                    return (pc + 1, new_branch_val);
-                }
-                _ => panic!("Failed to parse the arguments to tgt."),
-            }
+            let new_branch_val = value_of(&args[0], registers) > value_of(&args[1], registers);
+            (pc + 1, Flag::from(new_branch_val))
         }
         Instruction::Tlt => {
             // tlt R/I R/I
-            let first_arg = args[0].clone();
-            let second_arg = args[1].clone();
-            let new_branch_val: bool;
-            match (first_arg, second_arg) {
-                (Arg::Register(first), Arg::Register(second)) => {
-                    let first_reg = get_register_value(first, registers);
-                    let secnd_reg = get_register_value(second, registers);
-                    new_branch_val = match first_reg.value < secnd_reg.value {
-                        true => true,
-                        _ => false,
-                    };
-                    return (pc + 1, new_branch_val);
-                }
-                (Arg::Number(i), Arg::Number(j)) => {
-                    new_branch_val = match i < j {
-                        true => true,
-                        _ => false,
-                    };
-                    return (pc + 1, new_branch_val);
-                }
-                (Arg::Register(first), Arg::Number(i)) => {
-                    let first_reg = get_register_value(first, registers);
-                    new_branch_val = match first_reg.value < i {
-                        true => true,
-                        _ => false,
-                    };
-                    return (pc + 1, new_branch_val);
-                }
-                (Arg::Number(i), Arg::Register(second)) => {
-                    let secnd_reg = get_register_value(second, registers);
-                    new_branch_val = match i < secnd_reg.value {
-                        true => true,
-                        _ => false,
-                    };
-                    return (pc + 1, new_branch_val);
-                }
-                u => panic!("Unknown arguments given to tlt. {:?}", u),
+            let new_branch_val = value_of(&args[0], registers) < value_of(&args[1], registers);
+            (pc + 1, Flag::from(new_branch_val))
+        }
+        Instruction::Tcp => {
+            // tcp R/I R/I
+            // Equal values leave neither + nor - lines enabled.
+            let new_branch_val =
+                match value_of(&args[0], registers).cmp(&value_of(&args[1], registers)) {
+                    Ordering::Greater => Flag::Plus,
+                    Ordering::Less => Flag::Minus,
+                    Ordering::Equal => Flag::Neither,
+                };
+            (pc + 1, new_branch_val)
+        }
+        Instruction::Dgt => {
+            // dgt R/I
+            let acc = get_register_value("acc".to_owned(), registers).value;
+            let value = match value_of(&args[0], registers) {
+                i @ 0..=2 => acc.signum() * (acc.abs() / 10_i64.pow(i as u32) % 10),
+                _ => 0,
+            };
+            let _ = registers.insert("acc".to_owned(), Register { value });
+            (pc + 1, unchanged_branch)
+        }
+        Instruction::Dst => {
+            // dst R/I R/I
+            let acc = get_register_value("acc".to_owned(), registers).value;
+            let digit = value_of(&args[1], registers);
+            if let i @ 0..=2 = value_of(&args[0], registers) {
+                let place = 10_i64.pow(i as u32);
+                let magnitude =
+                    acc.abs() - acc.abs() / place % 10 * place + digit.abs() % 10 * place;
+                let sign = if digit < 0 || acc < 0 { -1 } else { 1 };
+                let _ = registers.insert(
+                    "acc".to_owned(),
+                    Register {
+                        value: sign * magnitude,
+                    },
+                );
             }
+            (pc + 1, unchanged_branch)
         }
         Instruction::Jmp => {
             // jmp L
-            let location = args[0].clone();
-            let _ = match &location {
-                Arg::Label(_) => {
-                    let position = labels.get(&location).unwrap();
-                    return (*position, unchanged_branch);
-                }
+            match &args[0] {
+                Arg::Label(name) => match labels.get(&args[0]) {
+                    Some(position) => (*position, unchanged_branch),
+                    None => panic!("no label called {:?}", name),
+                },
                 _ => panic!("Argument provided to jmp was not a label."),
-            };
+            }
         }
     }
 }
 
+/// The register a line writes to when it runs.
+pub fn written_register(line: &(Instruction, Vec<Arg>)) -> Option<&str> {
+    match line {
+        (
+            Instruction::Add
+            | Instruction::Sub
+            | Instruction::Mul
+            | Instruction::Not
+            | Instruction::Dgt
+            | Instruction::Dst,
+            _,
+        ) => Some("acc"),
+        (Instruction::Mov, args) => match args.get(1) {
+            Some(Arg::Register(name)) => Some(name),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The registers a line reads from when it runs.
+pub fn read_registers(line: &(Instruction, Vec<Arg>)) -> Vec<&str> {
+    let (instr, args) = line;
+    let mut reads: Vec<&str> = args
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| !(*instr == Instruction::Mov && *i == 1))
+        .filter_map(|(_, arg)| match arg {
+            Arg::Register(name) => Some(name.as_str()),
+            _ => None,
+        })
+        .collect();
+    if matches!(
+        instr,
+        Instruction::Add
+            | Instruction::Sub
+            | Instruction::Mul
+            | Instruction::Not
+            | Instruction::Dgt
+            | Instruction::Dst
+    ) {
+        reads.insert(0, "acc");
+    }
+    reads
+}
+
+/// The value of an R/I operand.
+fn value_of(arg: &Arg, registers: &HashMap<String, Register>) -> i64 {
+    match arg {
+        Arg::Register(name) => get_register_value(name.to_owned(), registers).value,
+        Arg::Number(i) => *i,
+        u => panic!("Incorrect argument given. {:?}", u),
+    }
+}
+
 fn get_register_value(arg: String, registers: &HashMap<String, Register>) -> &Register {
     match registers.get(&arg) {
         Some(v) => v,
-        None => {
-            println!("Failed to find a register called {:?}", arg);
-            panic!();
+        None => panic!("no register called {:?}", arg),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Flag, Io, Machine, Step};
+    use crate::chip::ChipModel;
+
+    const FIBONACCI: &str = include_str!("../program.asm");
+
+    #[test]
+    fn runs_program_to_completion() {
+        let mut machine = Machine::load(FIBONACCI).unwrap();
+        let steps = machine.run();
+        assert!(machine.is_halted());
+        assert_eq!(machine.steps(), steps);
+        assert_eq!(machine.register("acc"), Some(9));
+        assert_eq!(machine.register("x2"), Some(55));
+    }
+
+    #[test]
+    fn label_sharing_a_line_runs_its_instruction() {
+        let mut machine = Machine::load("mov 3 x1\nl: add 1\nteq acc x1\n- jmp l").unwrap();
+        machine.run();
+        assert_eq!(machine.register("acc"), Some(3));
+    }
+
+    #[test]
+    fn rejects_hand_built_programs_that_would_panic() {
+        use crate::instruction::{Arg, Instruction};
+        let register = vec![(Instruction::Add, vec![Arg::Register(String::from("zz"))])];
+        assert!(Machine::new(register).is_err());
+        let label = vec![(Instruction::Jmp, vec![Arg::Label(String::from("nowhere"))])];
+        assert!(Machine::new(label).is_err());
+        let number = vec![(Instruction::Jmp, vec![Arg::Number(0)])];
+        assert!(Machine::new(number).is_err());
+        assert!(Machine::new(vec![(Instruction::Label, vec![])]).is_err());
+        assert!(Machine::new(vec![(Instruction::Mov, vec![Arg::Number(1)])]).is_err());
+        let into_number = vec![(Instruction::Mov, vec![Arg::Number(1), Arg::Number(5)])];
+        assert!(Machine::new(into_number).is_err());
+        let too_big = vec![(Instruction::Add, vec![Arg::Number(i64::MAX)])];
+        assert!(Machine::new(too_big).is_err());
+        let too_small = vec![(
+            Instruction::Mov,
+            vec![Arg::Number(-1000), Arg::Register(String::from("acc"))],
+        )];
+        assert!(Machine::new(too_small).is_err());
+        let condition_first = vec![(Instruction::Add, vec![Arg::BranchTrue, Arg::Number(1)])];
+        assert!(Machine::new(condition_first).is_err());
+        let two_conditions = vec![(
+            Instruction::Nop,
+            vec![Arg::BranchTrue, Arg::BranchFalse],
+        )];
+        assert!(Machine::new(two_conditions).is_err());
+        assert!(Machine::load("mov 1 5").is_err());
+    }
+
+    #[test]
+    fn step_reports_halt() {
+        let mut machine = Machine::load("mov 3 acc").unwrap();
+        assert!(machine.step());
+        assert!(!machine.step());
+        assert_eq!(machine.pc(), 1);
+        assert_eq!(machine.register("acc"), Some(3));
+    }
+
+    #[test]
+    fn run_for_stops_at_timestep() {
+        let program = "loop:\n  add 1\n  slp 2\n  jmp loop";
+        let mut machine = Machine::load(program).unwrap();
+        machine.run_for(5).unwrap();
+        assert_eq!(machine.timestep(), 5);
+        // Woke up at 0, 2 and 4.
+        assert_eq!(machine.register("acc"), Some(3));
+        assert!(machine.is_asleep());
+    }
+
+    #[test]
+    fn run_for_stops_without_sleep() {
+        let mut machine = Machine::load("loop:\n  jmp loop").unwrap();
+        assert!(machine.run_for(5).is_err());
+        assert_eq!(machine.timestep(), 0);
+
+        let mut machine = Machine::load("add 1").unwrap();
+        machine.set_wrap(true);
+        assert!(machine.run_for(5).is_err());
+    }
+
+    #[test]
+    fn arithmetic_saturates() {
+        let mut machine =
+            Machine::load("mov 900 acc\nadd 500\nmov acc x1\nsub 999\nsub 999").unwrap();
+        machine.run();
+        assert_eq!(machine.register("x1"), Some(999));
+        assert_eq!(machine.register("acc"), Some(-999));
+        assert!(machine.set_register("acc", 1000).is_err());
+    }
+
+    #[test]
+    fn inputs_follow_the_clock() {
+        let program = "add p0\nslp 1\nadd p0\nslp 1\nadd p0";
+        let mut machine = Machine::load(program).unwrap();
+        machine.set_input("p0", vec![5, 50]).unwrap();
+        assert_eq!(machine.register("p0"), Some(5));
+        machine.run();
+        assert_eq!(machine.register("acc"), Some(105));
+    }
+
+    #[test]
+    fn restore_rewinds_state() {
+        let mut machine = Machine::load(FIBONACCI).unwrap();
+        for _ in 0..20 {
+            machine.step();
+        }
+        let snapshot = machine.snapshot();
+        let display = machine.to_string();
+        let executions = machine.executions().to_vec();
+        let skips = machine.skips().to_vec();
+        machine.run();
+        machine.restore(&snapshot);
+        assert_eq!(machine.to_string(), display);
+        assert_eq!(machine.steps(), 20);
+        assert_eq!(machine.executions(), executions);
+        assert_eq!(machine.skips(), skips);
+        machine.run();
+        assert_eq!(machine.register("x2"), Some(55));
+    }
+
+    #[test]
+    fn wraps_like_the_game() {
+        let mut machine = Machine::load("add 1\nslp 1").unwrap();
+        machine.set_wrap(true);
+        machine.run_for(3).unwrap();
+        assert!(!machine.is_halted());
+        assert_eq!(machine.register("acc"), Some(3));
+    }
+
+    #[test]
+    fn blocks_on_io() {
+        // Values waiting to be read, taken from the back.
+        struct Port(Vec<i64>, Vec<i64>);
+        impl Io for Port {
+            fn connected(&self, name: &str) -> bool {
+                name == "x0"
+            }
+            fn ready(&self, names: &[&str]) -> bool {
+                names.len() <= self.0.len()
+            }
+            fn read(&mut self, _: &str) -> Option<i64> {
+                self.0.pop()
+            }
+            fn write(&mut self, _: &str, value: i64) {
+                self.1.push(value);
+            }
+            fn delivered(&self, _: &str) -> bool {
+                true
+            }
         }
+
+        let mut machine = Machine::load("add x0\nmov acc x0").unwrap();
+        let mut port = Port(Vec::new(), Vec::new());
+        assert_eq!(machine.step_with(&mut port), Step::Blocked);
+        assert_eq!(machine.steps(), 0);
+        port.0 = vec![4];
+        assert_eq!(machine.step_with(&mut port), Step::Ran);
+        assert_eq!(machine.step_with(&mut port), Step::Ran);
+        assert_eq!(machine.step_with(&mut port), Step::Halted);
+        assert_eq!(port.1, vec![4]);
+    }
+
+    #[test]
+    fn load_rejects_bad_programs() {
+        assert!(Machine::load("frob acc").is_err());
+        assert!(Machine::load("mov 1 zz").is_err());
+        assert!(Machine::load("jmp nowhere").is_err());
+        assert!(Machine::load("add").is_err());
+        assert!(Machine::load("add 1000").is_err());
+        assert!(Machine::load_chip("mov 1 dat", ChipModel::Mc4000).is_err());
+    }
+
+    #[test]
+    fn digits_and_three_way_compare() {
+        let program =
+            "mov -123 acc\ndgt 1\nmov acc dat\nmov 123 acc\ndst 0 -7\ntcp dat -2\n+ add 1\n- add 1";
+        let mut machine = Machine::load(program).unwrap();
+        machine.run();
+        assert_eq!(machine.register("dat"), Some(-2));
+        assert_eq!(machine.register("acc"), Some(-127));
+        assert_eq!(machine.flag(), Flag::Neither);
+    }
+
+    #[test]
+    fn power_skips_labels_and_untaken_lines() {
+        let program = "start:\n  teq 1 2\n  + add 1\n  - add 2";
+        let mut machine = Machine::load(program).unwrap();
+        machine.run();
+        assert_eq!(machine.steps(), 4);
+        assert_eq!(machine.power(), 2);
+        assert_eq!(machine.lines_of_code(), 3);
     }
-}
\ No newline at end of file
+}
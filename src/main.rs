@@ -1,72 +1,777 @@
-mod instruction;
-mod machine;
-mod parse;
-mod register;
+mod cli;
 
-use std::collections::HashMap;
-use std::fs;
 use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::ExitCode;
+
+use cli::{Command, Options, Verbosity};
+use rust_shenzhenio::cfg::Cfg;
+use rust_shenzhenio::chip::ChipModel;
+use rust_shenzhenio::compile;
+use rust_shenzhenio::config::Config;
+use rust_shenzhenio::coverage::Coverage;
+use rust_shenzhenio::debugger::Debugger;
+use rust_shenzhenio::decompile;
+use rust_shenzhenio::golden::{self, Expectations};
+use rust_shenzhenio::lsp;
+use rust_shenzhenio::puzzle::Puzzle;
+use rust_shenzhenio::repl::Repl;
+use rust_shenzhenio::save::Solution;
+use rust_shenzhenio::superopt::Spec;
+use rust_shenzhenio::trace::JsonTrace;
+use rust_shenzhenio::svg;
+use rust_shenzhenio::vcd::{self, Vcd};
+use rust_shenzhenio::{Limits, Machine, Outcome, format, lint, optimize, parse, profile};
 
-use crate::instruction::Instruction;
-use instruction::Arg;
-use register::Register;
+/// The program failed to parse or validate.
+const EXIT_INVALID: u8 = 1;
+/// The command line was wrong or the file could not be read.
+const EXIT_USAGE: u8 = 2;
+/// The program was stopped by `--max-steps` or `--timesteps` before it halted.
+const EXIT_LIMIT: u8 = 3;
+/// The program was stopped by `--timeout` before it halted, or a `superopt`
+/// search before it finished.
+const EXIT_TIMEOUT: u8 = 4;
+/// A check failed: a puzzle solution failed a test case, a program didn't
+/// meet its expectations, `fmt --check` found a file that isn't formatted,
+/// `lint` found a problem or `superopt` found no program.
+const EXIT_FAILED: u8 = 5;
 
-fn main() {
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match cli::parse_args(&args) {
+        Ok(Some(v)) => v,
+        Ok(None) => {
+            println!("{}", cli::USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, cli::USAGE);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
 
-    // ./exe <registers> "path-to-file"
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 3 {
-        panic!("Useage: ./exe <registers> \"path_to_file\"");
+    if options.command == Command::Lsp {
+        let mut server = lsp::Server::new(options.chip.unwrap_or_default());
+        return match server.run(io::stdin().lock(), io::stdout().lock()) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                ExitCode::FAILURE
+            }
+        };
     }
 
-    let mut file = String::new();
-    if let Some(_) = args.iter().nth(2) {
-        file = fs::read_to_string("program.asm").unwrap();
+    if options.command == Command::Repl {
+        let mut repl = Repl::new(options.chip.unwrap_or_default());
+        return match repl.run(io::stdin().lock(), io::stdout()) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                ExitCode::FAILURE
+            }
+        };
     }
 
-    let mut registers: HashMap<String, Register> = HashMap::from([
-        ("acc".to_owned(), Register { value: 0 }),
-        ("x1".to_owned(), Register { value: 0 }),
-        ("x2".to_owned(), Register { value: 0 }),
-        ("x3".to_owned(), Register { value: 0 }),
-    ]);
+    if options.command == Command::Test {
+        return test(&options);
+    }
 
-    //let file = fs::read_to_string("program.asm").unwrap();
+    let file = match fs::read_to_string(&options.path) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("error: could not read {}: {}", options.path, e);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
 
-    let mut distilled_program: Vec<(Instruction, Vec<Arg>)> = Vec::new();
-    let mut labels: HashMap<Arg, usize> = HashMap::new();
-    for line in file.lines() {
-        let first_pass = parse::split_line(&line);
-        let parsed = parse::abstracted(first_pass);
-        match parsed {
-            Some(v) => {
-                // let (ref instr, ref args) = v;
-                distilled_program.push(v);
+    match options.command {
+        Command::Fmt => return fmt(&file, &options),
+        Command::Verify => return verify(&file, &options),
+        Command::Import => return import(&file, &options),
+        Command::Export => return export(&file, &options),
+        Command::Superopt => return superopt(&file, &options),
+        Command::Compile => return compile(&file, &options),
+        _ => (),
+    }
+
+    let config = match &options.config {
+        Some(path) => match fs::read_to_string(path).map_err(|e| e.to_string()) {
+            Ok(v) if path.ends_with(".json") => match Config::parse_json(&v) {
+                Ok(v) => v,
+                Err(e) => {
+                    eprintln!("error: {}: {}", path, e);
+                    return ExitCode::from(EXIT_USAGE);
+                }
+            },
+            Ok(v) => match Config::parse(&v) {
+                Ok(v) => v,
+                Err(e) => {
+                    eprintln!("error: {}: {}", path, e);
+                    return ExitCode::from(EXIT_USAGE);
+                }
+            },
+            Err(e) => {
+                eprintln!("error: could not read {}: {}", path, e);
+                return ExitCode::from(EXIT_USAGE);
+            }
+        },
+        None => Config::default(),
+    };
+
+    let chip = options.chip.or(config.chip).unwrap_or_default();
+    if options.command == Command::Lint {
+        return lint(&file, chip, &options);
+    }
+    let mut machine = match Machine::load_chip(&file, chip) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("error: {}: {}", options.path, e);
+            return ExitCode::from(EXIT_INVALID);
+        }
+    };
+    if let Err(e) = config.apply(&mut machine) {
+        eprintln!("error: {}", e);
+        return ExitCode::from(EXIT_USAGE);
+    }
+    for (name, value) in &options.registers {
+        if let Err(e) = machine.set_register(name, *value) {
+            eprintln!("error: {}", e);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    }
+
+    // Limits given on the command line win over the config file.
+    let limits = Limits {
+        max_steps: options.limits.max_steps.or(config.limits.max_steps),
+        max_timesteps: options.limits.max_timesteps.or(config.limits.max_timesteps),
+        timeout: options.limits.timeout.or(config.limits.timeout),
+    };
+
+    match options.command {
+        Command::Check => check(&machine, &options),
+        Command::Optimize => optimize(&mut machine, &file, &options, limits),
+        Command::Cfg => {
+            let name = Path::new(&options.path)
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_else(|| String::from("program"));
+            print!("{}", Cfg::build(machine.program()).dot(machine.program(), &name));
+            ExitCode::SUCCESS
+        }
+        Command::Decompile => {
+            print!("{}", decompile::decompile(&machine, &file));
+            ExitCode::SUCCESS
+        }
+        Command::Debug => {
+            let mut debugger = Debugger::new(machine, &file);
+            debugger.set_limits(limits);
+            match debugger.run(io::stdin().lock(), io::stdout()) {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        _ => execute(&mut machine, &file, &options, &config, limits),
+    }
+}
+
+/// Check every program under the path against its expectation comments.
+fn test(options: &Options) -> ExitCode {
+    let path = Path::new(&options.path);
+    let mut paths = Vec::new();
+    if path.is_dir() {
+        let entries = match fs::read_dir(path) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("error: could not read {}: {}", options.path, e);
+                return ExitCode::from(EXIT_USAGE);
+            }
+        };
+        for entry in entries.flatten() {
+            if entry.path().extension().is_some_and(|e| e == "asm") {
+                paths.push(entry.path());
+            }
+        }
+        paths.sort();
+    } else {
+        paths.push(path.to_path_buf());
+    }
+
+    let chip = options.chip.unwrap_or_default();
+    let mut limits = options.limits.clone();
+    if limits.max_steps.is_none() && limits.timeout.is_none() {
+        limits.max_steps = Some(golden::MAX_STEPS);
+    }
+    let (mut passed, mut failed) = (0, 0);
+    for path in &paths {
+        let source = match fs::read_to_string(path) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("error: could not read {}: {}", path.display(), e);
+                return ExitCode::from(EXIT_USAGE);
+            }
+        };
+        let mismatches = match Expectations::parse(&source) {
+            Ok(expectations) if expectations.is_empty() => {
+                if options.verbosity > Verbosity::Normal {
+                    println!("{}: skipped, no expectations", path.display());
+                }
+                continue;
+            }
+            Ok(expectations) => expectations.check(&source, chip, &limits),
+            Err(e) => vec![e],
+        };
+        if mismatches.is_empty() {
+            passed += 1;
+            if options.verbosity > Verbosity::Quiet {
+                println!("{}: pass", path.display());
+            }
+        } else {
+            failed += 1;
+            for mismatch in mismatches {
+                println!("{}: FAIL {}", path.display(), mismatch);
+            }
+        }
+    }
+    if options.verbosity > Verbosity::Quiet {
+        println!("{} of {} programs passed", passed, passed + failed);
+    }
+    if failed > 0 {
+        ExitCode::from(EXIT_FAILED)
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+/// Print the program in canonical style, or with `--check` report whether
+/// it already is.
+fn fmt(source: &str, options: &Options) -> ExitCode {
+    let formatted = match format::format(source) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("error: {}: {}", options.path, e);
+            return ExitCode::from(EXIT_INVALID);
+        }
+    };
+    if !options.check {
+        print!("{}", formatted);
+        return ExitCode::SUCCESS;
+    }
+    if formatted == source {
+        return ExitCode::SUCCESS;
+    }
+    if options.verbosity > Verbosity::Quiet {
+        let first = source
+            .lines()
+            .zip(formatted.lines())
+            .position(|(a, b)| a != b)
+            .unwrap_or(source.lines().count().min(formatted.lines().count()));
+        eprintln!("{}: not formatted, first at line {}", options.path, first + 1);
+    }
+    ExitCode::from(EXIT_FAILED)
+}
+
+/// Report whether the program loaded, and warn if it would not fit on the chip.
+fn check(machine: &Machine, options: &Options) -> ExitCode {
+    let lines = machine.lines_of_code();
+    let chip = machine.chip();
+    if options.verbosity > Verbosity::Quiet {
+        if lines > chip.max_lines() {
+            eprintln!(
+                "warning: {} lines of code, but the {} only holds {}",
+                lines,
+                chip.name(),
+                chip.max_lines()
+            );
+        }
+        println!("{}: ok ({} lines of code)", options.path, lines);
+    }
+    ExitCode::SUCCESS
+}
+
+/// Print the optimized program, and on stderr what changed.
+fn optimize(machine: &mut Machine, source: &str, options: &Options, mut limits: Limits) -> ExitCode {
+    // The machine loaded, so the source parses.
+    let mut program = parse::program(source).unwrap_or_default();
+    let rewrites = optimize::optimize(&mut program);
+    let program: Vec<_> = program.into_iter().map(|(_, line)| line).collect();
+    print!("{}", format::print(&program));
+    if options.verbosity == Verbosity::Quiet {
+        return ExitCode::SUCCESS;
+    }
+    for rewrite in &rewrites {
+        eprintln!("{}", rewrite);
+    }
+    let mut optimized = match Machine::with_chip(program, machine.chip()) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("error: {}: optimized program doesn't load: {}", options.path, e);
+            return ExitCode::from(EXIT_INVALID);
+        }
+    };
+    for name in machine.chip().registers() {
+        let _ = optimized.set_register(name, machine.register(name).unwrap_or(0));
+    }
+    eprintln!(
+        "lines of code: {} -> {}",
+        machine.lines_of_code(),
+        optimized.lines_of_code()
+    );
+
+    // Compare power over a whole run when the program finishes by itself.
+    if limits.max_steps.is_none() && limits.timeout.is_none() {
+        limits.max_steps = Some(golden::MAX_STEPS);
+    }
+    if machine.run_with(&limits, |_| ()) == Outcome::Halted
+        && optimized.run_with(&limits, |_| ()) == Outcome::Halted
+    {
+        eprintln!("power for one run: {} -> {}", machine.power(), optimized.power());
+    }
+    ExitCode::SUCCESS
+}
+
+/// Print the assembly for a program in the structured language.
+fn compile(source: &str, options: &Options) -> ExitCode {
+    let chip = options.chip.unwrap_or_default();
+    let program = match compile::compile(source, chip) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("error: {}: {}", options.path, e);
+            return ExitCode::from(EXIT_INVALID);
+        }
+    };
+    print!("{}", format::print(&program));
+    let lines = match Machine::with_chip(program, chip) {
+        Ok(v) => v.lines_of_code(),
+        Err(e) => {
+            eprintln!("error: {}: {}", options.path, e);
+            return ExitCode::from(EXIT_INVALID);
+        }
+    };
+    if lines > chip.max_lines() && options.verbosity > Verbosity::Quiet {
+        eprintln!(
+            "warning: {} lines of code, more than the {} an {} holds",
+            lines,
+            chip.max_lines(),
+            chip.name()
+        );
+    }
+    ExitCode::SUCCESS
+}
+
+/// Print the best program for a spec, and on stderr how it scores and how
+/// much was searched.
+fn superopt(source: &str, options: &Options) -> ExitCode {
+    let mut spec = match Spec::parse(source) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("error: {}: {}", options.path, e);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+    if let Some(chip) = options.chip {
+        spec.chip = chip;
+        if let Err(e) = spec.check() {
+            eprintln!("error: {}: {}", options.path, e);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    }
+
+    let search = spec.search(options.limits.timeout);
+    if let Some(best) = &search.best {
+        print!("{}", format::print(&best.program));
+    }
+    if options.verbosity > Verbosity::Quiet {
+        if let Some(best) = &search.best {
+            eprintln!(
+                "lines of code: {}, power over {} case(s): {}",
+                best.program.len(),
+                spec.cases.len(),
+                best.power
+            );
+        }
+        eprintln!("tried {} programs", search.tried);
+    }
+    match (&search.best, search.complete) {
+        (_, false) => {
+            eprintln!("error: stopped by --timeout before the search finished");
+            ExitCode::from(EXIT_TIMEOUT)
+        }
+        (None, true) => {
+            eprintln!(
+                "error: no program of up to {} lines passes every case",
+                spec.max_lines
+            );
+            ExitCode::from(EXIT_FAILED)
+        }
+        (Some(_), true) => ExitCode::SUCCESS,
+    }
+}
+
+/// Print a warning for each likely mistake in the program.
+fn lint(source: &str, chip: ChipModel, options: &Options) -> ExitCode {
+    let warnings = match lint::lint_source(source, chip) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("error: {}: {}", options.path, e);
+            return ExitCode::from(EXIT_INVALID);
+        }
+    };
+    if options.verbosity > Verbosity::Quiet {
+        for warning in &warnings {
+            eprintln!("{}:{}: warning: {}", options.path, warning.line, warning.message);
+        }
+    }
+    if warnings.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(EXIT_FAILED)
+    }
+}
+
+/// Run a puzzle's test cases against its chips' programs.
+fn verify(source: &str, options: &Options) -> ExitCode {
+    let puzzle = match Puzzle::parse(source) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("error: {}: {}", options.path, e);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+    for (chip, _) in &options.programs {
+        if !puzzle.chips.iter().any(|c| c.name == *chip) {
+            eprintln!("error: {} has no chip called {:?}", options.path, chip);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    }
+
+    // Programs named in the puzzle are relative to the puzzle file.
+    let dir = Path::new(&options.path).parent().unwrap_or(Path::new(""));
+    let mut programs = Vec::new();
+    let mut paths = Vec::new();
+    for chip in &puzzle.chips {
+        let path = match options.programs.iter().rev().find(|(c, _)| *c == chip.name) {
+            Some((_, path)) => Path::new(path).to_path_buf(),
+            None => match &chip.program {
+                Some(path) => dir.join(path),
+                None => {
+                    eprintln!("error: no program given for {}", chip.name);
+                    return ExitCode::from(EXIT_USAGE);
+                }
+            },
+        };
+        match fs::read_to_string(&path) {
+            Ok(v) => programs.push(v),
+            Err(e) => {
+                eprintln!("error: could not read {}: {}", path.display(), e);
+                return ExitCode::from(EXIT_USAGE);
+            }
+        }
+        paths.push(path.display().to_string());
+    }
+
+    // Coverage is summed over every test case, per chip.
+    let mut coverage: Vec<Coverage> = Vec::new();
+    let results = puzzle.verify_with(&programs, |board| {
+        if coverage.is_empty() {
+            coverage = board.chips.iter().map(|c| Coverage::new(&c.machine)).collect();
+        }
+        for (chip, coverage) in board.chips.iter().zip(coverage.iter_mut()) {
+            coverage.add(&chip.machine);
+        }
+    });
+    let results = match results {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return ExitCode::from(EXIT_INVALID);
+        }
+    };
+    let mut failed = 0;
+    for result in &results {
+        match &result.failure {
+            Some(failure) => {
+                failed += 1;
+                println!("test {}: FAIL {}", result.name, failure);
+            }
+            None if options.verbosity > Verbosity::Quiet => {
+                println!("test {}: pass", result.name)
             }
             None => (),
         }
     }
+    if options.verbosity > Verbosity::Quiet {
+        println!("{} of {} tests passed", results.len() - failed, results.len());
+    }
+    let reports: Vec<(&str, &str, &Coverage)> = paths
+        .iter()
+        .zip(&programs)
+        .zip(&coverage)
+        .map(|((path, source), coverage)| (path.as_str(), source.as_str(), coverage))
+        .collect();
+    if let Err(code) = report_coverage(&reports, options) {
+        return code;
+    }
+    if options.profile {
+        for (spec, (path, source, coverage)) in puzzle.chips.iter().zip(&reports) {
+            // The puzzle already loaded every program, so this can't fail.
+            if let Ok(machine) = Machine::load_chip(source, spec.model) {
+                println!("\n{}:", path);
+                print!("{}", profile::report(&machine, coverage, source));
+            }
+        }
+    }
+    if failed > 0 {
+        ExitCode::from(EXIT_FAILED)
+    } else {
+        ExitCode::SUCCESS
+    }
+}
 
-    // we want the labels to be based off of the distilled program,
-    // instead of being based off of the initial parsing.
-    for (i, (instr, args)) in distilled_program.iter().enumerate() {
-        if let Instruction::Label = instr {
-            labels.insert(args[0].to_owned(), i);
+/// Print and write the coverage `--coverage` and `--lcov` ask for, given
+/// each program's path, source and counts.
+fn report_coverage(reports: &[(&str, &str, &Coverage)], options: &Options) -> Result<(), ExitCode> {
+    if options.coverage {
+        for (path, source, coverage) in reports {
+            println!("\n{}:", path);
+            print!("{}", coverage.report(source));
         }
     }
+    if let Some(lcov) = &options.lcov {
+        let records: String = reports
+            .iter()
+            .map(|(path, _, coverage)| coverage.lcov(path))
+            .collect();
+        if let Err(e) = fs::write(lcov, records) {
+            eprintln!("error: could not write {}: {}", lcov, e);
+            return Err(ExitCode::FAILURE);
+        }
+    }
+    Ok(())
+}
 
-    let mut count = 0;
-    let mut program_counter = 0;
-    let mut branch: bool = false;
-    while program_counter < distilled_program.len() {
-        let line = &distilled_program[program_counter];
-        let (pc, curr_branch) =
-            machine::exec(line, &mut registers, &labels, program_counter, &branch);
-        program_counter = pc;
-        branch = curr_branch;
-        println!("{:?}", &registers);
-        count += 1;
+/// Load a solution saved by the game and simulate its board.
+fn import(source: &str, options: &Options) -> ExitCode {
+    let solution = match Solution::parse(source) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("error: {}: {}", options.path, e);
+            return ExitCode::from(EXIT_INVALID);
+        }
+    };
+    let mut board = match solution.board() {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("error: {}: {}", options.path, e);
+            return ExitCode::from(EXIT_INVALID);
+        }
+    };
+    for component in solution.unsupported() {
+        eprintln!(
+            "warning: {} at ({}, {}) can't be simulated and is left off the board",
+            component.kind, component.x, component.y
+        );
+    }
+    if options.verbosity > Verbosity::Quiet {
+        println!("{} ({})", solution.name, solution.puzzle);
+        for chip in &board.chips {
+            println!(
+                "{}: {}, {} lines of code",
+                chip.name,
+                chip.machine.chip().name(),
+                chip.machine.lines_of_code()
+            );
+        }
     }
-    println!("Final register state: {:?}", registers);
-    println!("Finished execution in {} steps.", &count);
+
+    let timesteps = match options.limits.max_timesteps {
+        Some(v) => v,
+        None => return ExitCode::SUCCESS,
+    };
+    for _ in 0..timesteps {
+        if let Err(e) = board.run_timestep() {
+            eprintln!("error: {}", e);
+            return ExitCode::FAILURE;
+        }
+    }
+    if options.verbosity > Verbosity::Quiet {
+        println!("After {} timesteps:", timesteps);
+        for chip in &board.chips {
+            println!("{}: {}", chip.name, chip.machine);
+        }
+    }
+    ExitCode::SUCCESS
+}
+
+/// Print a saved solution with new code for some of its chips.
+fn export(source: &str, options: &Options) -> ExitCode {
+    let mut solution = match Solution::parse(source) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("error: {}: {}", options.path, e);
+            return ExitCode::from(EXIT_INVALID);
+        }
+    };
+    for (name, path) in &options.programs {
+        let code = match fs::read_to_string(path) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("error: could not read {}: {}", path, e);
+                return ExitCode::from(EXIT_USAGE);
+            }
+        };
+        let component = match solution.chip_mut(name) {
+            Some(v) => v,
+            None => {
+                eprintln!("error: {} has no chip called {:?}", options.path, name);
+                return ExitCode::from(EXIT_USAGE);
+            }
+        };
+        // Don't hand the game a program it would reject.
+        if let Err(e) = Machine::load_chip(&code, component.model().unwrap()) {
+            eprintln!("error: {}: {}", path, e);
+            return ExitCode::from(EXIT_INVALID);
+        }
+        component.code = Some(code);
+    }
+    if !options.programs.is_empty() {
+        solution.clear_scores();
+    }
+    print!("{}", solution);
+    ExitCode::SUCCESS
+}
+
+/// Run the program for the `run`, `trace` and `score` commands.
+fn execute(
+    machine: &mut Machine,
+    source: &str,
+    options: &Options,
+    config: &Config,
+    mut limits: Limits,
+) -> ExitCode {
+    // A diagram against expected outputs covers exactly that many timesteps,
+    // so reaching the end of them is how the run is meant to stop.
+    let planned = options.svg.is_some()
+        && limits.max_timesteps.is_none()
+        && !config.expected.is_empty();
+    if planned {
+        limits.max_timesteps = config.expected.iter().map(|(_, v)| v.len()).max();
+    }
+
+    let json = options.command == Command::Trace && options.json;
+    let trace = options.command == Command::Trace || options.verbosity == Verbosity::Verbose;
+    let scope = Path::new(&options.path)
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| String::from("chip"));
+    let mut json_trace =
+        json.then(|| JsonTrace::new(io::stdout().lock(), machine, source).named(&scope));
+    let mut recorder = (options.vcd.is_some() || options.svg.is_some())
+        .then(|| vcd::Recorder::new(&scope, machine));
+    let mut write_error = None;
+    let outcome = machine.run_with(&limits, |machine| {
+        if let Some(recorder) = recorder.as_mut() {
+            recorder.record(machine);
+        }
+        if let Some(json_trace) = json_trace.as_mut() {
+            if let Err(e) = json_trace.record(machine) {
+                write_error.get_or_insert(e);
+            }
+        } else if trace && options.verbosity > Verbosity::Quiet {
+            println!("{:>6} {}", machine.steps(), machine);
+        }
+    });
+    drop(json_trace);
+    if let Some(e) = write_error {
+        eprintln!("error: could not write trace: {}", e);
+        return ExitCode::FAILURE;
+    }
+    if let Some(recorder) = recorder {
+        let end = limits.max_timesteps.unwrap_or(machine.timestep() + 1);
+        let dump = Vcd {
+            scopes: vec![recorder.finish()],
+            end,
+        };
+        if let Some(path) = &options.vcd
+            && let Err(e) = fs::File::create(path).and_then(|f| dump.write(io::BufWriter::new(f)))
+        {
+            eprintln!("error: could not write {}: {}", path, e);
+            return ExitCode::FAILURE;
+        }
+        if let Some(path) = &options.svg {
+            let rows = svg::rows(&dump.scopes[0], &config.inputs, &config.expected, end);
+            if let Err(e) = fs::write(path, svg::render(&rows, end)) {
+                eprintln!("error: could not write {}: {}", path, e);
+                return ExitCode::FAILURE;
+            }
+            if options.verbosity > Verbosity::Quiet {
+                for row in &rows {
+                    let mismatches = row.mismatches();
+                    if !mismatches.is_empty() {
+                        eprintln!(
+                            "{} differs from the expected output at {} timestep(s), first at {}",
+                            row.name,
+                            mismatches.len(),
+                            mismatches[0]
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    let coverage = Coverage::of(machine);
+    if let Err(code) = report_coverage(&[(&options.path, source, &coverage)], options) {
+        return code;
+    }
+    if options.profile {
+        print!("{}", profile::report(machine, &coverage, source));
+    }
+
+    // The JSON trace is the whole output, so it isn't followed by a summary.
+    if options.verbosity > Verbosity::Quiet && !json {
+        match options.command {
+            Command::Score => {
+                println!("cost:  {}", machine.chip().cost());
+                println!("power: {}", machine.power());
+                println!("lines: {}", machine.lines_of_code());
+            }
+            _ => {
+                println!("Final state: {}", machine);
+                println!(
+                    "Finished execution in {} steps over {} timesteps.",
+                    machine.steps(),
+                    machine.timestep() + 1
+                );
+            }
+        }
+    }
+
+    let (reason, code) = match outcome {
+        Outcome::Halted => return ExitCode::SUCCESS,
+        // A program stuck in one timestep stops with the same outcome, but
+        // hasn't reached the end of the diagram.
+        Outcome::TimestepLimit
+            if planned && limits.max_timesteps.is_some_and(|max| machine.next_timestep() >= max) =>
+        {
+            return ExitCode::SUCCESS;
+        }
+        Outcome::StepLimit => ("step limit", EXIT_LIMIT),
+        Outcome::TimestepLimit => ("timestep limit", EXIT_LIMIT),
+        Outcome::Timeout => ("timeout", EXIT_TIMEOUT),
+    };
+    // Always dump the state, even with --quiet, so CI logs show where it got stuck.
+    eprintln!(
+        "warning: {} reached after {} steps at timestep {}, before the program halted",
+        reason,
+        machine.steps(),
+        machine.timestep()
+    );
+    eprintln!("state: {}", machine);
+    ExitCode::from(code)
 }
@@ -0,0 +1,356 @@
+/*
+    A peephole optimizer: small rewrites of the parsed program that keep
+    what it does but take fewer lines or less power.
+*/
+
+use std::fmt;
+
+use crate::format;
+use crate::instruction::{Arg, Instruction, condition};
+use crate::parse::Line;
+use crate::register;
+
+/// One change the optimizer made.
+#[derive(Debug, PartialEq)]
+pub struct Rewrite {
+    /// The source line the change was made at.
+    pub line: usize,
+    pub description: String,
+    /// Change in lines of code.
+    pub lines: i64,
+    /// Change in power each time execution passes the spot, when it saves
+    /// the most.
+    pub power: i64,
+}
+
+impl fmt::Display for Rewrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {} (", self.line, self.description)?;
+        if self.lines == 0 && self.power == 0 {
+            return write!(f, "no change in lines or power)");
+        }
+        write!(
+            f,
+            "{:+} line{}, {:+} power per pass)",
+            self.lines,
+            if self.lines.abs() == 1 { "" } else { "s" },
+            self.power
+        )
+    }
+}
+
+/// Makes one rewrite if it can find a place for it.
+type Pass = fn(&mut Vec<(usize, Line)>) -> Option<Rewrite>;
+
+/// Rewrite `program`, whose entries are paired with their source lines,
+/// until none of the rewrites apply. Returns what was changed, in order.
+pub fn optimize(program: &mut Vec<(usize, Line)>) -> Vec<Rewrite> {
+    let passes: [Pass; 6] = [
+        remove_no_ops,
+        fold_arithmetic,
+        drop_jumps_to_next,
+        merge_labels,
+        prefix_jumped_over_lines,
+        remove_unused_labels,
+    ];
+    let mut rewrites = Vec::new();
+    while let Some(rewrite) = passes.iter().find_map(|pass| pass(program)) {
+        rewrites.push(rewrite);
+    }
+    rewrites
+}
+
+/// The signed amount an `add` or `sub` of a literal changes acc by.
+fn delta(line: &Line) -> Option<i64> {
+    match line {
+        (Instruction::Add, args) => match args[0] {
+            Arg::Number(n) => Some(n),
+            _ => None,
+        },
+        (Instruction::Sub, args) => match args[0] {
+            Arg::Number(n) => Some(-n),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Where each label is defined, or `None` if it is defined more than once.
+fn definition(program: &[(usize, Line)], label: &Arg) -> Option<usize> {
+    let mut found = program
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, (instr, args)))| *instr == Instruction::Label && args[0] == *label)
+        .map(|(i, _)| i);
+    let first = found.next()?;
+    match found.next() {
+        Some(_) => None,
+        None => Some(first),
+    }
+}
+
+fn is_jump_to(line: &Line, label: &Arg) -> bool {
+    line.0 == Instruction::Jmp && line.1[0] == *label
+}
+
+/// `nop`, and arithmetic that leaves acc as it was.
+fn remove_no_ops(program: &mut Vec<(usize, Line)>) -> Option<Rewrite> {
+    let i = program.iter().position(|(_, line)| {
+        line.0 == Instruction::Nop
+            || delta(line) == Some(0)
+            || line.0 == Instruction::Mul && line.1[0] == Arg::Number(1)
+    })?;
+    let (source, line) = program.remove(i);
+    Some(Rewrite {
+        line: source,
+        description: format!("removed {}", format::line(&line)),
+        lines: -1,
+        power: -1,
+    })
+}
+
+/// Two `add`s or `sub`s of literals in a row become one. Only done when
+/// both move acc the same way, since saturating in between could otherwise
+/// give a different answer, and the total fits in a literal.
+fn fold_arithmetic(program: &mut Vec<(usize, Line)>) -> Option<Rewrite> {
+    let i = (1..program.len()).find(|&i| {
+        let (a, b) = (&program[i - 1].1, &program[i].1);
+        match (delta(a), delta(b)) {
+            (Some(x), Some(y)) => {
+                condition(&a.1) == condition(&b.1)
+                    && x.signum() == y.signum()
+                    && (x + y).abs() <= register::MAX
+            }
+            _ => false,
+        }
+    })?;
+    let total = delta(&program[i - 1].1)? + delta(&program[i].1)?;
+    let (source, removed) = program.remove(i);
+    let first = &mut program[i - 1].1;
+    let before = format::line(first);
+    first.1[0] = Arg::Number(if first.0 == Instruction::Sub {
+        -total
+    } else {
+        total
+    });
+    Some(Rewrite {
+        line: source,
+        description: format!(
+            "folded {} into {}, now {}",
+            format::line(&removed),
+            before,
+            format::line(first)
+        ),
+        lines: -1,
+        power: -1,
+    })
+}
+
+/// A `jmp` to a label that comes straight after it, with at most other
+/// labels in between.
+fn drop_jumps_to_next(program: &mut Vec<(usize, Line)>) -> Option<Rewrite> {
+    let i = (0..program.len()).find(|&i| {
+        let (instr, args) = &program[i].1;
+        *instr == Instruction::Jmp
+            && definition(program, &args[0]).is_some_and(|target| {
+                target > i
+                    && program[i + 1..target]
+                        .iter()
+                        .all(|(_, (instr, _))| *instr == Instruction::Label)
+            })
+    })?;
+    let (source, line) = program.remove(i);
+    Some(Rewrite {
+        line: source,
+        description: format!(
+            "removed {}, which goes to the next line",
+            format::line(&line)
+        ),
+        lines: -1,
+        power: -1,
+    })
+}
+
+/// Two labels on the same spot become one.
+fn merge_labels(program: &mut Vec<(usize, Line)>) -> Option<Rewrite> {
+    let i = (1..program.len()).find(|&i| {
+        let (a, b) = (&program[i - 1].1, &program[i].1);
+        a.0 == Instruction::Label
+            && b.0 == Instruction::Label
+            && a.1[0] != b.1[0]
+            && definition(program, &a.1[0]).is_some()
+            && definition(program, &b.1[0]).is_some()
+    })?;
+    let keep = program[i - 1].1.1[0].clone();
+    let (source, (_, args)) = program.remove(i);
+    for (_, line) in program.iter_mut() {
+        if is_jump_to(line, &args[0]) {
+            line.1[0] = keep.clone();
+        }
+    }
+    Some(Rewrite {
+        line: source,
+        description: format!("merged label {} into {}", args[0], keep),
+        lines: 0,
+        power: 0,
+    })
+}
+
+/// A conditional `jmp` straight after a test, over lines to a label:
+///
+/// ```text
+/// teq acc 0          teq acc 0
+/// - jmp skip    =>   + add 1
+/// add 1              skip:
+/// skip:
+/// ```
+///
+/// The skipped lines get the opposite condition instead. `tcp` can leave
+/// neither condition set, so only `teq`, `tgt` and `tlt` qualify.
+fn prefix_jumped_over_lines(program: &mut Vec<(usize, Line)>) -> Option<Rewrite> {
+    let (i, target) = (1..program.len()).find_map(|i| {
+        let (test, test_args) = &program[i - 1].1;
+        let (instr, args) = &program[i].1;
+        if !matches!(test, Instruction::Teq | Instruction::Tgt | Instruction::Tlt)
+            || condition(test_args).is_some()
+            || *instr != Instruction::Jmp
+            || condition(args).is_none()
+        {
+            return None;
+        }
+        let target = definition(program, &args[0]).filter(|t| *t > i + 1)?;
+        program[i + 1..target]
+            .iter()
+            .all(|(_, (instr, args))| {
+                *instr != Instruction::Label && !instr.is_test() && condition(args).is_none()
+            })
+            .then_some((i, target))
+    })?;
+    let (source, jump) = program.remove(i);
+    let opposite = match condition(&jump.1) {
+        Some(Arg::BranchTrue) => Arg::BranchFalse,
+        _ => Arg::BranchTrue,
+    };
+    for (_, (_, args)) in &mut program[i..target - 1] {
+        args.push(opposite.clone());
+    }
+    Some(Rewrite {
+        line: source,
+        description: format!(
+            "replaced {} with {} on the {} line(s) it jumped over",
+            format::line(&jump),
+            opposite,
+            target - 1 - i
+        ),
+        lines: -1,
+        power: -1,
+    })
+}
+
+/// Labels nothing jumps to.
+fn remove_unused_labels(program: &mut Vec<(usize, Line)>) -> Option<Rewrite> {
+    let i = program.iter().position(|(_, (instr, args))| {
+        *instr == Instruction::Label && !program.iter().any(|(_, line)| is_jump_to(line, &args[0]))
+    })?;
+    let (source, (_, args)) = program.remove(i);
+    Some(Rewrite {
+        line: source,
+        description: format!("removed label {}, which nothing jumps to", args[0]),
+        lines: 0,
+        power: 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::optimize;
+    use crate::format;
+    use crate::machine::Machine;
+    use crate::parse;
+
+    fn optimized(source: &str) -> (String, Vec<String>) {
+        let mut program = parse::program(source).unwrap();
+        let rewrites = optimize(&mut program);
+        let lines: Vec<_> = program.into_iter().map(|(_, line)| line).collect();
+        (
+            format::print(&lines),
+            rewrites.iter().map(|r| r.to_string()).collect(),
+        )
+    }
+
+    #[test]
+    fn applies_each_rewrite() {
+        let source = "\
+start:
+top:
+  nop
+  add 3
+  sub -4
+  teq acc 7
+  - jmp skip
+  mov acc x0
+  add 1
+skip:
+  jmp next
+next:
+  sub 0
+  jmp start";
+        let (program, rewrites) = optimized(source);
+        assert_eq!(
+            program,
+            "start:\n    add 7\n    teq acc 7\n  + mov acc x0\n  + add 1\n    jmp start\n"
+        );
+        assert_eq!(
+            rewrites,
+            vec![
+                "line 3: removed nop (-1 line, -1 power per pass)",
+                "line 13: removed sub 0 (-1 line, -1 power per pass)",
+                "line 5: folded sub -4 into add 3, now add 7 (-1 line, -1 power per pass)",
+                "line 11: removed jmp next, which goes to the next line (-1 line, -1 power per pass)",
+                "line 2: merged label top into start (no change in lines or power)",
+                "line 12: merged label next into skip (no change in lines or power)",
+                "line 7: replaced - jmp skip with + on the 2 line(s) it jumped over \
+                 (-1 line, -1 power per pass)",
+                "line 10: removed label skip, which nothing jumps to (no change in lines or power)",
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_unsafe_patterns_alone() {
+        let source = "\
+add 900
+sub 5
+add 600
+add 600
+tcp acc 1
+- jmp skip
+add 1
+skip:
+teq acc 1
++ jmp over
+add 1
+teq acc 2
+over:
+jmp over";
+        let (_, rewrites) = optimized(source);
+        assert_eq!(rewrites, Vec::<String>::new());
+    }
+
+    #[test]
+    fn optimized_programs_end_in_the_same_state() {
+        let source = include_str!("../program.asm");
+        let mut program = parse::program(source).unwrap();
+        optimize(&mut program);
+        let lines: Vec<_> = program.into_iter().map(|(_, line)| line).collect();
+        let mut before = Machine::load(source).unwrap();
+        let mut after = Machine::new(lines).unwrap();
+        before.run();
+        after.run();
+        assert_eq!(before.registers().len(), after.registers().len());
+        for (name, register) in before.registers() {
+            assert_eq!(after.register(name), Some(register.value), "{}", name);
+        }
+        assert!(after.power() <= before.power());
+    }
+}
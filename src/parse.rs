@@ -3,65 +3,52 @@ use std::collections::VecDeque;
 
 use crate::instruction;
 use crate::instruction::Instruction;
+use crate::register;
 
 /// Turn the tuple of (String, args) to Option<(instruction::Instruction, args)>
-/// 
+///
 /// Returns None if parsed instruction is something we dont want, (e.g. a comment.)
+/// Fails on an unknown mnemonic, and on a label sharing its line with an
+/// instruction, which `entries` splits up instead.
 pub fn abstracted(
     instruction: (String, Vec<String>),
-) -> Option<(instruction::Instruction, Vec<Arg>)> {
+) -> Result<Option<(instruction::Instruction, Vec<Arg>)>, String> {
     let (instr, args) = instruction;
 
     if instr.is_empty() {
-        return None;
+        return Ok(None);
     }
 
     if instr.contains(":") {
+        if !args.is_empty() {
+            return Err(format!("{} is followed by an instruction", instr));
+        }
         let label = instr.replace(":", "");
         let label_name = Arg::Label(label);
-        return Some((Instruction::Label, Vec::from([label_name])));
+        return Ok(Some((Instruction::Label, Vec::from([label_name]))));
     }
 
-    let first_char = instr.chars().nth(0).unwrap();
-    match first_char {
-        '#' => return None,
-        _ => (),
+    if instr.starts_with('#') {
+        return Ok(None);
     }
 
-    let parsed_instruction = match instr.as_ref() {
-        "nop" => Instruction::Nop,
-        "add" => Instruction::Add,
-        "mov" => Instruction::Mov,
-        "sub" => Instruction::Sub,
-        "mul" => Instruction::Mul,
-        "not" => Instruction::Not,
-        "jmp" => Instruction::Jmp,
-        "teq" => Instruction::Teq,
-        "tgt" => Instruction::Tgt,
-        "tlt" => Instruction::Tlt,
-        _ => todo!(),
+    let parsed_instruction = match mnemonic(&instr) {
+        Some(v) => v,
+        None => return Err(format!("unknown instruction {:?}", instr)),
     };
 
     let mut should_branch_true: bool = false;
     let mut should_branch_false: bool = false;
     let mut arguments: Vec<Arg> = Vec::new();
     for el in args {
-        if let Ok(v) = el.parse::<u64>() {
+        if let Ok(v) = el.parse::<i64>() {
             arguments.push(Arg::Number(v));
         } else {
-            if el.len() == 1 {
-                let character = el.chars().nth(0).unwrap();
-                let _ = match character {
-                    '+' => should_branch_true = true,
-                    '-' => should_branch_false = true,
-                    _ => (),
-                };
-            } else {
-                if instr == "jmp" {
-                    arguments.push(Arg::Label(el));
-                } else {
-                    arguments.push(Arg::Register(el));
-                }
+            match el.as_str() {
+                "+" => should_branch_true = true,
+                "-" => should_branch_false = true,
+                _ if instr == "jmp" => arguments.push(Arg::Label(el)),
+                _ => arguments.push(Arg::Register(el)),
             }
         }
     }
@@ -70,25 +57,140 @@ pub fn abstracted(
     } else if should_branch_false {
         arguments.push(Arg::BranchFalse);
     }
-    Some((parsed_instruction, arguments))
+    Ok(Some((parsed_instruction, arguments)))
+}
+
+/// Every mnemonic `mnemonic` knows.
+pub const MNEMONICS: [&str; 14] = [
+    "nop", "mov", "add", "sub", "mul", "not", "jmp", "teq", "tgt", "tlt", "tcp", "dgt", "dst",
+    "slp",
+];
+
+/// Look up the instruction for a mnemonic such as `"mov"`.
+pub fn mnemonic(name: &str) -> Option<Instruction> {
+    let instruction = match name {
+        "nop" => Instruction::Nop,
+        "add" => Instruction::Add,
+        "mov" => Instruction::Mov,
+        "sub" => Instruction::Sub,
+        "mul" => Instruction::Mul,
+        "not" => Instruction::Not,
+        "jmp" => Instruction::Jmp,
+        "teq" => Instruction::Teq,
+        "tgt" => Instruction::Tgt,
+        "tlt" => Instruction::Tlt,
+        "tcp" => Instruction::Tcp,
+        "dgt" => Instruction::Dgt,
+        "dst" => Instruction::Dst,
+        "slp" => Instruction::Slp,
+        _ => return None,
+    };
+    Some(instruction)
+}
+
+/// Check that a split line names a real instruction with the right number of
+/// arguments, so that `abstracted` and `machine::exec` can trust it.
+pub fn validate(instruction: &(String, Vec<String>)) -> Result<(), String> {
+    let (instr, args) = instruction;
+    if instr.contains(':') && !instr.starts_with('#') && !args.is_empty() {
+        return validate(&parse_instruction(&args.join(" "))?);
+    }
+    if instr.is_empty() || instr.starts_with('#') || instr.contains(':') {
+        return Ok(());
+    }
+
+    let parsed = match mnemonic(instr) {
+        Some(v) => v,
+        None => return Err(format!("unknown instruction {:?}", instr)),
+    };
+
+    let markers = args.iter().filter(|a| *a == "+" || *a == "-").count();
+    if markers > 1 {
+        return Err(String::from("more than one condition prefix"));
+    }
+    for arg in args {
+        if let Ok(v) = arg.parse::<i64>()
+            && !(register::MIN..=register::MAX).contains(&v)
+        {
+            return Err(format!(
+                "{} is outside the range {} to {}",
+                v,
+                register::MIN,
+                register::MAX
+            ));
+        }
+    }
+
+    let given = args.len() - markers;
+    if given != parsed.arity() {
+        return Err(format!(
+            "{} takes {} argument(s), {} given",
+            instr,
+            parsed.arity(),
+            given
+        ));
+    }
+
+    let operands: Vec<&String> = args.iter().filter(|a| *a != "+" && *a != "-").collect();
+    let number = |i: usize| operands[i].parse::<i64>().is_ok();
+    if parsed == Instruction::Mov && number(1) {
+        return Err(format!("mov can't write to {}", operands[1]));
+    }
+    if parsed == Instruction::Jmp && number(0) {
+        return Err(format!("jmp needs a label, not {}", operands[0]));
+    }
+    Ok(())
+}
+
+/// One parsed line: an instruction and its arguments.
+pub type Line = (Instruction, Vec<Arg>);
+
+/// Parse and validate a whole program, pairing each entry with the source
+/// line it came from. Comments and blank lines produce no entries.
+pub fn program(source: &str) -> Result<Vec<(usize, Line)>, String> {
+    let mut program = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        match entries(line) {
+            Ok(v) => program.extend(v.into_iter().map(|entry| (i + 1, entry))),
+            Err(e) => return Err(format!("line {}: {}", i + 1, e)),
+        }
+    }
+    Ok(program)
+}
+
+/// Split a label off the front of a line, as in `loop: add 1`, returning
+/// its name and whatever follows it.
+pub fn label(line: &str) -> Option<(&str, &str)> {
+    let trimmed = line.trim_start();
+    let first = trimmed.split_ascii_whitespace().next()?;
+    if first.starts_with('#') || !first.contains(':') {
+        return None;
+    }
+    let (name, rest) = trimmed.split_once(':')?;
+    Some((name, rest))
+}
+
+/// Parse and validate one line of source. A label sharing its line with an
+/// instruction gives an entry for each; comments and blank lines give none.
+pub fn entries(line: &str) -> Result<Vec<Line>, String> {
+    let mut entries = Vec::new();
+    let mut rest = line;
+    if let Some((name, after)) = label(line) {
+        entries.push((Instruction::Label, vec![Arg::Label(name.to_owned())]));
+        rest = after;
+    }
+    let first_pass = split_line(rest);
+    validate(&first_pass)?;
+    entries.extend(abstracted(first_pass)?);
+    Ok(entries)
 }
 
 /// Given a line, determine what it is and split it into (instruction, args)
 pub fn split_line(line: &str) -> (String, Vec<String>) {
-    let first_char = line.chars().nth(0);
-    if let Some(_) = first_char {
-        match line.chars().nth(0).unwrap() {
-            '#' | ':' => (line.to_owned(), Vec::new()),
-            _ => {
-                if let Ok(v) = parse_instruction(line) {
-                    return v;
-                } else {
-                    (String::new(), Vec::new())
-                }
-            }
-        }
-    } else {
-        (String::new(), Vec::new())
+    match line.chars().next() {
+        Some('#') | Some(':') => (line.to_owned(), Vec::new()),
+        Some(_) => parse_instruction(line).unwrap_or_default(),
+        None => (String::new(), Vec::new()),
     }
 }
 
@@ -96,16 +198,17 @@ pub fn split_line(line: &str) -> (String, Vec<String>) {
 pub fn parse_instruction(line: &str) -> Result<(String, Vec<String>), String> {
     let mut split: VecDeque<String> = line
         .split_ascii_whitespace()
-        .into_iter()
         .map(|x| x.to_owned())
         .collect();
     let instruction: String;
-    if split.len() == 0 {
+    if split.is_empty() {
         return Err(String::from("Empty string"));
     }
     if split[0] == "+" || split[0] == "-" {
+        if split.len() == 1 {
+            return Err(String::from("Condition prefix without an instruction"));
+        }
         instruction = split[1].clone();
-        // dbg!(&split);
         let arguments = match split.len() {
             1 => Vec::new(),
             _ => {
@@ -132,9 +235,9 @@ pub fn parse_instruction(line: &str) -> Result<(String, Vec<String>), String> {
 #[cfg(test)]
 mod tests {
     use super::abstracted;
+    use super::entries;
     use super::parse_instruction;
-    use super::split_line;
-    use crate::Arg;
+    use crate::instruction::Arg;
     use crate::instruction::Instruction;
 
     #[test]
@@ -164,27 +267,27 @@ mod tests {
     #[test]
     fn abstracted_test() {
         assert_eq!(
-            abstracted(parse_instruction("nop").unwrap()).unwrap(),
+            abstracted(parse_instruction("nop").unwrap()).unwrap().unwrap(),
             (Instruction::Nop, vec![])
         );
 
         assert_eq!(
-            abstracted(parse_instruction("add 1").unwrap()).unwrap(),
+            abstracted(parse_instruction("add 1").unwrap()).unwrap().unwrap(),
             (Instruction::Add, vec![Arg::Number(1)])
         );
 
         assert_eq!(
-            abstracted(parse_instruction("add x1").unwrap()).unwrap(),
+            abstracted(parse_instruction("add x1").unwrap()).unwrap().unwrap(),
             (Instruction::Add, vec![Arg::Register(String::from("x1"))])
         );
 
         assert_eq!(
-            abstracted(parse_instruction("jmp end").unwrap()).unwrap(),
+            abstracted(parse_instruction("jmp end").unwrap()).unwrap().unwrap(),
             (Instruction::Jmp, vec![Arg::Label(String::from("end"))])
         );
 
         assert_eq!(
-            abstracted(parse_instruction("teq acc 5").unwrap()).unwrap(),
+            abstracted(parse_instruction("teq acc 5").unwrap()).unwrap().unwrap(),
             (
                 Instruction::Teq,
                 vec![Arg::Register(String::from("acc")), Arg::Number(5)]
@@ -192,7 +295,7 @@ mod tests {
         );
 
         assert_eq!(
-            abstracted(parse_instruction("mov acc x1").unwrap()).unwrap(),
+            abstracted(parse_instruction("mov acc x1").unwrap()).unwrap().unwrap(),
             (
                 Instruction::Mov,
                 vec![
@@ -203,8 +306,35 @@ mod tests {
         );
 
         assert_eq!(
-            abstracted(parse_instruction("+ add 3").unwrap()).unwrap(),
+            abstracted(parse_instruction("+ add 3").unwrap()).unwrap().unwrap(),
             (Instruction::Add, vec![Arg::Number(3), Arg::BranchTrue,])
         );
     }
+
+    #[test]
+    fn label_sharing_a_line() {
+        assert_eq!(
+            entries("l: add 1").unwrap(),
+            vec![
+                (Instruction::Label, vec![Arg::Label(String::from("l"))]),
+                (Instruction::Add, vec![Arg::Number(1)]),
+            ]
+        );
+        assert_eq!(
+            entries("l: + jmp l").unwrap()[1],
+            (
+                Instruction::Jmp,
+                vec![Arg::Label(String::from("l")), Arg::BranchTrue]
+            )
+        );
+        assert_eq!(entries("l: # done").unwrap().len(), 1);
+        assert!(entries("l: bogus 1 2").unwrap_err().contains("bogus"));
+        assert!(entries("l: add").is_err());
+    }
+
+    #[test]
+    fn unknown_mnemonic() {
+        let err = abstracted(parse_instruction("bogus 1").unwrap()).unwrap_err();
+        assert!(err.contains("bogus"));
+    }
 }
@@ -0,0 +1,211 @@
+/*
+    Where a program spends its power: how often each line ran, as a share of
+    the total, and which loops the most of it went to.
+*/
+
+use std::cmp::Reverse;
+use std::fmt::Write;
+
+use crate::coverage::Coverage;
+use crate::instruction::Instruction;
+use crate::machine::Machine;
+
+/// A `jmp` back to a label at or before it, and the lines it repeats.
+#[derive(Debug, PartialEq)]
+pub struct Loop {
+    pub label: String,
+    /// Source lines of the label and of the `jmp`.
+    pub start: usize,
+    pub end: usize,
+    /// How many times the `jmp` went back.
+    pub iterations: usize,
+    /// Power used by the lines from the label to the `jmp`.
+    pub power: usize,
+}
+
+/// Every backward jump in the program `machine` runs, with the power spent in
+/// it according to `coverage`, most expensive first.
+pub fn loops(machine: &Machine, coverage: &Coverage) -> Vec<Loop> {
+    let mut loops = Vec::new();
+    for (i, (instr, args)) in machine.program().iter().enumerate() {
+        if *instr != Instruction::Jmp {
+            continue;
+        }
+        let label = match machine.label(&args[0].to_string()) {
+            Some(label) if label <= i => label,
+            _ => continue,
+        };
+        let body = coverage
+            .entries()
+            .filter(|e| (label..=i).contains(&e.index));
+        loops.push(Loop {
+            label: args[0].to_string(),
+            start: machine.line_of(label).unwrap_or(label + 1),
+            end: machine.line_of(i).unwrap_or(i + 1),
+            iterations: coverage
+                .entries()
+                .find(|e| e.index == i)
+                .map_or(0, |e| e.executed),
+            power: body.map(|e| e.executed).sum(),
+        });
+    }
+    loops.sort_by_key(|l| Reverse(l.power));
+    loops
+}
+
+/// `part` as a share of `whole`, as a percentage to one decimal place.
+/// A `whole` of 0 gives 0.0%.
+fn power_share(part: usize, whole: usize) -> String {
+    let tenths = (part * 1000).checked_div(whole).unwrap_or(0);
+    format!("{}.{}%", tenths / 10, tenths % 10)
+}
+
+/// The source with how many times each line ran and its share of the power
+/// in front of it, followed by the loops that used the most.
+pub fn report(machine: &Machine, coverage: &Coverage, source: &str) -> String {
+    let total: usize = coverage.entries().map(|e| e.executed).sum();
+    let mut out = String::new();
+    let _ = writeln!(out, "{:>7} {:>6}  line", "count", "power");
+    for (i, text) in source.lines().enumerate() {
+        match coverage.entries().find(|e| e.line == i + 1) {
+            Some(e) => {
+                let _ = writeln!(
+                    out,
+                    "{:>7} {:>6}  {:>4}: {}",
+                    e.executed,
+                    power_share(e.executed, total),
+                    i + 1,
+                    text
+                );
+            }
+            None => {
+                let row = format!("{:>7} {:>6}  {:>4}: {}", "", "", i + 1, text);
+                let _ = writeln!(out, "{}", row.trim_end());
+            }
+        }
+    }
+    let _ = writeln!(out, "\ntotal power: {}", total);
+    let loops = loops(machine, coverage);
+    if !loops.is_empty() {
+        let _ = writeln!(out, "loops by power:");
+    }
+    for l in loops {
+        let _ = writeln!(
+            out,
+            "  {} (lines {}-{}): {} power, {}, jumped back {} times",
+            l.label,
+            l.start,
+            l.end,
+            l.power,
+            power_share(l.power, total),
+            l.iterations
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Loop, loops, power_share, report};
+    use crate::coverage::Coverage;
+    use crate::machine::Machine;
+
+    const FIBONACCI: &str = include_str!("../program.asm");
+
+    #[test]
+    fn finds_the_expensive_loop() {
+        let mut machine = Machine::load(FIBONACCI).unwrap();
+        machine.run();
+        let coverage = Coverage::of(&machine);
+        assert_eq!(
+            loops(&machine, &coverage),
+            vec![Loop {
+                label: String::from("loop"),
+                start: 3,
+                end: 21,
+                iterations: 8,
+                power: 91,
+            }]
+        );
+        let report = report(&machine, &coverage, FIBONACCI);
+        assert!(
+            report.contains("      9   9.5%     4:     add 1\n"),
+            "{}",
+            report
+        );
+        assert!(
+            report.contains("                   3: loop:\n"),
+            "{}",
+            report
+        );
+        assert!(report.contains("total power: 94\n"), "{}", report);
+        assert!(
+            report.contains("  loop (lines 3-21): 91 power, 96.8%, jumped back 8 times\n"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn orders_two_loops_by_power() {
+        let source = "a:\nadd 1\nteq acc 3\n- jmp a\nb:\nsub 1\nteq acc -3\n- jmp b";
+        let mut machine = Machine::load(source).unwrap();
+        machine.run();
+        let coverage = Coverage::of(&machine);
+        // b counts down from 3 to -3, twice as far as a counts up.
+        assert_eq!(
+            loops(&machine, &coverage),
+            vec![
+                Loop {
+                    label: String::from("b"),
+                    start: 5,
+                    end: 8,
+                    iterations: 5,
+                    power: 17,
+                },
+                Loop {
+                    label: String::from("a"),
+                    start: 1,
+                    end: 4,
+                    iterations: 2,
+                    power: 8,
+                },
+            ]
+        );
+        assert_eq!(machine.power(), 25);
+    }
+
+    #[test]
+    fn counts_a_conditional_back_jump() {
+        let source = "top:\nadd 2\ntlt acc 9\n+ jmp top\nmov acc x1";
+        let mut machine = Machine::load(source).unwrap();
+        machine.run();
+        let coverage = Coverage::of(&machine);
+        // Jumps back at 2, 4, 6 and 8, and falls through at 10.
+        assert_eq!(
+            loops(&machine, &coverage),
+            vec![Loop {
+                label: String::from("top"),
+                start: 1,
+                end: 4,
+                iterations: 4,
+                power: 14,
+            }]
+        );
+        let report = report(&machine, &coverage, source);
+        assert!(report.contains("total power: 15\n"), "{}", report);
+        assert!(
+            report.contains("  top (lines 1-4): 14 power, 93.3%, jumped back 4 times\n"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn power_share_rounds_down_to_tenths() {
+        assert_eq!(power_share(0, 0), "0.0%");
+        assert_eq!(power_share(1, 3), "33.3%");
+        assert_eq!(power_share(2, 3), "66.6%");
+        assert_eq!(power_share(5, 5), "100.0%");
+    }
+}
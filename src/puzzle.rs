@@ -0,0 +1,478 @@
+/*
+    Puzzle specifications: a board of chips, and test cases to check it with.
+*/
+
+use std::fmt;
+
+use crate::board::{Board, Kind};
+use crate::chip::ChipModel;
+use crate::machine::Machine;
+use crate::toml::{self, Table, Value};
+
+/// A chip on the puzzle's board.
+#[derive(Debug, PartialEq)]
+pub struct ChipSpec {
+    pub name: String,
+    pub model: ChipModel,
+    /// Where the chip's program is, relative to the puzzle file.
+    pub program: Option<String>,
+}
+
+/// The values fed to each input and expected from each output in one run.
+#[derive(Debug, PartialEq)]
+pub struct TestCase {
+    pub name: String,
+    pub values: Vec<(String, Value)>,
+}
+
+/// A board of chips wired to the puzzle's inputs and outputs.
+///
+/// ```toml
+/// name = "Amplifier"
+/// inputs = ["sensor"]
+/// outputs = ["speaker"]
+///
+/// [[chip]]
+/// name = "amp"
+/// model = "mc4000"
+/// program = "amplifier.asm"
+///
+/// [[wire]]
+/// connect = ["sensor", "amp.p0"]
+///
+/// [[wire]]
+/// connect = ["amp.p1", "speaker"]
+///
+/// [[test]]
+/// name = "rising"
+/// sensor = [0, 10, 20, 30]
+/// speaker = [0, 20, 40, 60]
+/// ```
+///
+/// A simple I/O port lists its value at each timestep. An XBus input lists
+/// the packets sent at each timestep, such as `[[1, 2], [], [3]]`, and an
+/// XBus output the values it should receive, in order. Tests run for
+/// `timesteps` timesteps if given, or otherwise for as long as the longest
+/// list.
+#[derive(Debug, Default, PartialEq)]
+pub struct Puzzle {
+    pub name: String,
+    pub timesteps: Option<usize>,
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+    pub chips: Vec<ChipSpec>,
+    pub wires: Vec<Vec<String>>,
+    pub tests: Vec<TestCase>,
+}
+
+/// How a test case went wrong.
+#[derive(Debug, PartialEq)]
+pub enum Failure {
+    /// An output was wrong. `None` means nothing arrived on an XBus output,
+    /// or a value arrived that wasn't expected.
+    Mismatch {
+        timestep: usize,
+        port: String,
+        expected: Option<i64>,
+        actual: Option<i64>,
+    },
+    /// The board could not keep running, such as when a chip never sleeps.
+    Stuck { timestep: usize, reason: String },
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let show = |v: &Option<i64>| match v {
+            Some(v) => v.to_string(),
+            None => String::from("nothing"),
+        };
+        match self {
+            Failure::Mismatch {
+                timestep,
+                port,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "at timestep {}: {} expected {}, got {}",
+                timestep,
+                port,
+                show(expected),
+                show(actual)
+            ),
+            Failure::Stuck { timestep, reason } => {
+                write!(f, "at timestep {}: {}", timestep, reason)
+            }
+        }
+    }
+}
+
+/// The result of one test case.
+#[derive(Debug, PartialEq)]
+pub struct TestResult {
+    pub name: String,
+    pub failure: Option<Failure>,
+}
+
+impl Puzzle {
+    pub fn parse(source: &str) -> Result<Puzzle, String> {
+        let mut puzzle = Puzzle::default();
+        for table in toml::parse(source)? {
+            let name = || match table.get("name") {
+                Some(v) => v.as_str().map(|s| s.to_owned()),
+                None => Err(format!("a [[{}]] has no name", table.name)),
+            };
+            match table.name.as_str() {
+                "" => {
+                    for (key, value) in &table.entries {
+                        let context = |e: String| format!("{}: {}", key, e);
+                        match key.as_str() {
+                            "name" => puzzle.name = value.as_str().map_err(context)?.to_owned(),
+                            "timesteps" => {
+                                puzzle.timesteps = Some(count(value).map_err(context)?);
+                            }
+                            "inputs" => puzzle.inputs = value.as_strings().map_err(context)?,
+                            "outputs" => puzzle.outputs = value.as_strings().map_err(context)?,
+                            _ => return Err(format!("unknown key {}", key)),
+                        }
+                    }
+                }
+                "chip" => {
+                    only(&table, &["name", "model", "program"])?;
+                    let name = name()?;
+                    if puzzle.chips.iter().any(|chip| chip.name == name) {
+                        return Err(format!("chip {} is listed twice", name));
+                    }
+                    let model = match table.get("model") {
+                        Some(v) => v.as_str()?.parse()?,
+                        None => ChipModel::default(),
+                    };
+                    let program = match table.get("program") {
+                        Some(v) => Some(v.as_str()?.to_owned()),
+                        None => None,
+                    };
+                    puzzle.chips.push(ChipSpec {
+                        name,
+                        model,
+                        program,
+                    });
+                }
+                "wire" => {
+                    only(&table, &["connect"])?;
+                    match table.get("connect") {
+                        Some(v) => puzzle.wires.push(v.as_strings()?),
+                        None => return Err(String::from("a [[wire]] has nothing to connect")),
+                    }
+                }
+                "test" => {
+                    let values = table
+                        .entries
+                        .iter()
+                        .filter(|(k, _)| k != "name")
+                        .cloned()
+                        .collect();
+                    puzzle.tests.push(TestCase {
+                        name: name()?,
+                        values,
+                    });
+                }
+                other => return Err(format!("unknown section [{}]", other)),
+            }
+        }
+
+        for test in &puzzle.tests {
+            for (port, _) in &test.values {
+                if !puzzle.inputs.contains(port) && !puzzle.outputs.contains(port) {
+                    return Err(format!(
+                        "test {}: {} is not an input or output",
+                        test.name, port
+                    ));
+                }
+            }
+        }
+        Ok(puzzle)
+    }
+
+    /// Wire up a board with `programs`, the source for each chip in order.
+    pub fn board(&self, programs: &[String]) -> Result<Board, String> {
+        let mut board = Board::new();
+        for (spec, source) in self.chips.iter().zip(programs) {
+            let machine = Machine::load_chip(source, spec.model)
+                .map_err(|e| format!("{}: {}", spec.name, e))?;
+            board.add_chip(&spec.name, machine);
+        }
+        for wire in &self.wires {
+            let endpoints: Vec<&str> = wire.iter().map(|s| s.as_str()).collect();
+            board.connect(&endpoints)?;
+        }
+        for port in self.inputs.iter().chain(&self.outputs) {
+            if board.port_kind(port).is_none() {
+                return Err(format!("{} isn't wired to anything", port));
+            }
+        }
+        Ok(board)
+    }
+
+    /// Run every test case with `programs`, the source for each chip in order.
+    pub fn verify(&self, programs: &[String]) -> Result<Vec<TestResult>, String> {
+        self.verify_with(programs, |_| ())
+    }
+
+    /// Like `verify`, also handing `observe` the board as each test case
+    /// left it.
+    pub fn verify_with(
+        &self,
+        programs: &[String],
+        mut observe: impl FnMut(&Board),
+    ) -> Result<Vec<TestResult>, String> {
+        if programs.len() != self.chips.len() {
+            return Err(format!(
+                "{} programs given for {} chips",
+                programs.len(),
+                self.chips.len()
+            ));
+        }
+        self.tests
+            .iter()
+            .map(|test| {
+                let mut board = self.board(programs)?;
+                let failure = self
+                    .run_test(&mut board, test)
+                    .map_err(|e| format!("test {}: {}", test.name, e))?;
+                observe(&board);
+                Ok(TestResult {
+                    name: test.name.to_owned(),
+                    failure,
+                })
+            })
+            .collect()
+    }
+
+    fn run_test(&self, board: &mut Board, test: &TestCase) -> Result<Option<Failure>, String> {
+        // Decode each port's values now the board says what kind it is.
+        let mut simple_inputs = Vec::new();
+        let mut xbus_inputs = Vec::new();
+        let mut simple_outputs = Vec::new();
+        let mut xbus_outputs = Vec::new();
+        for (port, value) in &test.values {
+            let context = |e: String| format!("{}: {}", port, e);
+            let input = self.inputs.contains(port);
+            match (board.port_kind(port), input) {
+                (Some(Kind::Simple), true) => {
+                    simple_inputs.push((port, value.as_integers().map_err(context)?))
+                }
+                (Some(Kind::Simple), false) => {
+                    simple_outputs.push((port, value.as_integers().map_err(context)?))
+                }
+                (Some(Kind::XBus), true) => {
+                    let packets = match value {
+                        Value::Array(items) => items
+                            .iter()
+                            .map(|v| v.as_integers())
+                            .collect::<Result<Vec<_>, _>>()
+                            .map_err(context)?,
+                        v => return Err(context(format!("expected an array, found {:?}", v))),
+                    };
+                    xbus_inputs.push((port, packets));
+                }
+                (Some(Kind::XBus), false) => {
+                    xbus_outputs.push((port, value.as_integers().map_err(context)?, 0))
+                }
+                (None, _) => return Err(format!("{} isn't wired to anything", port)),
+            }
+        }
+
+        let timesteps = match self.timesteps {
+            Some(v) => v,
+            None => {
+                let simple = simple_inputs.iter().chain(&simple_outputs);
+                let xbus = xbus_inputs.iter().map(|(_, v)| v.len());
+                simple.map(|(_, v)| v.len()).chain(xbus).max().unwrap_or(0)
+            }
+        };
+
+        for t in 0..timesteps {
+            // Simple inputs hold their last value once their list runs out.
+            for (port, values) in &simple_inputs {
+                if let Some(v) = values.get(t).or(values.last()) {
+                    board.set_input(port, *v)?;
+                }
+            }
+            for (port, packets) in &xbus_inputs {
+                for v in packets.get(t).into_iter().flatten() {
+                    board.send_input(port, *v)?;
+                }
+            }
+
+            if let Err(reason) = board.run_timestep() {
+                return Ok(Some(Failure::Stuck {
+                    timestep: t,
+                    reason,
+                }));
+            }
+
+            for (port, values) in &simple_outputs {
+                let actual = board.output(port)?;
+                if let Some(expected) = values.get(t)
+                    && *expected != actual
+                {
+                    return Ok(Some(Failure::Mismatch {
+                        timestep: t,
+                        port: port.to_string(),
+                        expected: Some(*expected),
+                        actual: Some(actual),
+                    }));
+                }
+            }
+            for (port, values, received) in xbus_outputs.iter_mut() {
+                for actual in board.receive_output(port)? {
+                    let expected = values.get(*received).copied();
+                    if expected != Some(actual) {
+                        return Ok(Some(Failure::Mismatch {
+                            timestep: t,
+                            port: port.to_string(),
+                            expected,
+                            actual: Some(actual),
+                        }));
+                    }
+                    *received += 1;
+                }
+            }
+        }
+
+        for (port, values, received) in &xbus_outputs {
+            if let Some(expected) = values.get(*received) {
+                return Ok(Some(Failure::Mismatch {
+                    timestep: timesteps.saturating_sub(1),
+                    port: port.to_string(),
+                    expected: Some(*expected),
+                    actual: None,
+                }));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Reject keys in `table` other than `allowed`, so a misspelt one isn't
+/// silently ignored.
+fn only(table: &Table, allowed: &[&str]) -> Result<(), String> {
+    match table.entries.iter().find(|(k, _)| !allowed.contains(&k.as_str())) {
+        Some((key, _)) => Err(format!("unknown key {} in a [[{}]]", key, table.name)),
+        None => Ok(()),
+    }
+}
+
+fn count(value: &Value) -> Result<usize, String> {
+    let v = value.as_integer()?;
+    usize::try_from(v).map_err(|_| format!("{} is not a count", v))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Failure, Puzzle};
+
+    const AMPLIFIER: &str = "\
+name = \"Amplifier\"
+inputs = [\"sensor\"]
+outputs = [\"speaker\"]
+
+[[chip]]
+name = \"amp\"
+model = \"mc4000\"
+
+[[wire]]
+connect = [\"sensor\", \"amp.p0\"]
+
+[[wire]]
+connect = [\"amp.p1\", \"speaker\"]
+
+[[test]]
+name = \"rising\"
+sensor = [0, 10, 20, 30]
+speaker = [0, 20, 40, 60]
+";
+
+    fn program(source: &str) -> Vec<String> {
+        vec![source.to_owned()]
+    }
+
+    #[test]
+    fn parses_a_puzzle() {
+        let puzzle = Puzzle::parse(AMPLIFIER).unwrap();
+        assert_eq!(puzzle.name, "Amplifier");
+        assert_eq!(puzzle.chips[0].name, "amp");
+        assert_eq!(puzzle.wires[1], ["amp.p1", "speaker"]);
+        assert_eq!(puzzle.tests[0].values.len(), 2);
+
+        assert!(Puzzle::parse("[[test]]\nname = \"t\"\nfoo = [1]\n").is_err());
+        assert!(Puzzle::parse("[[chip]]\nmodel = \"mc4000\"\n").is_err());
+        assert!(Puzzle::parse("[[chip]]\nname = \"a\"\nprgoram = \"a.asm\"\n").is_err());
+        assert!(Puzzle::parse("[[wire]]\nconnect = [\"a.p0\", \"b.p0\"]\nkind = 1\n").is_err());
+        assert_eq!(
+            Puzzle::parse("[[chip]]\nname = \"a\"\n[[chip]]\nname = \"a\"\n"),
+            Err(String::from("chip a is listed twice"))
+        );
+    }
+
+    #[test]
+    fn passes_a_working_solution() {
+        let puzzle = Puzzle::parse(AMPLIFIER).unwrap();
+        let results = puzzle
+            .verify(&program("mov p0 acc\nmul 2\nmov acc p1\nslp 1"))
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].failure, None);
+    }
+
+    #[test]
+    fn names_the_first_mismatch() {
+        let puzzle = Puzzle::parse(AMPLIFIER).unwrap();
+        let results = puzzle
+            .verify(&program("mov p0 acc\nadd 10\nmov acc p1\nslp 1"))
+            .unwrap();
+        assert_eq!(
+            results[0].failure,
+            Some(Failure::Mismatch {
+                timestep: 0,
+                port: String::from("speaker"),
+                expected: Some(0),
+                actual: Some(10),
+            })
+        );
+        assert_eq!(
+            results[0].failure.as_ref().unwrap().to_string(),
+            "at timestep 0: speaker expected 0, got 10"
+        );
+    }
+
+    #[test]
+    fn checks_xbus_outputs_in_order() {
+        let source = "\
+inputs = [\"radio\"]
+outputs = [\"out\"]
+[[chip]]
+name = \"relay\"
+model = \"mc4000\"
+[[wire]]
+connect = [\"radio\", \"relay.x0\"]
+[[wire]]
+connect = [\"relay.x1\", \"out\"]
+[[test]]
+name = \"packets\"
+radio = [[1, 2], [], [3]]
+out = [1, 2, 3, 4]
+";
+        let puzzle = Puzzle::parse(source).unwrap();
+        let results = puzzle.verify(&program("mov x0 x1")).unwrap();
+        assert_eq!(
+            results[0].failure,
+            Some(Failure::Mismatch {
+                timestep: 2,
+                port: String::from("out"),
+                expected: Some(4),
+                actual: None,
+            })
+        );
+    }
+}
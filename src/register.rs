@@ -2,7 +2,17 @@
     Definition for the registers to be used witin the machines.
 */
 
-#[derive(Debug)]
+/// The smallest value a register can hold.
+pub const MIN: i64 = -999;
+/// The largest value a register can hold.
+pub const MAX: i64 = 999;
+
+#[derive(Debug, Clone)]
 pub struct Register {
-    pub value: u64,
+    pub value: i64,
+}
+
+/// Pin a value into the range the game's registers can hold.
+pub fn clamp(value: i64) -> i64 {
+    value.clamp(MIN, MAX)
 }
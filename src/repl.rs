@@ -0,0 +1,277 @@
+/*
+    An interactive prompt for trying instructions one line at a time.
+*/
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use crate::chip::ChipModel;
+use crate::format;
+use crate::instruction::{Arg, Instruction};
+use crate::machine::{self, Flag, MAX_STEPS_PER_TIMESTEP};
+use crate::parse::{self, Line};
+use crate::register::Register;
+
+/// How many instructions one entered line may run, so that a `jmp` back to a
+/// label that loops forever gives the prompt back. A line never sleeps, so
+/// this is the same cap a chip gets in one timestep.
+const MAX_STEPS: usize = MAX_STEPS_PER_TIMESTEP;
+
+const HELP: &str = "\
+Type an instruction to run it, or a label such as `loop:` to mark the
+current position. A jmp runs the lines entered since its label again.
+
+Commands:
+    :show, :s       Show the flag and every register
+    :program, :p    Print the lines entered so far
+    :reset          Zero the registers and forget every line and label
+    :help, :h       Show this message
+    :quit, :q       Leave the REPL";
+
+/// Registers, flag and labels that persist between entered lines.
+pub struct Repl {
+    chip: ChipModel,
+    registers: HashMap<String, Register>,
+    labels: HashMap<Arg, usize>,
+    flag: Flag,
+    /// Every line entered so far, which is what a `jmp` replays.
+    lines: Vec<Line>,
+}
+
+impl Repl {
+    pub fn new(chip: ChipModel) -> Repl {
+        let mut repl = Repl {
+            chip,
+            registers: HashMap::new(),
+            labels: HashMap::new(),
+            flag: Flag::Minus,
+            lines: Vec::new(),
+        };
+        repl.reset();
+        repl
+    }
+
+    /// Go back to a fresh chip with no lines entered.
+    pub fn reset(&mut self) {
+        self.registers = self
+            .chip
+            .registers()
+            .iter()
+            .map(|name| (name.to_string(), Register { value: 0 }))
+            .collect();
+        self.labels.clear();
+        self.flag = Flag::Minus;
+        self.lines.clear();
+    }
+
+    pub fn register(&self, name: &str) -> Option<i64> {
+        self.registers.get(name).map(|r| r.value)
+    }
+
+    pub fn flag(&self) -> Flag {
+        self.flag
+    }
+
+    /// Run one line of source. Labels are remembered at the current
+    /// position, and a `jmp` to one runs everything entered after it again,
+    /// including the `jmp` itself if it is still taken. A line that keeps
+    /// jumping is stopped, not remembered, and leaves the registers and flag
+    /// as they were.
+    pub fn enter(&mut self, source: &str) -> Result<(), String> {
+        let entries = parse::entries(source)?;
+        if entries.is_empty() {
+            return Ok(());
+        }
+        for line in &entries {
+            for arg in &line.1 {
+                match arg {
+                    Arg::Register(name) if !self.registers.contains_key(name) => {
+                        return Err(format!(
+                            "{} has no register called {:?}",
+                            self.chip.name(),
+                            name
+                        ));
+                    }
+                    Arg::Label(name)
+                        if line.0 == Instruction::Jmp
+                            && !self.labels.contains_key(arg)
+                            && !entries.contains(&(Instruction::Label, vec![arg.clone()])) =>
+                    {
+                        return Err(format!("no label called {:?} yet", name));
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        let start = self.lines.len();
+        let replaced: Vec<_> = entries
+            .iter()
+            .filter(|line| line.0 == Instruction::Label)
+            .map(|line| (line.1[0].clone(), self.labels.get(&line.1[0]).copied()))
+            .collect();
+        for line in entries {
+            if line.0 == Instruction::Label {
+                self.labels.insert(line.1[0].clone(), self.lines.len());
+            }
+            self.lines.push(line);
+        }
+
+        let registers = self.registers.clone();
+        let flag = self.flag;
+        let mut pc = start;
+        let mut steps = 0;
+        while pc < self.lines.len() {
+            if steps == MAX_STEPS {
+                // Replaying it would only loop again.
+                self.lines.truncate(start);
+                for (label, old) in replaced {
+                    match old {
+                        Some(at) => self.labels.insert(label, at),
+                        None => self.labels.remove(&label),
+                    };
+                }
+                self.registers = registers;
+                self.flag = flag;
+                return Err(format!("stopped after {} instructions", MAX_STEPS));
+            }
+            let (next, flag) = machine::exec(
+                &self.lines[pc],
+                &mut self.registers,
+                &self.labels,
+                pc,
+                &self.flag,
+            );
+            pc = next;
+            self.flag = flag;
+            steps += 1;
+        }
+        Ok(())
+    }
+
+    /// The flag and every register in the order the chip lists them.
+    fn state(&self) -> Vec<(&'static str, String)> {
+        let mut state = vec![("flag", self.flag.to_string())];
+        for name in self.chip.registers() {
+            state.push((name, self.registers[*name].value.to_string()));
+        }
+        state
+    }
+
+    /// Read lines from `input` until `:quit` or end of input, reporting what
+    /// each one changed.
+    pub fn run(&mut self, mut input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        loop {
+            write!(output, "> ")?;
+            output.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let result = match line.trim() {
+                "" => Ok(()),
+                ":quit" | ":q" => return Ok(()),
+                ":help" | ":h" => writeln!(output, "{}", HELP),
+                ":show" | ":s" => {
+                    let state: Vec<String> = self
+                        .state()
+                        .iter()
+                        .map(|(name, value)| format!("{}={}", name, value))
+                        .collect();
+                    writeln!(output, "{}", state.join(" "))
+                }
+                ":program" | ":p" => write!(output, "{}", format::print(&self.lines)),
+                ":reset" => {
+                    self.reset();
+                    writeln!(output, "Reset")
+                }
+                command if command.starts_with(':') => {
+                    writeln!(output, "error: unknown command {:?}, try :help", command)
+                }
+                source => {
+                    let before = self.state();
+                    let entered = self.enter(source);
+                    let changes: Vec<String> = before
+                        .iter()
+                        .zip(self.state())
+                        .filter(|((_, old), (_, new))| *old != *new)
+                        .map(|((name, old), (_, new))| format!("{}: {} -> {}", name, old, new))
+                        .collect();
+                    if !changes.is_empty() {
+                        writeln!(output, "{}", changes.join(", "))?;
+                    }
+                    match entered {
+                        Ok(()) => Ok(()),
+                        Err(e) => writeln!(output, "error: {}", e),
+                    }
+                }
+            };
+            result?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Repl;
+    use crate::chip::ChipModel;
+    use crate::machine::Flag;
+
+    #[test]
+    fn keeps_state_between_lines() {
+        let mut repl = Repl::new(ChipModel::Mc6000);
+        repl.enter("mov 5 acc").unwrap();
+        repl.enter("add 37").unwrap();
+        assert_eq!(repl.register("acc"), Some(42));
+        repl.enter("tcp acc 42").unwrap();
+        assert_eq!(repl.flag(), Flag::Neither);
+        repl.enter("- add 1").unwrap();
+        repl.enter("+ add 1").unwrap();
+        assert_eq!(repl.register("acc"), Some(42));
+        repl.enter("dgt 1").unwrap();
+        assert_eq!(repl.register("acc"), Some(4));
+
+        assert!(repl.enter("mov 1 zz").is_err());
+        assert!(repl.enter("jmp nowhere").is_err());
+        assert!(Repl::new(ChipModel::Mc4000).enter("mov 1 dat").is_err());
+    }
+
+    #[test]
+    fn jumps_replay_lines_since_the_label() {
+        let mut repl = Repl::new(ChipModel::Mc6000);
+        for line in ["loop:", "add 1", "teq acc 5", "- jmp loop"] {
+            repl.enter(line).unwrap();
+        }
+        assert_eq!(repl.register("acc"), Some(5));
+        repl.enter("again: add 1").unwrap();
+        assert_eq!(repl.register("acc"), Some(6));
+        assert!(repl.enter("jmp loop").is_err());
+        assert_eq!(repl.register("acc"), Some(6));
+        assert_eq!(repl.flag(), Flag::Plus);
+        assert!(repl.enter("stuck: jmp stuck").is_err());
+        assert!(repl.enter("jmp stuck").is_err());
+        assert!(repl.enter("l: bogus").is_err());
+        repl.reset();
+        assert_eq!(repl.register("acc"), Some(0));
+        assert!(repl.enter("jmp loop").is_err());
+    }
+
+    #[test]
+    fn scripted_session() {
+        let mut repl = Repl::new(ChipModel::Mc6000);
+        let script = "mov 5 acc\ntgt acc 3\nnop\n:show\n:program\n:frob\n:q\nmov 1 acc\n";
+        let mut output = Vec::new();
+        repl.run(script.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("> acc: 0 -> 5\n"), "{}", output);
+        assert!(output.contains("> flag: - -> +\n"), "{}", output);
+        assert!(output.contains("> > flag=+ acc=5 dat=0 p0=0"), "{}", output);
+        assert!(output.contains("    tgt acc 3\n    nop\n"), "{}", output);
+        assert!(
+            output.contains("error: unknown command \":frob\""),
+            "{}",
+            output
+        );
+        assert!(!output.contains("acc: 5 -> 1"), "{}", output);
+    }
+}
@@ -0,0 +1,496 @@
+/*
+    Reading and writing solutions saved by the game.
+*/
+
+use std::fmt;
+
+use crate::board::Board;
+use crate::chip::ChipModel;
+use crate::machine::Machine;
+
+/// Columns in the board's trace grid.
+pub const WIDTH: usize = 22;
+/// Rows in the board's trace grid.
+pub const HEIGHT: usize = 14;
+
+/// Bits in a trace cell, one per side of the cell a trace leaves through.
+pub const UP: u8 = 1;
+pub const RIGHT: u8 = 2;
+pub const DOWN: u8 = 4;
+pub const LEFT: u8 = 8;
+
+/// Header fields the game scores a solution with when it last ran it.
+const SCORES: [&str; 3] = ["production-cost", "power-usage", "lines-of-code"];
+
+/// A part placed on the board, such as a microcontroller.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Component {
+    /// The game's name for the part, such as `UC6` for an MC6000.
+    pub kind: String,
+    pub x: usize,
+    pub y: usize,
+    pub code: Option<String>,
+    /// Fields this reader doesn't use, such as `is-puzzle-provided`, in order.
+    pub extra: Vec<(String, String)>,
+}
+
+impl Component {
+    /// The chip model of a programmable part.
+    pub fn model(&self) -> Option<ChipModel> {
+        match self.kind.as_str() {
+            "UC4" => Some(ChipModel::Mc4000),
+            "UC6" => Some(ChipModel::Mc6000),
+            _ => None,
+        }
+    }
+
+    /// The name the chip is given on a board built from the solution, such
+    /// as `mc6000_4_2` for an MC6000 at (4, 2).
+    pub fn chip_name(&self) -> Option<String> {
+        let model = self.model()?;
+        Some(format!(
+            "{}_{}_{}",
+            model.name().to_ascii_lowercase(),
+            self.x,
+            self.y
+        ))
+    }
+
+    /// The cells each of a chip's pins sits on, relative to its top left.
+    fn pins(&self) -> &'static [(&'static str, usize, usize)] {
+        match self.model() {
+            Some(ChipModel::Mc4000) => &[("p0", 0, 0), ("x0", 0, 1), ("p1", 2, 0), ("x1", 2, 1)],
+            Some(ChipModel::Mc6000) => &[
+                ("p0", 0, 0),
+                ("x0", 0, 1),
+                ("x1", 0, 2),
+                ("p1", 3, 0),
+                ("x2", 3, 1),
+                ("x3", 3, 2),
+            ],
+            None => &[],
+        }
+    }
+}
+
+/// A solution file as the game writes it:
+///
+/// ```text
+/// [name] Fast
+/// [puzzle] Sz000
+/// [production-cost] 600
+///
+/// [traces]
+/// ......................
+/// ...14.................
+///
+/// [chip]
+/// [type] UC6
+/// [x] 4
+/// [y] 2
+/// [code]
+///   mov p0 acc
+///   slp 1
+/// ```
+///
+/// Each trace row holds one hex digit per cell, made of the `UP`, `RIGHT`,
+/// `DOWN` and `LEFT` bits for the sides a trace leaves through, or `.` for
+/// an empty cell. Code is indented by two spaces.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Solution {
+    pub name: String,
+    pub puzzle: String,
+    /// Header fields this reader doesn't use, such as the scores.
+    pub extra: Vec<(String, String)>,
+    /// `traces[y][x]` holds the trace bits of each cell.
+    pub traces: Vec<Vec<u8>>,
+    pub components: Vec<Component>,
+}
+
+impl Default for Solution {
+    fn default() -> Solution {
+        Solution {
+            name: String::new(),
+            puzzle: String::new(),
+            extra: Vec::new(),
+            traces: vec![vec![0; WIDTH]; HEIGHT],
+            components: Vec::new(),
+        }
+    }
+}
+
+/// The part of the file the reader is in.
+enum Section {
+    Header,
+    Traces(usize),
+    Component,
+    Code,
+}
+
+impl Solution {
+    pub fn parse(source: &str) -> Result<Solution, String> {
+        let mut solution = Solution::default();
+        let mut section = Section::Header;
+        for (i, line) in source.lines().enumerate() {
+            let context = |e: String| format!("line {}: {}", i + 1, e);
+            let field = line.strip_prefix('[').and_then(|l| l.split_once(']'));
+            let (key, value) = match field {
+                Some((key, value)) => (key, value.trim()),
+                None => {
+                    match &mut section {
+                        Section::Code => {
+                            let code = solution.code();
+                            code.push_str(line.strip_prefix("  ").unwrap_or(line.trim_start()));
+                            code.push('\n');
+                        }
+                        Section::Traces(row) if !line.trim().is_empty() => {
+                            if *row >= HEIGHT {
+                                return Err(context(format!("more than {} trace rows", HEIGHT)));
+                            }
+                            solution.traces[*row] = trace_row(line.trim()).map_err(context)?;
+                            *row += 1;
+                        }
+                        _ if line.trim().is_empty() => (),
+                        _ => return Err(context(format!("expected [field], got {:?}", line))),
+                    }
+                    continue;
+                }
+            };
+
+            if let Section::Code = section {
+                solution.end_code();
+                section = Section::Component;
+            }
+            match (key, &section) {
+                ("traces", _) => section = Section::Traces(0),
+                ("chip", _) => {
+                    solution.components.push(Component {
+                        kind: String::new(),
+                        x: 0,
+                        y: 0,
+                        code: None,
+                        extra: Vec::new(),
+                    });
+                    section = Section::Component;
+                }
+                ("name", Section::Header) => solution.name = value.to_owned(),
+                ("puzzle", Section::Header) => solution.puzzle = value.to_owned(),
+                (_, Section::Header) => solution.extra.push((key.to_owned(), value.to_owned())),
+                (_, Section::Traces(_)) => {
+                    return Err(context(format!("[{}] inside the traces", key)));
+                }
+                (_, _) => {
+                    let component = solution.components.last_mut().unwrap();
+                    match key {
+                        "type" => component.kind = value.to_owned(),
+                        "x" => component.x = coordinate(value).map_err(context)?,
+                        "y" => component.y = coordinate(value).map_err(context)?,
+                        "code" => {
+                            component.code = Some(String::new());
+                            section = Section::Code;
+                        }
+                        _ => component.extra.push((key.to_owned(), value.to_owned())),
+                    }
+                }
+            }
+        }
+
+        if let Section::Code = section {
+            solution.end_code();
+        }
+        Ok(solution)
+    }
+
+    /// The code of the component being read.
+    fn code(&mut self) -> &mut String {
+        let component = self.components.last_mut().unwrap();
+        component.code.get_or_insert_with(String::new)
+    }
+
+    /// Drop the blank lines that separate code from the next component.
+    fn end_code(&mut self) {
+        let code = self.code();
+        code.truncate(code.trim_end().len());
+        if !code.is_empty() {
+            code.push('\n');
+        }
+    }
+
+    /// The chip a board built from the solution calls `name`.
+    pub fn chip_mut(&mut self, name: &str) -> Option<&mut Component> {
+        self.components
+            .iter_mut()
+            .find(|c| c.chip_name().as_deref() == Some(name))
+    }
+
+    /// Drop the scores the game recorded, which no longer hold once the code
+    /// has changed. The game works them out again when it next runs the
+    /// solution.
+    pub fn clear_scores(&mut self) {
+        self.extra.retain(|(key, _)| !SCORES.contains(&key.as_str()));
+    }
+
+    /// Parts the interpreter can't simulate, which are left off the board.
+    pub fn unsupported(&self) -> Vec<&Component> {
+        self.components
+            .iter()
+            .filter(|c| c.model().is_none())
+            .collect()
+    }
+
+    /// Groups of cells joined by traces, each as a list of `(x, y)` cells.
+    pub fn nets(&self) -> Vec<Vec<(usize, usize)>> {
+        let mut seen = vec![vec![false; WIDTH]; HEIGHT];
+        let mut nets = Vec::new();
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                if seen[y][x] || self.traces[y][x] == 0 {
+                    continue;
+                }
+                let mut net = Vec::new();
+                let mut stack = vec![(x, y)];
+                seen[y][x] = true;
+                while let Some((x, y)) = stack.pop() {
+                    net.push((x, y));
+                    for (nx, ny) in self.neighbours(x, y) {
+                        if !seen[ny][nx] {
+                            seen[ny][nx] = true;
+                            stack.push((nx, ny));
+                        }
+                    }
+                }
+                net.sort();
+                nets.push(net);
+            }
+        }
+        nets
+    }
+
+    /// Cells joined to (x, y) by a trace leaving either cell towards the
+    /// other.
+    fn neighbours(&self, x: usize, y: usize) -> Vec<(usize, usize)> {
+        let bits = |x: usize, y: usize| self.traces[y][x];
+        let mut cells = Vec::new();
+        if y > 0 && (bits(x, y) & UP != 0 || bits(x, y - 1) & DOWN != 0) {
+            cells.push((x, y - 1));
+        }
+        if x + 1 < WIDTH && (bits(x, y) & RIGHT != 0 || bits(x + 1, y) & LEFT != 0) {
+            cells.push((x + 1, y));
+        }
+        if y + 1 < HEIGHT && (bits(x, y) & DOWN != 0 || bits(x, y + 1) & UP != 0) {
+            cells.push((x, y + 1));
+        }
+        if x > 0 && (bits(x, y) & LEFT != 0 || bits(x - 1, y) & RIGHT != 0) {
+            cells.push((x - 1, y));
+        }
+        cells
+    }
+
+    /// Build a board with every chip in the solution, wiring together pins
+    /// that traces join.
+    pub fn board(&self) -> Result<Board, String> {
+        let mut board = Board::new();
+        let mut pins = Vec::new();
+        for component in &self.components {
+            let (model, name) = match (component.model(), component.chip_name()) {
+                (Some(model), Some(name)) => (model, name),
+                _ => continue,
+            };
+            let code = component.code.as_deref().unwrap_or("");
+            let machine =
+                Machine::load_chip(code, model).map_err(|e| format!("{}: {}", name, e))?;
+            board.add_chip(&name, machine);
+            for (pin, dx, dy) in component.pins() {
+                pins.push((
+                    format!("{}.{}", name, pin),
+                    component.x + dx,
+                    component.y + dy,
+                ));
+            }
+        }
+
+        for net in self.nets() {
+            let endpoints: Vec<&str> = pins
+                .iter()
+                .filter(|(_, x, y)| net.contains(&(*x, *y)))
+                .map(|(pin, _, _)| pin.as_str())
+                .collect();
+            if endpoints.len() > 1 {
+                board.connect(&endpoints)?;
+            }
+        }
+        Ok(board)
+    }
+}
+
+/// Writes the solution in the game's format, which `Solution::parse` reads
+/// back unchanged.
+impl fmt::Display for Solution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "[name] {}", self.name)?;
+        writeln!(f, "[puzzle] {}", self.puzzle)?;
+        for (key, value) in &self.extra {
+            writeln!(f, "[{}] {}", key, value)?;
+        }
+        writeln!(f)?;
+        writeln!(f, "[traces] ")?;
+        for row in &self.traces {
+            for bits in row {
+                match bits {
+                    0 => write!(f, ".")?,
+                    v => write!(f, "{:X}", v)?,
+                }
+            }
+            writeln!(f)?;
+        }
+        for component in &self.components {
+            writeln!(f)?;
+            writeln!(f, "[chip] ")?;
+            writeln!(f, "[type] {}", component.kind)?;
+            writeln!(f, "[x] {}", component.x)?;
+            writeln!(f, "[y] {}", component.y)?;
+            for (key, value) in &component.extra {
+                writeln!(f, "[{}] {}", key, value)?;
+            }
+            if let Some(code) = &component.code {
+                writeln!(f, "[code] ")?;
+                for line in code.lines() {
+                    writeln!(f, "  {}", line.trim_end())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn trace_row(line: &str) -> Result<Vec<u8>, String> {
+    if line.chars().count() != WIDTH {
+        return Err(format!("trace rows are {} cells wide", WIDTH));
+    }
+    line.chars()
+        .map(|c| match c {
+            '.' => Ok(0),
+            c => match c.to_digit(16) {
+                Some(v) => Ok(v as u8),
+                None => Err(format!("{:?} is not a trace cell", c)),
+            },
+        })
+        .collect()
+}
+
+fn coordinate(value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{:?} is not a board position", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HEIGHT, Solution};
+
+    fn save() -> String {
+        // Two chips, with a trace from the first's p1 to the second's p0.
+        let mut traces = vec![".".repeat(22); HEIGHT];
+        traces[1] = String::from("...2AAAA8.............");
+        format!(
+            "[name] Doubler\n\
+             [puzzle] Sz000\n\
+             [production-cost] 600\n\
+             \n\
+             [traces] \n\
+             {}\n\
+             \n\
+             [chip] \n\
+             [type] UC4\n\
+             [x] 1\n\
+             [y] 1\n\
+             [code] \n  \
+               mov p0 acc\n  \
+               mul 2\n  \
+               mov acc p1\n  \
+               slp 1\n\
+             \n\
+             [chip] \n\
+             [type] UC4\n\
+             [x] 8\n\
+             [y] 1\n\
+             [is-puzzle-provided] true\n\
+             [code] \n  \
+               mov p0 p1\n  \
+               slp 1\n\
+             \n\
+             [chip] \n\
+             [type] BridgeComponent\n\
+             [x] 15\n\
+             [y] 8\n",
+            traces.join("\n")
+        )
+    }
+
+    #[test]
+    fn reads_components_and_code() {
+        let solution = Solution::parse(&save()).unwrap();
+        assert_eq!(solution.name, "Doubler");
+        assert_eq!(solution.puzzle, "Sz000");
+        assert_eq!(
+            solution.extra,
+            vec![(String::from("production-cost"), String::from("600"))]
+        );
+        assert_eq!(solution.components.len(), 3);
+        let first = &solution.components[0];
+        assert_eq!((first.x, first.y), (1, 1));
+        assert_eq!(
+            first.code.as_deref(),
+            Some("mov p0 acc\nmul 2\nmov acc p1\nslp 1\n")
+        );
+        assert_eq!(first.chip_name().as_deref(), Some("mc4000_1_1"));
+        assert_eq!(
+            solution.components[1].extra,
+            vec![(String::from("is-puzzle-provided"), String::from("true"))]
+        );
+        assert_eq!(solution.unsupported().len(), 1);
+    }
+
+    #[test]
+    fn wires_chips_joined_by_traces() {
+        let solution = Solution::parse(&save()).unwrap();
+        assert_eq!(solution.nets().len(), 1);
+
+        let mut board = solution.board().unwrap();
+        board.connect(&["in", "mc4000_1_1.p0"]).unwrap();
+        board.connect(&["mc4000_8_1.p1", "out"]).unwrap();
+        board.set_input("in", 21).unwrap();
+        board.run_timestep().unwrap();
+        assert_eq!(board.output("out"), Ok(42));
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut solution = Solution::parse(&save()).unwrap();
+        assert_eq!(Solution::parse(&solution.to_string()), Ok(solution.clone()));
+
+        let chip = solution.chip_mut("mc4000_8_1").unwrap();
+        chip.code = Some(String::from("mov p0 acc\n\nmov acc p1\n"));
+        let written = solution.to_string();
+        assert!(written.contains("[code] \n  mov p0 acc\n  \n  mov acc p1\n"));
+        assert_eq!(Solution::parse(&written), Ok(solution));
+    }
+
+    #[test]
+    fn clears_scores() {
+        let mut solution = Solution::parse(&save()).unwrap();
+        solution.extra.push((String::from("flags"), String::from("0")));
+        solution.clear_scores();
+        assert_eq!(
+            solution.extra,
+            vec![(String::from("flags"), String::from("0"))]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        assert!(Solution::parse("[traces]\n....\n").is_err());
+        assert!(Solution::parse("[chip]\n[x] left\n").is_err());
+        assert!(Solution::parse("stray text\n").is_err());
+    }
+}
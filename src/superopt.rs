@@ -0,0 +1,506 @@
+/*
+    A superoptimizer: tries every straight-line program up to a length and
+    keeps the shortest, or the cheapest in power, that turns each case's
+    inputs into its expected outputs.
+*/
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::chip::ChipModel;
+use crate::instruction::{Arg, Instruction};
+use crate::machine::{self, Flag};
+use crate::parse::Line;
+use crate::register::{self, Register};
+use crate::toml;
+
+/// Longest program searched when the spec doesn't say.
+const DEFAULT_MAX_LINES: usize = 3;
+
+/// Literals the search uses when the spec doesn't list any.
+const DEFAULT_CONSTANTS: [i64; 2] = [0, 1];
+
+/// What to make as small as possible, with the other breaking ties.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Goal {
+    #[default]
+    Lines,
+    Power,
+}
+
+/// Register values before the program runs and the values expected after.
+#[derive(Debug, PartialEq)]
+pub struct Case {
+    pub input: Vec<i64>,
+    pub output: Vec<i64>,
+}
+
+/// What the program has to do, and how far to look for it.
+///
+/// ```toml
+/// chip = "mc4000"
+/// inputs = ["x0"]
+/// outputs = ["acc"]
+/// constants = [0, -1]
+/// max_lines = 3
+/// minimize = "lines"
+///
+/// [[case]]
+/// input = [5]
+/// output = [5]
+///
+/// [[case]]
+/// input = [-7]
+/// output = [7]
+/// ```
+///
+/// Each case gives a value for every input, in order, and the value every
+/// output must hold once the program ends. `minimize` is `lines` or
+/// `power`, the power being summed over the cases.
+#[derive(Debug, PartialEq)]
+pub struct Spec {
+    pub chip: ChipModel,
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+    /// Literals the program may use as operands.
+    pub constants: Vec<i64>,
+    pub max_lines: usize,
+    pub goal: Goal,
+    pub cases: Vec<Case>,
+}
+
+/// The best program found.
+#[derive(Debug, PartialEq)]
+pub struct Solution {
+    pub program: Vec<Line>,
+    /// Power used over all the cases.
+    pub power: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Search {
+    pub best: Option<Solution>,
+    /// How many programs were run.
+    pub tried: usize,
+    /// Whether the search finished rather than running out of time, so
+    /// nothing better exists within `max_lines`.
+    pub complete: bool,
+}
+
+/// One case partway through a program.
+#[derive(Debug, Clone)]
+struct State {
+    registers: HashMap<String, Register>,
+    flag: Flag,
+    power: usize,
+}
+
+impl Spec {
+    pub fn parse(source: &str) -> Result<Spec, String> {
+        let mut spec = Spec {
+            chip: ChipModel::default(),
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            constants: DEFAULT_CONSTANTS.to_vec(),
+            max_lines: DEFAULT_MAX_LINES,
+            goal: Goal::default(),
+            cases: Vec::new(),
+        };
+        for table in toml::parse(source)? {
+            match table.name.as_str() {
+                "" => {
+                    for (key, value) in &table.entries {
+                        let context = |e: String| format!("{}: {}", key, e);
+                        match key.as_str() {
+                            "chip" => spec.chip = value.as_str().map_err(context)?.parse()?,
+                            "inputs" => spec.inputs = value.as_strings().map_err(context)?,
+                            "outputs" => spec.outputs = value.as_strings().map_err(context)?,
+                            "constants" => spec.constants = value.as_integers().map_err(context)?,
+                            "max_lines" => {
+                                let v = value.as_integer().map_err(context)?;
+                                spec.max_lines = usize::try_from(v)
+                                    .map_err(|_| context(format!("{} is negative", v)))?;
+                            }
+                            "minimize" => {
+                                spec.goal = match value.as_str().map_err(context)? {
+                                    "lines" => Goal::Lines,
+                                    "power" => Goal::Power,
+                                    other => {
+                                        return Err(context(format!(
+                                            "expected \"lines\" or \"power\", found {:?}",
+                                            other
+                                        )));
+                                    }
+                                }
+                            }
+                            _ => return Err(format!("unknown setting {:?}", key)),
+                        }
+                    }
+                }
+                "case" => {
+                    let n = spec.cases.len() + 1;
+                    let values = |key: &str| match table.get(key) {
+                        Some(v) => v
+                            .as_integers()
+                            .map_err(|e| format!("case {}: {}: {}", n, key, e)),
+                        None => Ok(Vec::new()),
+                    };
+                    spec.cases.push(Case {
+                        input: values("input")?,
+                        output: values("output")?,
+                    });
+                }
+                other => return Err(format!("unknown section [{}]", other)),
+            }
+        }
+        spec.check()?;
+        Ok(spec)
+    }
+
+    /// Make sure the registers exist and every case fits them.
+    pub fn check(&self) -> Result<(), String> {
+        for name in self.inputs.iter().chain(&self.outputs) {
+            if !self.chip.registers().contains(&name.as_str()) {
+                return Err(format!(
+                    "{} has no register called {:?}",
+                    self.chip.name(),
+                    name
+                ));
+            }
+        }
+        if self.outputs.is_empty() {
+            return Err(String::from("no outputs to check"));
+        }
+        if self.cases.is_empty() {
+            return Err(String::from("no cases to check"));
+        }
+        for (i, case) in self.cases.iter().enumerate() {
+            if case.input.len() != self.inputs.len() || case.output.len() != self.outputs.len() {
+                return Err(format!(
+                    "case {}: expected {} input(s) and {} output(s), found {} and {}",
+                    i + 1,
+                    self.inputs.len(),
+                    self.outputs.len(),
+                    case.input.len(),
+                    case.output.len()
+                ));
+            }
+        }
+        let values = self
+            .cases
+            .iter()
+            .flat_map(|c| c.input.iter().chain(&c.output))
+            .chain(&self.constants);
+        for value in values {
+            if !(register::MIN..=register::MAX).contains(value) {
+                return Err(format!(
+                    "{} is outside the range {} to {}",
+                    value,
+                    register::MIN,
+                    register::MAX
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Try programs until the best one is certain or `timeout` passes.
+    ///
+    /// Programs are made of every instruction except `jmp`, `slp` and
+    /// `nop`, over `acc`, `dat`, the inputs, the outputs and the constants,
+    /// with `+` and `-` lines after a test. Programs that only reach a state
+    /// some other program already reached with no more lines and power are
+    /// not taken any further.
+    pub fn search(&self, timeout: Option<Duration>) -> Search {
+        let mut searcher = Searcher {
+            spec: self,
+            alphabet: self.alphabet(),
+            deadline: timeout.map(|t| Instant::now() + t),
+            seen: HashMap::new(),
+            path: Vec::new(),
+            best: None,
+            tried: 0,
+            timed_out: false,
+        };
+        let start: Vec<State> = self.cases.iter().map(|c| self.initial(c)).collect();
+        match self.goal {
+            // The shortest program is found by searching each length in turn.
+            Goal::Lines => {
+                for limit in 0..=self.max_lines {
+                    searcher.seen.clear();
+                    searcher.visit(&start, false, limit);
+                    if searcher.best.is_some() || searcher.timed_out {
+                        break;
+                    }
+                }
+            }
+            Goal::Power => searcher.visit(&start, false, self.max_lines),
+        }
+        Search {
+            best: searcher.best,
+            tried: searcher.tried,
+            complete: !searcher.timed_out,
+        }
+    }
+
+    /// Registers the program works with, in the chip's order.
+    fn registers(&self) -> Vec<&'static str> {
+        self.chip
+            .registers()
+            .iter()
+            .copied()
+            .filter(|r| {
+                matches!(*r, "acc" | "dat")
+                    || self.inputs.iter().chain(&self.outputs).any(|n| n == r)
+            })
+            .collect()
+    }
+
+    /// Every line a program may be made of, without `+` or `-`.
+    fn alphabet(&self) -> Vec<Line> {
+        let registers = self.registers();
+        let writable: Vec<Arg> = registers
+            .iter()
+            .filter(|r| {
+                !self.inputs.iter().any(|n| n == *r) || self.outputs.iter().any(|n| n == *r)
+            })
+            .map(|r| Arg::Register(r.to_string()))
+            .collect();
+        let operands: Vec<Arg> = registers
+            .iter()
+            .map(|r| Arg::Register(r.to_string()))
+            .chain(self.constants.iter().map(|n| Arg::Number(*n)))
+            .collect();
+
+        let mut lines = vec![(Instruction::Not, Vec::new())];
+        for a in &operands {
+            for to in writable.iter().filter(|to| *to != a) {
+                lines.push((Instruction::Mov, vec![a.clone(), to.clone()]));
+            }
+            for instr in [
+                Instruction::Add,
+                Instruction::Sub,
+                Instruction::Mul,
+                Instruction::Dgt,
+            ] {
+                lines.push((instr, vec![a.clone()]));
+            }
+        }
+        for a in &operands {
+            for b in &operands {
+                lines.push((Instruction::Dst, vec![a.clone(), b.clone()]));
+                let literals = matches!((a, b), (Arg::Number(_), Arg::Number(_)));
+                if a == b || literals {
+                    continue;
+                }
+                for instr in [
+                    Instruction::Teq,
+                    Instruction::Tgt,
+                    Instruction::Tlt,
+                    Instruction::Tcp,
+                ] {
+                    lines.push((instr, vec![a.clone(), b.clone()]));
+                }
+            }
+        }
+        lines
+    }
+
+    fn initial(&self, case: &Case) -> State {
+        let mut registers: HashMap<String, Register> = self
+            .chip
+            .registers()
+            .iter()
+            .map(|name| (name.to_string(), Register { value: 0 }))
+            .collect();
+        for (name, value) in self.inputs.iter().zip(&case.input) {
+            registers.insert(name.to_owned(), Register { value: *value });
+        }
+        State {
+            registers,
+            flag: Flag::Minus,
+            power: 0,
+        }
+    }
+}
+
+/// The registers and flag of every case, which is all that decides what
+/// the rest of a program does.
+type Key = Vec<(Vec<i64>, Flag)>;
+
+/// What a search has found so far.
+struct Searcher<'a> {
+    spec: &'a Spec,
+    alphabet: Vec<Line>,
+    deadline: Option<Instant>,
+    /// The fewest lines and least power each state has been reached with.
+    seen: HashMap<Key, (usize, usize)>,
+    path: Vec<Line>,
+    best: Option<Solution>,
+    tried: usize,
+    timed_out: bool,
+}
+
+impl Searcher<'_> {
+    /// Try every way of adding lines to `path`, whose cases ended in
+    /// `states`, up to `limit` lines.
+    fn visit(&mut self, states: &[State], tested: bool, limit: usize) {
+        let depth = self.path.len();
+        let power = states.iter().map(|s| s.power).sum();
+        // Lines and power only grow, so a program no better than the best
+        // can't lead to one that is.
+        if let Some(best) = &self.best {
+            let (ours, theirs) = match self.spec.goal {
+                Goal::Lines => ((depth, power), (best.program.len(), best.power)),
+                Goal::Power => ((power, depth), (best.power, best.program.len())),
+            };
+            if ours >= theirs {
+                return;
+            }
+        }
+        if self.solves(states) {
+            self.best = Some(Solution {
+                program: self.path.clone(),
+                power,
+            });
+            return;
+        }
+        if depth == limit || self.timed_out {
+            return;
+        }
+        if self.deadline.is_some_and(|d| Instant::now() >= d) {
+            self.timed_out = true;
+            return;
+        }
+        let key = self.key(states);
+        if let Some(&(lines, least)) = self.seen.get(&key)
+            && lines <= depth
+            && least <= power
+        {
+            return;
+        }
+        self.seen.insert(key, (depth, power));
+
+        // Before any test the flag is always -, so + and - add nothing.
+        let conditions: &[Option<Arg>] = if tested {
+            &[None, Some(Arg::BranchTrue), Some(Arg::BranchFalse)]
+        } else {
+            &[None]
+        };
+        for i in 0..self.alphabet.len() {
+            for condition in conditions {
+                let mut line = self.alphabet[i].clone();
+                line.1.extend(condition.clone());
+                let next: Vec<State> = states.iter().map(|s| step(s, &line)).collect();
+                self.tried += 1;
+                let test = line.0.is_test();
+                self.path.push(line);
+                self.visit(&next, tested || test, limit);
+                self.path.pop();
+            }
+        }
+    }
+
+    fn solves(&self, states: &[State]) -> bool {
+        states.iter().zip(&self.spec.cases).all(|(state, case)| {
+            self.spec
+                .outputs
+                .iter()
+                .zip(&case.output)
+                .all(|(name, expected)| state.registers[name].value == *expected)
+        })
+    }
+
+    fn key(&self, states: &[State]) -> Key {
+        let registers = self.spec.registers();
+        states
+            .iter()
+            .map(|s| {
+                let values = registers.iter().map(|r| s.registers[*r].value).collect();
+                (values, s.flag)
+            })
+            .collect()
+    }
+}
+
+/// Run one more line of a straight-line program.
+fn step(state: &State, line: &Line) -> State {
+    let mut next = state.clone();
+    if state.flag.runs(&line.1) {
+        next.power += 1;
+    }
+    let (_, flag) = machine::exec(line, &mut next.registers, &HashMap::new(), 0, &state.flag);
+    next.flag = flag;
+    next
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Goal, Spec};
+    use crate::format;
+    use crate::machine::Machine;
+
+    const ABS: &str = "\
+chip = \"mc4000\"
+inputs = [\"x0\"]
+outputs = [\"acc\"]
+constants = [0, -1]
+
+[[case]]
+input = [5]
+output = [5]
+
+[[case]]
+input = [-7]
+output = [7]
+
+[[case]]
+input = [0]
+output = [0]
+";
+
+    #[test]
+    fn finds_the_shortest_program() {
+        let spec = Spec::parse(ABS).unwrap();
+        let search = spec.search(None);
+        assert!(search.complete);
+        let best = search.best.unwrap();
+        assert_eq!(best.program.len(), 3);
+
+        // Whatever it found has to pass every case in the interpreter.
+        let source = format::print(&best.program);
+        let mut power = 0;
+        for case in &spec.cases {
+            let mut machine = Machine::load_chip(&source, spec.chip).unwrap();
+            machine.set_register("x0", case.input[0]).unwrap();
+            machine.run();
+            assert_eq!(machine.register("acc"), Some(case.output[0]), "{}", source);
+            power += machine.power();
+        }
+        assert_eq!(power, best.power);
+    }
+
+    #[test]
+    fn minimizes_power() {
+        let source = ABS.replace("constants", "minimize = \"power\"\nconstants");
+        let spec = Spec::parse(&source).unwrap();
+        assert_eq!(spec.goal, Goal::Power);
+        let best = spec.search(None).best.unwrap();
+        // tcp acc x0, then - mov x0 acc and + sub x0: a 0 leaves neither
+        // set, so that case only pays for the test.
+        assert_eq!(best.power, 5, "{}", format::print(&best.program));
+    }
+
+    #[test]
+    fn reports_impossible_specs() {
+        let source = ABS.replace("constants = [0, -1]", "max_lines = 2");
+        let search = Spec::parse(&source).unwrap().search(None);
+        assert!(search.complete);
+        assert_eq!(search.best, None);
+
+        assert!(Spec::parse("outputs = [\"x9\"]\n[[case]]\noutput = [1]").is_err());
+        assert!(Spec::parse("outputs = [\"acc\"]\n[[case]]\ninput = [1]\noutput = [1]").is_err());
+        assert!(Spec::parse("outputs = [\"acc\"]\n[[case]]\noutput = [1000]").is_err());
+        assert!(Spec::parse("outputs = [\"acc\"]").is_err());
+    }
+}
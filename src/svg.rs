@@ -0,0 +1,288 @@
+/*
+    SVG timing diagrams in the style of the game's verification panel.
+*/
+
+use std::fmt::Write;
+
+use crate::vcd::Scope;
+
+const MARGIN: usize = 10;
+const LABEL_WIDTH: usize = 70;
+const STEP_WIDTH: usize = 16;
+const ROW_HEIGHT: usize = 40;
+const ROW_GAP: usize = 10;
+/// Space above the rows for the timestep ruler.
+const RULER_HEIGHT: usize = 20;
+/// Space between a row's edge and its highest or lowest value.
+const PADDING: usize = 5;
+
+const BACKGROUND: &str = "#1d1f21";
+const ROW_BACKGROUND: &str = "#2b2e31";
+const TEXT: &str = "#c5c8c6";
+const INPUT: &str = "#81a2be";
+const EXPECTED: &str = "#5f8f5f";
+const ACTUAL: &str = "#f0c674";
+const MISMATCH: &str = "#cc6666";
+
+/// One stacked graph. Inputs show a single waveform; outputs show the
+/// expected waveform with the actual one drawn over it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row {
+    pub name: String,
+    pub actual: Vec<i64>,
+    pub expected: Option<Vec<i64>>,
+}
+
+impl Row {
+    /// Timesteps at which the actual value differs from the expected one.
+    pub fn mismatches(&self) -> Vec<usize> {
+        match &self.expected {
+            Some(expected) => expected
+                .iter()
+                .enumerate()
+                .filter(|(t, v)| self.actual.get(*t) != Some(*v))
+                .map(|(t, _)| t)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Build the rows for a recorded run: one per input, then one per expected
+/// output. With no expected outputs, every pin that isn't an input is shown
+/// instead.
+pub fn rows(
+    scope: &Scope,
+    inputs: &[(String, Vec<i64>)],
+    expected: &[(String, Vec<i64>)],
+    timesteps: usize,
+) -> Vec<Row> {
+    let sample = |name: &str| -> Vec<i64> {
+        match scope.signal(name) {
+            Some(signal) => (0..timesteps)
+                .map(|t| signal.value_at(t).unwrap_or(0))
+                .collect(),
+            None => Vec::new(),
+        }
+    };
+
+    // Inputs are drawn from their values rather than the recording, which
+    // only samples after a step and so misses changes while the chip sleeps.
+    let mut rows: Vec<Row> = inputs
+        .iter()
+        .map(|(name, values)| Row {
+            name: name.to_owned(),
+            actual: (0..timesteps)
+                .map(|t| values.get(t).or(values.last()).copied().unwrap_or(0))
+                .collect(),
+            expected: None,
+        })
+        .collect();
+    if expected.is_empty() {
+        for signal in &scope.signals {
+            if signal.name.starts_with('p') && !inputs.iter().any(|(n, _)| *n == signal.name) {
+                rows.push(Row {
+                    name: signal.name.to_owned(),
+                    actual: sample(&signal.name),
+                    expected: None,
+                });
+            }
+        }
+    }
+    for (name, values) in expected {
+        rows.push(Row {
+            name: name.to_owned(),
+            actual: sample(name),
+            expected: Some(values.to_owned()),
+        });
+    }
+    rows
+}
+
+/// Draw `rows` over `timesteps` timesteps.
+pub fn render(rows: &[Row], timesteps: usize) -> String {
+    let width = MARGIN * 2 + LABEL_WIDTH + STEP_WIDTH * timesteps.max(1);
+    let height = MARGIN * 2 + RULER_HEIGHT + (ROW_HEIGHT + ROW_GAP) * rows.len();
+    let left = MARGIN + LABEL_WIDTH;
+
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" \
+         viewBox=\"0 0 {w} {h}\" font-family=\"monospace\" font-size=\"11\">",
+        w = width,
+        h = height
+    );
+    let _ = writeln!(
+        svg,
+        "<rect width=\"{}\" height=\"{}\" fill=\"{}\"/>",
+        width, height, BACKGROUND
+    );
+
+    // Timestep ruler.
+    for t in (0..timesteps).step_by(5) {
+        let _ = writeln!(
+            svg,
+            "<text x=\"{}\" y=\"{}\" fill=\"{}\">{}</text>",
+            left + t * STEP_WIDTH,
+            MARGIN + RULER_HEIGHT - 6,
+            TEXT,
+            t
+        );
+    }
+
+    for (i, row) in rows.iter().enumerate() {
+        let top = MARGIN + RULER_HEIGHT + i * (ROW_HEIGHT + ROW_GAP);
+        let _ = writeln!(
+            svg,
+            "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{}\"/>",
+            left,
+            top,
+            STEP_WIDTH * timesteps,
+            ROW_HEIGHT,
+            ROW_BACKGROUND
+        );
+        for t in row.mismatches() {
+            let _ = writeln!(
+                svg,
+                "<rect class=\"mismatch\" x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" \
+                 fill=\"{}\" fill-opacity=\"0.5\"/>",
+                left + t * STEP_WIDTH,
+                top,
+                STEP_WIDTH,
+                ROW_HEIGHT,
+                MISMATCH
+            );
+        }
+        let _ = writeln!(
+            svg,
+            "<text x=\"{}\" y=\"{}\" fill=\"{}\">{}</text>",
+            MARGIN,
+            top + ROW_HEIGHT / 2 + 4,
+            TEXT,
+            escape(&row.name)
+        );
+
+        let all = row.actual.iter().chain(row.expected.iter().flatten());
+        let (low, high) = all.fold((i64::MAX, i64::MIN), |(lo, hi), v| (lo.min(*v), hi.max(*v)));
+        // A row with no samples gets the range 0 to 1.
+        let (low, high) = if low > high {
+            (0, 1)
+        } else if low == high {
+            (low - 1, low + 1)
+        } else {
+            (low, high)
+        };
+        let scale = |v: i64| {
+            let usable = (ROW_HEIGHT - 2 * PADDING) as f64;
+            top as f64 + PADDING as f64 + (high - v) as f64 / (high - low) as f64 * usable
+        };
+
+        match &row.expected {
+            Some(expected) => {
+                svg.push_str(&waveform(expected, timesteps, left, &scale, EXPECTED, 4));
+                svg.push_str(&waveform(&row.actual, timesteps, left, &scale, ACTUAL, 1));
+            }
+            None => svg.push_str(&waveform(&row.actual, timesteps, left, &scale, INPUT, 2)),
+        }
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// A step graph of `values`, holding each one for a whole timestep.
+fn waveform(
+    values: &[i64],
+    timesteps: usize,
+    left: usize,
+    scale: &impl Fn(i64) -> f64,
+    colour: &str,
+    stroke: usize,
+) -> String {
+    let mut points = Vec::new();
+    for (t, v) in values.iter().take(timesteps).enumerate() {
+        let y = scale(*v);
+        points.push(format!("{},{:.1}", left + t * STEP_WIDTH, y));
+        points.push(format!("{},{:.1}", left + (t + 1) * STEP_WIDTH, y));
+    }
+    format!(
+        "<polyline points=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"{}\"/>\n",
+        points.join(" "),
+        colour,
+        stroke
+    )
+}
+
+/// `text` with the characters XML gives a meaning escaped.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Row, render, rows};
+    use crate::machine::Machine;
+    use crate::vcd::Recorder;
+
+    #[test]
+    fn highlights_mismatches() {
+        let rows = vec![
+            Row {
+                name: String::from("p0"),
+                actual: vec![0, 50, 100],
+                expected: None,
+            },
+            Row {
+                name: String::from("p1"),
+                actual: vec![0, 0, 100],
+                expected: Some(vec![0, 100, 100]),
+            },
+        ];
+        assert_eq!(rows[1].mismatches(), vec![1]);
+
+        let svg = render(&rows, 3);
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.trim_end().ends_with("</svg>"));
+        assert_eq!(svg.matches("class=\"mismatch\"").count(), 1);
+        assert_eq!(svg.matches("<polyline").count(), 3);
+    }
+
+    #[test]
+    fn draws_inputs_that_change_during_sleep() {
+        let inputs = vec![(String::from("p0"), vec![0, 10, 20, 30, 40])];
+        let mut machine = Machine::load("slp 3\nslp 2").unwrap();
+        machine.set_input("p0", inputs[0].1.clone()).unwrap();
+        let mut recorder = Recorder::new("chip", &machine);
+        while machine.step() {
+            recorder.record(&machine);
+        }
+        let rows = rows(&recorder.finish(), &inputs, &[], 6);
+        assert_eq!(rows[0].name, "p0");
+        assert_eq!(rows[0].actual, vec![0, 10, 20, 30, 40, 40]);
+    }
+
+    #[test]
+    fn draws_rows_without_samples() {
+        let rows = vec![Row {
+            name: String::from("p1"),
+            actual: Vec::new(),
+            expected: Some(Vec::new()),
+        }];
+        let svg = render(&rows, 0);
+        assert!(svg.trim_end().ends_with("</svg>"));
+    }
+
+    #[test]
+    fn escapes_row_names() {
+        let rows = vec![Row {
+            name: String::from("a<b>&c"),
+            actual: vec![1],
+            expected: None,
+        }];
+        let svg = render(&rows, 1);
+        assert!(svg.contains(">a&lt;b&gt;&amp;c</text>"));
+    }
+}
@@ -0,0 +1,277 @@
+/*
+    A reader for the small subset of TOML used by configuration files.
+*/
+
+/// A value on the right hand side of `key = value`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Value {
+    Integer(i64),
+    String(String),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+/// A `[name]` header and the `key = value` lines under it. Keys before the
+/// first header belong to a table with an empty name. Each `[[name]]` header
+/// starts another table called `name`, for arrays of tables.
+#[derive(Debug, PartialEq)]
+pub struct Table {
+    pub name: String,
+    pub entries: Vec<(String, Value)>,
+}
+
+impl Table {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.entries.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+}
+
+impl Value {
+    pub fn as_integer(&self) -> Result<i64, String> {
+        match self {
+            Value::Integer(i) => Ok(*i),
+            v => Err(format!("expected an integer, found {:?}", v)),
+        }
+    }
+
+    pub fn as_str(&self) -> Result<&str, String> {
+        match self {
+            Value::String(s) => Ok(s),
+            v => Err(format!("expected a string, found {:?}", v)),
+        }
+    }
+
+    /// An array of integers, or a single integer as an array of one.
+    pub fn as_integers(&self) -> Result<Vec<i64>, String> {
+        match self {
+            Value::Array(values) => values.iter().map(|v| v.as_integer()).collect(),
+            v => Ok(vec![v.as_integer()?]),
+        }
+    }
+
+    /// An array of strings, or a single string as an array of one.
+    pub fn as_strings(&self) -> Result<Vec<String>, String> {
+        match self {
+            Value::Array(values) => values
+                .iter()
+                .map(|v| v.as_str().map(|s| s.to_owned()))
+                .collect(),
+            v => Ok(vec![v.as_str()?.to_owned()]),
+        }
+    }
+}
+
+/// Split a document into its tables, in the order they appear.
+///
+/// Supports comments, `[table]` and `[[table]]` headers, integers, booleans, basic strings
+/// and arrays, which may be spread over several lines.
+pub fn parse(source: &str) -> Result<Vec<Table>, String> {
+    let mut tables = vec![Table {
+        name: String::new(),
+        entries: Vec::new(),
+    }];
+
+    let mut pending = String::new();
+    let mut start = 0;
+    for (i, line) in source.lines().enumerate() {
+        let line = strip_comment(line).trim();
+        if pending.is_empty() {
+            start = i + 1;
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[') {
+                let name = match name.strip_suffix(']') {
+                    Some(v) => v.trim(),
+                    None => return Err(format!("line {}: unclosed table header", start)),
+                };
+                let name = match name.strip_prefix('[') {
+                    Some(inner) => match inner.strip_suffix(']') {
+                        Some(v) => v.trim(),
+                        None => return Err(format!("line {}: unclosed table header", start)),
+                    },
+                    None => name,
+                };
+                tables.push(Table {
+                    name: name.to_owned(),
+                    entries: Vec::new(),
+                });
+                continue;
+            }
+        }
+
+        // Arrays may continue over several lines until their brackets balance.
+        pending.push_str(line);
+        pending.push(' ');
+        if depth(&pending) > 0 {
+            continue;
+        }
+
+        let (key, value) = match pending.split_once('=') {
+            Some((k, v)) => (k.trim(), v.trim()),
+            None => return Err(format!("line {}: expected key = value", start)),
+        };
+        let key = key.trim_matches('"').to_owned();
+        let value = parse_value(value).map_err(|e| format!("line {}: {}", start, e))?;
+        let table = tables.last_mut().unwrap();
+        if table.get(&key).is_some() {
+            return Err(format!("line {}: {} is set twice", start, key));
+        }
+        table.entries.push((key, value));
+        pending.clear();
+    }
+
+    if !pending.is_empty() {
+        return Err(format!("line {}: unclosed array", start));
+    }
+    Ok(tables)
+}
+
+/// Remove a trailing `# comment`, leaving `#` inside strings alone.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => (),
+        }
+    }
+    line
+}
+
+fn parse_value(text: &str) -> Result<Value, String> {
+    let text = text.trim();
+    if let Some(inner) = text.strip_prefix('[') {
+        let inner = match inner.strip_suffix(']') {
+            Some(v) => v,
+            None => return Err(format!("malformed array {:?}", text)),
+        };
+        let mut values = Vec::new();
+        for item in split_items(inner) {
+            let item = item.trim();
+            if !item.is_empty() {
+                values.push(parse_value(item)?);
+            }
+        }
+        return Ok(Value::Array(values));
+    }
+    if let Some(inner) = text.strip_prefix('"') {
+        return match inner.strip_suffix('"') {
+            Some(v) => Ok(Value::String(v.to_owned())),
+            None => Err(format!("unterminated string {:?}", text)),
+        };
+    }
+    match text {
+        "true" => return Ok(Value::Boolean(true)),
+        "false" => return Ok(Value::Boolean(false)),
+        _ => (),
+    }
+    match text.replace('_', "").parse::<i64>() {
+        Ok(v) => Ok(Value::Integer(v)),
+        Err(_) => Err(format!("unrecognised value {:?}", text)),
+    }
+}
+
+/// How many more `[` than `]` there are outside strings.
+fn depth(text: &str) -> i64 {
+    let mut depth = 0;
+    let mut in_string = false;
+    for c in text.chars() {
+        match c {
+            '"' => in_string = !in_string,
+            '[' if !in_string => depth += 1,
+            ']' if !in_string => depth -= 1,
+            _ => (),
+        }
+    }
+    depth
+}
+
+/// Split the inside of an array on the commas that are not inside a nested
+/// array or a string.
+fn split_items(inner: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut start = 0;
+    for (i, c) in inner.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '[' if !in_string => depth += 1,
+            ']' if !in_string => depth -= 1,
+            ',' if !in_string && depth == 0 => {
+                items.push(&inner[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    items.push(&inner[start..]);
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Value, parse};
+
+    #[test]
+    fn parses_tables_and_values() {
+        let source = "\
+chip = \"mc4000\" # trailing comment
+
+[registers]
+acc = -5
+p0 = 100
+
+[inputs]
+p0 = [0, 50,
+      100]
+";
+        let tables = parse(source).unwrap();
+        assert_eq!(tables.len(), 3);
+        assert_eq!(
+            tables[0].get("chip"),
+            Some(&Value::String(String::from("mc4000")))
+        );
+        assert_eq!(tables[1].name, "registers");
+        assert_eq!(tables[1].get("acc"), Some(&Value::Integer(-5)));
+        assert_eq!(
+            tables[2].get("p0").unwrap().as_integers(),
+            Ok(vec![0, 50, 100])
+        );
+    }
+
+    #[test]
+    fn arrays_of_tables() {
+        let tables = parse("[[chip]]\nname = \"a\"\n[[chip]]\nname = \"b\"\n").unwrap();
+        let names: Vec<&str> = tables.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["", "chip", "chip"]);
+        assert_eq!(tables[2].get("name").unwrap().as_str(), Ok("b"));
+        assert!(parse("[[chip]\n").is_err());
+    }
+
+    #[test]
+    fn reports_the_failing_line() {
+        let err = parse("a = 1\nb =\n").unwrap_err();
+        assert!(err.starts_with("line 2"), "{}", err);
+        assert!(parse("[broken\n").is_err());
+        assert!(parse("a = [1, 2\n").is_err());
+    }
+
+    #[test]
+    fn brackets_in_strings() {
+        let tables = parse("chip = \"mc[4000\"\nnames = [\"a]\", \"b\"]\n").unwrap();
+        assert_eq!(tables[0].get("chip").unwrap().as_str(), Ok("mc[4000"));
+        assert_eq!(
+            tables[0].get("names").unwrap().as_strings(),
+            Ok(vec![String::from("a]"), String::from("b")])
+        );
+    }
+
+    #[test]
+    fn rejects_duplicate_keys() {
+        let err = parse("[registers]\nacc = 1\nacc = 2\n").unwrap_err();
+        assert_eq!(err, "line 3: acc is set twice");
+    }
+}
@@ -0,0 +1,159 @@
+/*
+    Machine readable execution traces.
+*/
+
+use std::io::{self, Write};
+
+use crate::format;
+use crate::instruction::Instruction;
+use crate::json::Json;
+use crate::machine::Machine;
+
+/// What the machine looked like before the step being traced.
+struct Before {
+    pc: usize,
+    timestep: usize,
+    power: usize,
+    registers: Vec<(&'static str, i64)>,
+}
+
+impl Before {
+    fn capture(machine: &Machine) -> Before {
+        Before {
+            pc: machine.pc(),
+            timestep: machine.next_timestep(),
+            power: machine.power(),
+            registers: machine
+                .chip()
+                .registers()
+                .iter()
+                .map(|name| (*name, machine.register(name).unwrap()))
+                .collect(),
+        }
+    }
+}
+
+/// Writes one JSON object per executed step, one per line:
+///
+/// ```json
+/// {"step":3,"timestep":0,"chip":"amp","model":"MC6000","pc":2,"line":3,
+///  "instruction":"loop:","flag":"-","skipped":false,"changed":{}}
+/// ```
+///
+/// `chip` is the name the chip goes by on its board, or null if it wasn't
+/// given one with `named`. `changed` holds only the registers and ports whose value the step
+/// changed, and `skipped` is set for `+`/`-` lines that did not run.
+pub struct JsonTrace<W: Write> {
+    out: W,
+    name: Option<String>,
+    source: Vec<String>,
+    before: Before,
+}
+
+impl<W: Write> JsonTrace<W> {
+    /// Start tracing `machine`, which was loaded from `source`. Call
+    /// `record` after every step.
+    pub fn new(out: W, machine: &Machine, source: &str) -> JsonTrace<W> {
+        JsonTrace {
+            out,
+            name: None,
+            source: source.lines().map(|l| l.trim().to_owned()).collect(),
+            before: Before::capture(machine),
+        }
+    }
+
+    /// Tell the chip apart from others of the same model by `name`.
+    pub fn named(mut self, name: &str) -> JsonTrace<W> {
+        self.name = Some(name.to_owned());
+        self
+    }
+
+    /// Write the record for the step `machine` just took.
+    pub fn record(&mut self, machine: &Machine) -> io::Result<()> {
+        let after = Before::capture(machine);
+        let before = std::mem::replace(&mut self.before, after);
+
+        let line = machine.line_of(before.pc);
+        let entry = &machine.program()[before.pc];
+        // A label can share its source line with an instruction, so it is
+        // printed on its own rather than as that line.
+        let instruction = match line {
+            _ if entry.0 == Instruction::Label => Some(format::line(entry)),
+            Some(n) => self.source.get(n - 1).cloned(),
+            None => None,
+        };
+        let (instr, _) = entry;
+        let skipped = before.power == machine.power() && *instr != Instruction::Label;
+        let changed = before
+            .registers
+            .iter()
+            .zip(&self.before.registers)
+            .filter(|((_, old), (_, new))| old != new)
+            .map(|(_, (name, new))| (*name, Json::from(*new)));
+
+        let record = Json::object([
+            ("step", Json::from(machine.steps())),
+            ("timestep", before.timestep.into()),
+            ("chip", self.name.clone().into()),
+            ("model", machine.chip().name().into()),
+            ("pc", before.pc.into()),
+            ("line", line.into()),
+            ("instruction", instruction.into()),
+            ("flag", machine.flag().to_string().into()),
+            ("skipped", skipped.into()),
+            ("changed", Json::object(changed)),
+        ]);
+        writeln!(self.out, "{}", record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::JsonTrace;
+    use crate::machine::Machine;
+
+    #[test]
+    fn records_changes_per_step() {
+        let source = "mov 5 acc\nteq acc 5\n- add 1\n+ slp 2";
+        let mut machine = Machine::load(source).unwrap();
+        let mut out = Vec::new();
+        let mut trace = JsonTrace::new(&mut out, &machine, source).named("amp");
+        while machine.step() {
+            trace.record(&machine).unwrap();
+        }
+
+        let lines: Vec<String> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|l| l.to_owned())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[0],
+            "{\"step\":1,\"timestep\":0,\"chip\":\"amp\",\"model\":\"MC6000\",\"pc\":0,\
+             \"line\":1,\
+             \"instruction\":\"mov 5 acc\",\"flag\":\"-\",\"skipped\":false,\
+             \"changed\":{\"acc\":5}}"
+        );
+        assert!(lines[1].contains("\"flag\":\"+\""));
+        assert!(lines[2].contains("\"skipped\":true"));
+        assert!(lines[2].contains("\"changed\":{}"));
+        assert!(lines[3].contains("\"instruction\":\"+ slp 2\""));
+    }
+
+    #[test]
+    fn prints_labels_on_their_own() {
+        let source = "loop: add 1";
+        let mut machine = Machine::load(source).unwrap();
+        let mut out = Vec::new();
+        let mut trace = JsonTrace::new(&mut out, &machine, source);
+        while machine.step() {
+            trace.record(&machine).unwrap();
+        }
+
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert!(lines[0].contains("\"instruction\":\"loop:\""), "{}", lines[0]);
+        assert!(lines[1].contains("\"instruction\":\"loop: add 1\""), "{}", lines[1]);
+    }
+}
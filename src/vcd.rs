@@ -0,0 +1,303 @@
+/*
+    Value Change Dump export, for viewing runs in a waveform viewer.
+*/
+
+use std::io::{self, Write};
+
+use crate::machine::{self, Flag, Machine};
+
+/// Bits needed to hold every register value in two's complement.
+const REGISTER_WIDTH: usize = 11;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    /// A signed register value.
+    Integer,
+    /// A single bit.
+    Wire,
+    /// The `+`/`-` flag as a single bit: 1 for `+`, 0 for `-`, and `x` when
+    /// a `tcp` of equal values leaves neither set. Recorded with `level`.
+    Flag,
+    /// Something that happens at a point in time without holding a value,
+    /// such as an XBus transfer.
+    Event,
+}
+
+/// One waveform: the values a signal took, keyed by timestep.
+#[derive(Debug)]
+pub struct Signal {
+    pub name: String,
+    pub kind: Kind,
+    pub changes: Vec<(usize, i64)>,
+}
+
+impl Signal {
+    /// The value the signal held at the end of `timestep`, or `None` before
+    /// its first change.
+    pub fn value_at(&self, timestep: usize) -> Option<i64> {
+        self.changes
+            .iter()
+            .take_while(|(t, _)| *t <= timestep)
+            .last()
+            .map(|(_, v)| *v)
+    }
+}
+
+/// A group of signals, usually one per chip.
+#[derive(Debug)]
+pub struct Scope {
+    pub name: String,
+    pub signals: Vec<Signal>,
+}
+
+impl Scope {
+    pub fn signal(&self, name: &str) -> Option<&Signal> {
+        self.signals.iter().find(|s| s.name == name)
+    }
+}
+
+/// A whole dump. Time is measured in timesteps.
+#[derive(Debug, Default)]
+pub struct Vcd {
+    pub scopes: Vec<Scope>,
+    /// The timestep the dump runs until.
+    pub end: usize,
+}
+
+/// Identifier codes are strings of printable ASCII, `!` to `~`.
+fn identifier(mut n: usize) -> String {
+    let mut id = String::new();
+    loop {
+        id.push((b'!' + (n % 94) as u8) as char);
+        n /= 94;
+        if n == 0 {
+            return id;
+        }
+        n -= 1;
+    }
+}
+
+/// The value a `Kind::Flag` signal records for `flag`.
+pub fn level(flag: Flag) -> i64 {
+    match flag {
+        Flag::Plus => 1,
+        Flag::Minus => 0,
+        Flag::Neither => -1,
+    }
+}
+
+fn binary(value: i64) -> String {
+    let mask = (1i64 << REGISTER_WIDTH) - 1;
+    format!("{:0width$b}", value & mask, width = REGISTER_WIDTH)
+}
+
+impl Vcd {
+    pub fn write(&self, mut out: impl Write) -> io::Result<()> {
+        writeln!(out, "$version rust-shenzhenio $end")?;
+        writeln!(out, "$timescale 1 s $end")?;
+        let mut signals: Vec<(String, &Signal)> = Vec::new();
+        for scope in &self.scopes {
+            writeln!(out, "$scope module {} $end", scope.name)?;
+            for signal in &scope.signals {
+                let id = identifier(signals.len());
+                let (kind, width) = match signal.kind {
+                    Kind::Integer => ("integer", REGISTER_WIDTH),
+                    Kind::Wire | Kind::Flag => ("wire", 1),
+                    Kind::Event => ("event", 1),
+                };
+                writeln!(out, "$var {} {} {} {} $end", kind, width, id, signal.name)?;
+                signals.push((id, signal));
+            }
+            writeln!(out, "$upscope $end")?;
+        }
+        writeln!(out, "$enddefinitions $end")?;
+
+        // The value each signal had most recently been dumped with.
+        let mut dumped: Vec<Option<i64>> = vec![None; signals.len()];
+        let mut cursors = vec![0; signals.len()];
+        for time in 0..=self.end {
+            let mut lines = Vec::new();
+            for (i, (id, signal)) in signals.iter().enumerate() {
+                // Several changes in one timestep collapse to the last one.
+                let mut value = None;
+                while let Some((t, v)) = signal.changes.get(cursors[i])
+                    && *t <= time
+                {
+                    value = Some(*v);
+                    cursors[i] += 1;
+                }
+                let value = match value {
+                    Some(v) => v,
+                    None => continue,
+                };
+                match signal.kind {
+                    Kind::Event => lines.push(format!("1{}", id)),
+                    _ if dumped[i] == Some(value) => (),
+                    Kind::Integer => lines.push(format!("b{} {}", binary(value), id)),
+                    Kind::Wire => lines.push(format!("{}{}", value, id)),
+                    Kind::Flag => {
+                        let bit = match value {
+                            1 => "1",
+                            0 => "0",
+                            _ => "x",
+                        };
+                        lines.push(format!("{}{}", bit, id));
+                    }
+                }
+                dumped[i] = Some(value);
+            }
+            if !lines.is_empty() || time == self.end {
+                writeln!(out, "#{}", time)?;
+                for line in lines {
+                    writeln!(out, "{}", line)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Builds a scope from a machine as it runs. Call `record` after every step.
+pub struct Recorder {
+    scope: Scope,
+    previous_pc: usize,
+    previous_power: usize,
+    xbus: Vec<&'static str>,
+}
+
+impl Recorder {
+    /// Start recording `machine` under the scope `name`, with its current
+    /// values as the starting point.
+    pub fn new(name: &str, machine: &Machine) -> Recorder {
+        let mut signals: Vec<Signal> = machine
+            .chip()
+            .registers()
+            .iter()
+            .map(|r| Signal {
+                name: r.to_string(),
+                kind: Kind::Integer,
+                changes: vec![(0, machine.register(r).unwrap())],
+            })
+            .collect();
+        signals.push(Signal {
+            name: String::from("flag"),
+            kind: Kind::Flag,
+            changes: vec![(0, level(machine.flag()))],
+        });
+        let xbus: Vec<&'static str> = machine
+            .chip()
+            .registers()
+            .iter()
+            .copied()
+            .filter(|r| r.starts_with('x'))
+            .collect();
+        for port in &xbus {
+            signals.push(Signal {
+                name: format!("{}_transfer", port),
+                kind: Kind::Event,
+                changes: Vec::new(),
+            });
+        }
+
+        Recorder {
+            scope: Scope {
+                name: name.to_owned(),
+                signals,
+            },
+            previous_pc: machine.pc(),
+            previous_power: machine.power(),
+            xbus,
+        }
+    }
+
+    pub fn record(&mut self, machine: &Machine) {
+        let time = machine.timestep();
+        let registers = machine.chip().registers();
+        for (signal, name) in self.scope.signals.iter_mut().zip(registers) {
+            let value = machine.register(name).unwrap();
+            if signal.changes.last().map(|(_, v)| *v) != Some(value) {
+                signal.changes.push((time, value));
+            }
+        }
+        let flag = &mut self.scope.signals[registers.len()];
+        let value = level(machine.flag());
+        if flag.changes.last().map(|(_, v)| *v) != Some(value) {
+            flag.changes.push((time, value));
+        }
+
+        // A write to an XBus port is a transfer, even if the value is the
+        // same. Power only goes up when the line actually ran.
+        let line = &machine.program()[self.previous_pc];
+        if machine.power() > self.previous_power
+            && let Some(port) = machine::written_register(line)
+            && let Some(i) = self.xbus.iter().position(|p| *p == port)
+        {
+            let value = machine.register(port).unwrap();
+            self.scope.signals[registers.len() + 1 + i]
+                .changes
+                .push((time, value));
+        }
+        self.previous_pc = machine.pc();
+        self.previous_power = machine.power();
+    }
+
+    pub fn finish(self) -> Scope {
+        self.scope
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Recorder, Vcd};
+    use crate::machine::Machine;
+
+    #[test]
+    fn dumps_changes_per_timestep() {
+        let program = "mov 5 x0\nmov 5 x0\nmov 7 acc\nslp 2\nmov 100 p0\nmov 200 acc";
+        let mut machine = Machine::load(program).unwrap();
+        let mut recorder = Recorder::new("chip", &machine);
+        while machine.step() {
+            recorder.record(&machine);
+        }
+        let vcd = Vcd {
+            scopes: vec![recorder.finish()],
+            end: machine.timestep() + 1,
+        };
+        let mut out = Vec::new();
+        vcd.write(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("$var integer 11 ! acc $end"), "{}", out);
+        assert!(out.contains("$var wire 1 ) flag $end"), "{}", out);
+        assert!(out.contains("$var event 1 * x0_transfer $end"), "{}", out);
+        // acc goes 0 -> 7 within timestep 0, so only 7 is dumped there.
+        let zero = &out[out.find("#0").unwrap()..out.find("#2").unwrap()];
+        assert!(zero.contains("b00000000111 !"), "{}", zero);
+        assert_eq!(zero.matches("1*").count(), 1, "{}", zero);
+        let two = &out[out.find("#2").unwrap()..out.find("#3").unwrap()];
+        assert!(two.contains("b00001100100 #"), "{}", two);
+        assert!(two.contains("b00011001000 !"), "{}", two);
+        assert!(out.trim_end().ends_with("#3"), "{}", out);
+    }
+
+    #[test]
+    fn flag_shows_all_three_states() {
+        let program = "tgt 1 0\nslp 1\ntcp 2 2\nslp 1\ntlt 1 0";
+        let mut machine = Machine::load(program).unwrap();
+        let mut recorder = Recorder::new("chip", &machine);
+        while machine.step() {
+            recorder.record(&machine);
+        }
+        let vcd = Vcd {
+            scopes: vec![recorder.finish()],
+            end: machine.timestep(),
+        };
+        let mut out = Vec::new();
+        vcd.write(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        let zero = &out[out.find("#0").unwrap()..out.find("#1").unwrap()];
+        assert!(zero.contains("\n1)\n"), "{}", out);
+        assert!(out.contains("#1\nx)\n#2\n0)\n"), "{}", out);
+    }
+}